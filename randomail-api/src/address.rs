@@ -0,0 +1,195 @@
+use std::fmt;
+
+/// Longest local part allowed by RFC 5321
+const MAX_LOCAL_PART_LEN: usize = 64;
+
+/// Mailboxes that belong to whoever runs the domain (RFC 2142 and friends)
+const RESERVED_NAMES: &[&str] = &[
+    "abuse",
+    "hostmaster",
+    "mailer-daemon",
+    "noc",
+    "postmaster",
+    "root",
+    "security",
+    "webmaster",
+];
+
+/// Why an alias name was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RMAddressError {
+    Empty,
+    TooLong(usize),
+    ContainsAt,
+    InvalidChar(char),
+    Dots,
+    Reserved(String),
+    InvalidDomain(String),
+}
+
+impl fmt::Display for RMAddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "alias name is empty"),
+            Self::TooLong(len) => write!(
+                f,
+                "alias name is {len} characters long, the limit is {MAX_LOCAL_PART_LEN}"
+            ),
+            Self::ContainsAt => write!(f, "alias name must not contain @, give the part before it"),
+            Self::InvalidChar(c) => write!(
+                f,
+                "alias name contains '{c}', use letters, digits, '.', '-' and '_'"
+            ),
+            Self::Dots => write!(f, "alias name can't start or end with '.' or contain '..'"),
+            Self::Reserved(name) => write!(f, "{name} is reserved for the domain owner"),
+            Self::InvalidDomain(domain) => write!(f, "{domain} is not a valid domain"),
+        }
+    }
+}
+
+impl std::error::Error for RMAddressError {}
+
+/// Lowercase `name` and make sure it can be used as the local part of an
+/// alias. That's RFC 5321's dot-atom narrowed down to what Cloudflare
+/// accepts for custom addresses.
+pub fn normalize_local_part(name: &str) -> Result<String, RMAddressError> {
+    let name = name.trim().to_lowercase();
+
+    if name.is_empty() {
+        return Err(RMAddressError::Empty);
+    }
+
+    let len = name.chars().count();
+
+    if len > MAX_LOCAL_PART_LEN {
+        return Err(RMAddressError::TooLong(len));
+    }
+
+    if name.contains('@') {
+        return Err(RMAddressError::ContainsAt);
+    }
+
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_')))
+    {
+        return Err(RMAddressError::InvalidChar(c));
+    }
+
+    if name.starts_with('.') || name.ends_with('.') || name.contains("..") {
+        return Err(RMAddressError::Dots);
+    }
+
+    if RESERVED_NAMES.contains(&name.as_str()) {
+        return Err(RMAddressError::Reserved(name));
+    }
+
+    Ok(name)
+}
+
+/// Full address of the alias `name` under `domain`
+pub fn alias_address(name: &str, domain: &str) -> Result<String, RMAddressError> {
+    Ok(format!("{}@{domain}", normalize_local_part(name)?))
+}
+
+/// Full address from either a bare name, placed under `domain`, or an
+/// address whose local part gets validated like `normalize_local_part`
+pub fn parse_address(input: &str, domain: &str) -> Result<String, RMAddressError> {
+    let Some((name, alias_domain)) = input.trim().rsplit_once('@') else {
+        return alias_address(input, domain);
+    };
+
+    let alias_domain = alias_domain.to_lowercase();
+
+    let valid = alias_domain
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+
+    if alias_domain.is_empty() || !valid {
+        return Err(RMAddressError::InvalidDomain(alias_domain));
+    }
+
+    alias_address(name, &alias_domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_local_part_cases() {
+        let long = "a".repeat(MAX_LOCAL_PART_LEN);
+        let too_long = "a".repeat(MAX_LOCAL_PART_LEN + 1);
+
+        let cases: &[(&str, Result<&str, RMAddressError>)] = &[
+            ("Shop", Ok("shop")),
+            ("  news.2024  ", Ok("news.2024")),
+            ("a-b_c.d", Ok("a-b_c.d")),
+            (&long, Ok(&long)),
+            (
+                &too_long,
+                Err(RMAddressError::TooLong(MAX_LOCAL_PART_LEN + 1)),
+            ),
+            ("", Err(RMAddressError::Empty)),
+            ("   ", Err(RMAddressError::Empty)),
+            ("shop@example.com", Err(RMAddressError::ContainsAt)),
+            ("shop+news", Err(RMAddressError::InvalidChar('+'))),
+            ("my shop", Err(RMAddressError::InvalidChar(' '))),
+            ("café", Err(RMAddressError::InvalidChar('é'))),
+            (".shop", Err(RMAddressError::Dots)),
+            ("shop.", Err(RMAddressError::Dots)),
+            ("sh..op", Err(RMAddressError::Dots)),
+            (
+                "PostMaster",
+                Err(RMAddressError::Reserved("postmaster".to_string())),
+            ),
+            ("abuse", Err(RMAddressError::Reserved("abuse".to_string()))),
+            ("abuse-reports", Ok("abuse-reports")),
+        ];
+
+        for (name, want) in cases {
+            assert_eq!(
+                normalize_local_part(name),
+                want.clone().map(str::to_string),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn length_counts_characters() {
+        // 64 characters but more bytes, refused for the character rather than the length
+        let name = "é".repeat(MAX_LOCAL_PART_LEN);
+
+        assert_eq!(
+            normalize_local_part(&name),
+            Err(RMAddressError::InvalidChar('é'))
+        );
+    }
+
+    #[test]
+    fn parse_address_cases() {
+        let cases: &[(&str, Result<&str, RMAddressError>)] = &[
+            ("shop", Ok("shop@example.com")),
+            ("Shop@Other.Example", Ok("shop@other.example")),
+            ("shop@", Err(RMAddressError::InvalidDomain(String::new()))),
+            (
+                "shop@exa mple.com",
+                Err(RMAddressError::InvalidDomain("exa mple.com".to_string())),
+            ),
+            ("a@b@example.com", Err(RMAddressError::ContainsAt)),
+            (
+                "root@example.com",
+                Err(RMAddressError::Reserved("root".to_string())),
+            ),
+        ];
+
+        for (input, want) in cases {
+            assert_eq!(
+                parse_address(input, "example.com"),
+                want.clone().map(str::to_string),
+                "{input}"
+            );
+        }
+    }
+}
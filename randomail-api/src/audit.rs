@@ -0,0 +1,263 @@
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    future::Future,
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use log::error;
+use serde::{Deserialize, Serialize};
+use tabled::Tabled;
+
+use crate::metadata::current_user;
+
+pub const AUDIT_FILE_NAME: &str = "audit.jsonl";
+
+tokio::task_local! {
+    static ACTOR: String;
+}
+
+/// Run `f` on behalf of `actor`, the changes it makes are logged under
+/// that name instead of the OS user
+pub async fn with_actor<F>(actor: String, f: F) -> F::Output
+where
+    F: Future,
+{
+    ACTOR.scope(actor, f).await
+}
+
+/// Who the current change is made by: the web user when a request set one,
+/// the OS user otherwise
+#[must_use]
+pub fn current_actor() -> String {
+    ACTOR
+        .try_with(Clone::clone)
+        .unwrap_or_else(|_| current_user())
+}
+
+/// Kind of change made to a routing rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RMOperation {
+    Create,
+    Delete,
+    Enable,
+    Disable,
+    Rename,
+    Readdress,
+    Retarget,
+    /// A deleted rule created again from a snapshot or the trash
+    Restore,
+    /// A rule overwritten with its state from a snapshot
+    Reset,
+}
+
+impl fmt::Display for RMOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self {
+            Self::Create => "create",
+            Self::Delete => "delete",
+            Self::Enable => "enable",
+            Self::Disable => "disable",
+            Self::Rename => "rename",
+            Self::Readdress => "readdress",
+            Self::Retarget => "retarget",
+            Self::Restore => "restore",
+            Self::Reset => "reset",
+        };
+
+        f.write_str(op)
+    }
+}
+
+impl FromStr for RMOperation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "create" => Ok(Self::Create),
+            "delete" => Ok(Self::Delete),
+            "enable" => Ok(Self::Enable),
+            "disable" => Ok(Self::Disable),
+            "rename" => Ok(Self::Rename),
+            "readdress" | "move" => Ok(Self::Readdress),
+            "retarget" => Ok(Self::Retarget),
+            "restore" => Ok(Self::Restore),
+            "reset" => Ok(Self::Reset),
+            _ => bail!("unknown operation {s}"),
+        }
+    }
+}
+
+/// One line of the audit log
+#[derive(Debug, Clone, Serialize, Deserialize, Tabled)]
+pub struct RMAuditEntry {
+    #[tabled(rename = "time", display = "display_time")]
+    pub timestamp: DateTime<Utc>,
+    pub actor: String,
+    pub operation: RMOperation,
+    #[tabled(display = "display_option")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    /// Zone of the rule, the domain for Postfix
+    #[tabled(skip)]
+    pub zone_id: String,
+    /// Id of the rule in the Cloudflare response, the new id for creations
+    #[tabled(rename = "rule", display = "display_option")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_id: Option<String>,
+    /// The rule before the change, none for creations
+    #[tabled(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<serde_json::Value>,
+    /// The rule Cloudflare returned, none for deletions and failures
+    #[tabled(skip)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<serde_json::Value>,
+    /// Why Cloudflare refused the change
+    #[tabled(display = "display_option")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+fn display_time(date: &DateTime<Utc>) -> String {
+    date.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+// tabled hands display functions a reference to the field
+#[allow(clippy::ref_option)]
+fn display_option(value: &Option<String>) -> String {
+    value.clone().unwrap_or_default()
+}
+
+/// Entries `RMAuditLog::read` keeps, every field has to match
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct RMAuditFilter {
+    /// Address of the alias, ignoring case
+    pub alias: Option<String>,
+    pub actor: Option<String>,
+    pub operation: Option<RMOperation>,
+    pub since: Option<DateTime<Utc>>,
+    /// Only the most recent entries
+    pub limit: Option<usize>,
+}
+
+impl RMAuditFilter {
+    fn matches(&self, entry: &RMAuditEntry) -> bool {
+        self.alias.as_ref().is_none_or(|alias| {
+            entry
+                .alias
+                .as_ref()
+                .is_some_and(|a| a.eq_ignore_ascii_case(alias))
+        }) && self
+            .actor
+            .as_ref()
+            .is_none_or(|actor| entry.actor == *actor)
+            && self.operation.is_none_or(|op| entry.operation == op)
+            && self.since.is_none_or(|since| entry.timestamp >= since)
+    }
+}
+
+/// Append-only JSONL file recording every change made to routing rules and
+/// Postfix map entries
+#[derive(Debug, Clone)]
+pub struct RMAuditLog {
+    path: PathBuf,
+}
+
+impl RMAuditLog {
+    pub fn new<P>(path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Self { path: path.into() }
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Add `entry` at the end of the log, each entry is a single write so
+    /// concurrent writers don't interleave
+    pub fn append(&self, entry: &RMAuditEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry).context("Unable to serialize audit entry")?;
+        line.push(b'\n');
+
+        let mut fd = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Unable to open {}", self.path.display()))?;
+
+        fd.write_all(&line)
+            .with_context(|| format!("Unable to write to {}", self.path.display()))
+    }
+
+    /// Entries matching `filter`, oldest first. Lines that can't be parsed
+    /// are reported and skipped.
+    pub fn read(&self, filter: &RMAuditFilter) -> Result<Vec<RMAuditEntry>> {
+        let fd = match fs::File::open(&self.path) {
+            Ok(fd) => fd,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Unable to open {}", self.path.display()));
+            }
+        };
+
+        let mut entries = Vec::new();
+
+        for (i, line) in BufReader::new(fd).lines().enumerate() {
+            let line = line.with_context(|| format!("Unable to read {}", self.path.display()))?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str::<RMAuditEntry>(&line) {
+                Ok(entry) if filter.matches(&entry) => entries.push(entry),
+                Ok(_) => {}
+                Err(e) => error!(
+                    "{}:{} is not an audit entry ({e})",
+                    self.path.display(),
+                    i.saturating_add(1)
+                ),
+            }
+        }
+
+        if let Some(limit) = filter.limit {
+            let skip = entries.len().saturating_sub(limit);
+            entries.drain(..skip);
+        }
+
+        Ok(entries)
+    }
+}
+
+/// Parse how far back to look: a duration (`7d`, `12h`) or a date
+pub fn parse_since(value: &str) -> Result<DateTime<Utc>> {
+    let value = value.trim();
+
+    if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+        return Ok(date.with_timezone(&Utc));
+    }
+
+    if let Ok(date) = chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date.and_time(chrono::NaiveTime::MIN).and_utc());
+    }
+
+    let duration = humantime::parse_duration(value)
+        .with_context(|| format!("{value} is neither a date nor a duration"))?;
+
+    let duration = chrono::Duration::from_std(duration)
+        .with_context(|| format!("{value} is too far in the past"))?;
+
+    match Utc::now().checked_sub_signed(duration) {
+        Some(date) => Ok(date),
+        None => bail!("{value} is too far in the past"),
+    }
+}
@@ -1,42 +1,35 @@
-use std::fmt::Display;
-
-use anyhow::{Context, Result, bail};
+use anyhow::{Result, bail};
 use serde::Deserialize;
 
-use crate::http::{CF_API_URL, issue_get};
+use crate::http::{CFClient, issue_get_paged};
 
 #[derive(Deserialize)]
 pub struct CFDestinationAddr {
     pub id: String,
     pub email: String,
+    /// When the owner confirmed the address, mail isn't forwarded before
+    #[serde(default)]
+    pub verified: Option<String>,
 }
 
-#[derive(Deserialize)]
-struct CFDestinationAddrsResponse {
-    result: Vec<CFDestinationAddr>,
-}
-
-pub async fn destination_address<A, E, T>(
+pub async fn destination_address<A, E>(
+    client: &CFClient,
     account_id: A,
     email: E,
-    token: T,
 ) -> Result<CFDestinationAddr>
 where
     A: AsRef<str>,
     E: AsRef<str>,
-    T: AsRef<str> + Display,
 {
     let url = format!(
-        "{CF_API_URL}/accounts/{}/email/routing/addresses",
+        "{}/accounts/{}/email/routing/addresses",
+        client.api_url(),
         account_id.as_ref()
     );
 
-    let data = issue_get(url, token).await?;
-
-    let response: CFDestinationAddrsResponse =
-        serde_json::from_str(&data).with_context(|| format!("Unable to deserialize {data}"))?;
+    let addrs: Vec<CFDestinationAddr> = issue_get_paged(client, url).await?;
 
-    for r in response.result {
+    for r in addrs {
         if r.email == email.as_ref() {
             return Ok(r);
         }
@@ -44,3 +37,25 @@ where
 
     bail!("{} was not found in response", email.as_ref())
 }
+
+/// Like `destination_address`, but fails unless the address was verified
+pub async fn verified_destination_address<A, E>(
+    client: &CFClient,
+    account_id: A,
+    email: E,
+) -> Result<CFDestinationAddr>
+where
+    A: AsRef<str>,
+    E: AsRef<str>,
+{
+    let dst = destination_address(client, account_id, &email).await?;
+
+    if dst.verified.is_none() {
+        bail!(
+            "{} is not verified yet, follow the link Cloudflare sent to it",
+            email.as_ref()
+        );
+    }
+
+    Ok(dst)
+}
@@ -1,10 +1,16 @@
 use std::fmt::Display;
 
 use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use chrono::Utc;
+use log::error;
 use serde::{Deserialize, Serialize};
-use tabled::Tabled;
 
-use crate::http::{CF_API_URL, issue_delete, issue_get, issue_post, issue_put};
+use crate::{
+    audit::{RMAuditEntry, RMOperation, current_actor},
+    http::{CFClient, issue_delete, issue_get, issue_get_paged, issue_post, issue_put},
+    provider::{AliasProvider, RMAlias, RMAlreadyExists, RMCapabilities},
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 struct CFEmailRouteMatch {
@@ -28,6 +34,21 @@ impl CFEmailRouteMatch {
         }
     }
 
+    /// Whether this matches the recipient address literally, the matcher
+    /// holding the address of an alias
+    fn is_address(&self) -> bool {
+        self.action_type == "literal" && self.field.as_deref() == Some("to")
+    }
+
+    /// Whether this matches mail sent to `email`, ignoring case
+    fn matches(&self, email: &str) -> bool {
+        self.is_address()
+            && self
+                .value
+                .as_deref()
+                .is_some_and(|v| v.eq_ignore_ascii_case(email))
+    }
+
     pub fn email_alias(&self) -> Result<String> {
         if let Some(value) = &self.value {
             return Ok(value.into());
@@ -101,8 +122,9 @@ impl CFEmailRoute {
     pub fn email_alias(&self) -> Result<String> {
         let entry = self
             .matchers
-            .first()
-            .context("invalid context, matches missing")?;
+            .iter()
+            .find(|m| m.is_address())
+            .context("invalid context, recipient matcher missing")?;
         entry.email_alias()
     }
 
@@ -115,21 +137,9 @@ impl CFEmailRoute {
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct CFEmailRouting {
-    result: Vec<CFEmailRoute>,
-}
-
-#[derive(Debug, Default, Tabled, Serialize)]
-pub struct RMAlias {
-    #[tabled(skip)]
-    pub id: String,
-    #[tabled(skip)]
-    pub email_destination: String,
-    #[tabled(rename = "alias")]
-    pub email_alias: String,
-    pub name: String,
-    pub enabled: bool,
+#[derive(Deserialize)]
+struct CFEmailRouteResponse {
+    result: CFEmailRoute,
 }
 
 impl TryFrom<CFEmailRoute> for RMAlias {
@@ -143,134 +153,438 @@ impl TryFrom<CFEmailRoute> for RMAlias {
         let email_destination = route.email_dest()?;
         let email_alias = route.email_alias()?;
 
+        let extra_destinations = route
+            .actions
+            .first()
+            .and_then(|a| a.value.as_ref())
+            .map(|dests| dests.iter().skip(1).cloned().collect())
+            .unwrap_or_default();
+
         Ok(Self {
             id: id.into(),
             name: route.name.unwrap_or_default(),
             email_destination,
+            extra_destinations,
             email_alias,
             enabled: route.enabled,
+            ..Self::default()
         })
     }
 }
 
-async fn find_route<Z, E, T>(zone_id: Z, email_id: E, token: T) -> Result<CFEmailRoute>
+async fn find_route<Z, E>(client: &CFClient, zone_id: Z, email_id: E) -> Result<CFEmailRoute>
 where
     Z: AsRef<str> + Display,
-    T: AsRef<str> + Display,
     E: AsRef<str> + Display,
 {
-    let url = format!("{CF_API_URL}/zones/{zone_id}/email/routing/rules",);
+    let url = format!(
+        "{}/zones/{zone_id}/email/routing/rules/{email_id}",
+        client.api_url()
+    );
 
-    let data = issue_get(url, token).await?;
+    let data = issue_get(client, url).await?;
 
-    let response: CFEmailRouting =
+    let response: CFEmailRouteResponse =
         serde_json::from_str(&data).with_context(|| format!("Unable to deserialize {data}"))?;
 
-    for r in response.result {
-        if let Some(id) = &r.id
-            && id.as_str() == email_id.as_ref()
-        {
-            return Ok(r);
+    Ok(response.result)
+}
+
+/// A change to the rules of a zone, with the rule to send
+enum CFChange<'a, B> {
+    Create(&'a B),
+    /// Overwrite the rule with this id
+    Update(&'a str, &'a B),
+    Delete(&'a str),
+}
+
+#[derive(Deserialize)]
+struct CFValueResponse {
+    #[serde(default)]
+    result: serde_json::Value,
+}
+
+fn as_json<T>(value: &T) -> Option<serde_json::Value>
+where
+    T: Serialize,
+{
+    serde_json::to_value(value).ok()
+}
+
+/// Address a rule matches, read from its JSON
+fn json_alias(rule: &serde_json::Value) -> Option<String> {
+    serde_json::from_value::<CFEmailRule>(rule.clone())
+        .ok()
+        .and_then(|r| r.email_alias().map(str::to_string))
+}
+
+/// The single path every change to a rule goes through, so that each one
+/// is recorded in the audit log along with the rule before and after it,
+/// and the known rules drift is detected against stay current. Returns the
+/// `result` of Cloudflare's response.
+async fn mutate<B>(
+    client: &CFClient,
+    zone_id: &str,
+    operation: RMOperation,
+    change: CFChange<'_, B>,
+    before: Option<serde_json::Value>,
+) -> Result<serde_json::Value>
+where
+    B: Serialize,
+{
+    let url = format!("{}/zones/{zone_id}/email/routing/rules", client.api_url());
+
+    let deleted = match change {
+        CFChange::Delete(email_id) => Some(email_id),
+        _ => None,
+    };
+
+    let res = match change {
+        CFChange::Create(rule) => issue_post(client, url, rule).await,
+        CFChange::Update(email_id, rule) => {
+            issue_put(client, format!("{url}/{email_id}"), rule).await
+        }
+        CFChange::Delete(email_id) => issue_delete(client, format!("{url}/{email_id}")).await,
+    };
+
+    let result = match &res {
+        Ok(data) => serde_json::from_str::<CFValueResponse>(data)
+            .map(|r| r.result)
+            .unwrap_or_default(),
+        Err(_) => serde_json::Value::Null,
+    };
+
+    if let Some(audit) = client.audit() {
+        let after = (deleted.is_none() && !result.is_null()).then(|| result.clone());
+
+        let entry = RMAuditEntry {
+            timestamp: Utc::now(),
+            actor: current_actor(),
+            operation,
+            alias: after
+                .as_ref()
+                .and_then(json_alias)
+                .or_else(|| before.as_ref().and_then(json_alias)),
+            zone_id: zone_id.to_string(),
+            response_id: result
+                .get("id")
+                .and_then(|id| id.as_str())
+                .map(str::to_string),
+            before,
+            after,
+            error: res.as_ref().err().map(|e| format!("{e:#}")),
+        };
+
+        if let Err(e) = audit.append(&entry) {
+            error!("Unable to write audit log ({e:#})");
         }
     }
 
-    bail!("route not found for {email_id}")
+    res?;
+
+    if let Some(known) = client.known_rules() {
+        let res = match deleted {
+            Some(email_id) => known.forget(zone_id, email_id),
+            None => serde_json::from_value::<CFEmailRule>(result.clone())
+                .context("Unable to deserialize changed rule")
+                .and_then(|rule| known.remember(zone_id, &rule)),
+        };
+
+        if let Err(e) = res {
+            error!("Unable to record known rule ({e:#})");
+        }
+    }
+
+    Ok(result)
 }
 
 ////////////////////////////////////////////////////////////////////////////////
 // PUBLIC
 ////////////////////////////////////////////////////////////////////////////////
 
-pub async fn delete_email_route<Z, I, T>(zone_id: Z, email_id: I, token: T) -> Result<()>
+pub async fn delete_email_route<Z, I>(client: &CFClient, zone_id: Z, email_id: I) -> Result<()>
 where
     Z: AsRef<str> + Display,
     I: AsRef<str> + Display,
-    T: AsRef<str> + Display,
 {
-    let url = format!("{CF_API_URL}/zones/{zone_id}/email/routing/rules/{email_id}");
-    issue_delete(url, token).await
+    let before = find_route(client, &zone_id, &email_id).await?;
+
+    mutate(
+        client,
+        zone_id.as_ref(),
+        RMOperation::Delete,
+        CFChange::<()>::Delete(email_id.as_ref()),
+        as_json(&before),
+    )
+    .await?;
+
+    Ok(())
 }
 
-pub async fn add_email_route<Z, N, A, D, T>(
+/// The alias `route` stands for, to report it as taken. Unlike `RMAlias`
+/// conversion this doesn't need a forward action, rules that drop mail or
+/// hand it to a worker hold their address just the same.
+fn existing_alias(route: CFEmailRoute, email_alias: &str) -> RMAlias {
+    let address = route
+        .matchers
+        .iter()
+        .find(|m| m.matches(email_alias))
+        .and_then(|m| m.value.clone())
+        .unwrap_or_else(|| email_alias.to_string());
+
+    RMAlias {
+        email_destination: route.email_dest().unwrap_or_default(),
+        id: route.id.unwrap_or_default(),
+        email_alias: address,
+        name: route.name.unwrap_or_default(),
+        enabled: route.enabled,
+        ..RMAlias::default()
+    }
+}
+
+/// Fail with `RMAlreadyExists` when a rule other than `email_id` matches
+/// `email_alias`. Lists every rule, so bulk changes check once against
+/// their own listing instead, see `add_email_route_unchecked`.
+async fn check_free<Z>(
+    client: &CFClient,
+    zone_id: Z,
+    email_alias: &str,
+    email_id: Option<&str>,
+) -> Result<()>
+where
+    Z: AsRef<str> + Display,
+{
+    let url = format!("{}/zones/{zone_id}/email/routing/rules", client.api_url());
+
+    let routes: Vec<CFEmailRoute> = issue_get_paged(client, &url).await?;
+
+    if let Some(existing) = routes
+        .into_iter()
+        .find(|r| r.id.as_deref() != email_id && r.matchers.iter().any(|m| m.matches(email_alias)))
+    {
+        return Err(RMAlreadyExists {
+            alias: existing_alias(existing, email_alias),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Create an alias, failing with `RMAlreadyExists` when a rule already
+/// matches its address
+pub async fn add_email_route<Z, N, A, D>(
+    client: &CFClient,
     zone_id: Z,
     name: N,
     email_alias: A,
     email_dest: D,
-    token: T,
-) -> Result<()>
+) -> Result<RMAlias>
 where
     Z: AsRef<str> + Display,
     N: Into<String> + Display,
     A: Into<String> + Display,
     D: Into<String> + Display,
-    T: AsRef<str> + Display,
 {
-    let url = format!("{CF_API_URL}/zones/{zone_id}/email/routing/rules");
+    let email_alias: String = email_alias.into();
+
+    check_free(client, &zone_id, &email_alias, None).await?;
 
+    add_email_route_unchecked(client, zone_id, name, email_alias, email_dest).await
+}
+
+/// Create an alias without looking for an existing rule first, for callers
+/// that checked the address against a listing of their own
+pub async fn add_email_route_unchecked<Z, N, A, D>(
+    client: &CFClient,
+    zone_id: Z,
+    name: N,
+    email_alias: A,
+    email_dest: D,
+) -> Result<RMAlias>
+where
+    Z: AsRef<str> + Display,
+    N: Into<String> + Display,
+    A: Into<String> + Display,
+    D: Into<String> + Display,
+{
     let route = CFEmailRoute::new(name, email_alias, email_dest);
 
-    issue_post(url, token, &route).await
+    let result = mutate(
+        client,
+        zone_id.as_ref(),
+        RMOperation::Create,
+        CFChange::Create(&route),
+        None,
+    )
+    .await?;
+
+    serde_json::from_value::<CFEmailRoute>(result)
+        .context("Unable to deserialize created rule")?
+        .try_into()
+}
+
+pub async fn get_email_route<Z, I>(client: &CFClient, zone_id: Z, email_id: I) -> Result<RMAlias>
+where
+    Z: AsRef<str> + Display,
+    I: AsRef<str> + Display,
+{
+    find_route(client, zone_id, email_id).await?.try_into()
 }
 
-pub async fn update_email_route<Z, I, T>(
+pub async fn update_email_route<Z, I>(
+    client: &CFClient,
     zone_id: Z,
     email_id: I,
-    token: T,
     enabled: bool,
 ) -> Result<()>
 where
     Z: AsRef<str> + Display,
     I: AsRef<str> + Display,
-    T: AsRef<str> + Display,
 {
-    let url = format!("{CF_API_URL}/zones/{zone_id}/email/routing/rules/{email_id}");
-
-    let mut route = find_route(zone_id, email_id, &token).await?;
+    let mut route = find_route(client, &zone_id, &email_id).await?;
+    let before = as_json(&route);
 
     route.enabled = enabled;
 
-    issue_put(url, token, &route).await
+    let operation = if enabled {
+        RMOperation::Enable
+    } else {
+        RMOperation::Disable
+    };
+
+    mutate(
+        client,
+        zone_id.as_ref(),
+        operation,
+        CFChange::Update(email_id.as_ref(), &route),
+        before,
+    )
+    .await?;
+
+    Ok(())
 }
 
-pub async fn rename_email_route<Z, I, T, N>(
+pub async fn rename_email_route<Z, I, N>(
+    client: &CFClient,
     zone_id: Z,
     email_id: I,
-    token: T,
     name: N,
 ) -> Result<()>
 where
     Z: AsRef<str> + Display,
     I: AsRef<str> + Display,
-    T: AsRef<str> + Display,
     N: Into<String> + Display,
 {
-    let url = format!("{CF_API_URL}/zones/{zone_id}/email/routing/rules/{email_id}");
-
-    let mut route = find_route(zone_id, email_id, &token).await?;
+    let mut route = find_route(client, &zone_id, &email_id).await?;
+    let before = as_json(&route);
 
     route.name = Some(name.into());
 
-    issue_put(url, token, &route).await
+    mutate(
+        client,
+        zone_id.as_ref(),
+        RMOperation::Rename,
+        CFChange::Update(email_id.as_ref(), &route),
+        before,
+    )
+    .await?;
+
+    Ok(())
 }
 
-pub async fn list_email_routes<Z, T>(zone_id: Z, token: T) -> Result<Vec<RMAlias>>
+pub async fn readdress_email_route<Z, I, A>(
+    client: &CFClient,
+    zone_id: Z,
+    email_id: I,
+    email_alias: A,
+) -> Result<RMAlias>
 where
-    Z: AsRef<str>,
-    T: AsRef<str> + Display,
+    Z: AsRef<str> + Display,
+    I: AsRef<str> + Display,
+    A: Into<String> + Display,
 {
-    let url = format!(
-        "{CF_API_URL}/zones/{}/email/routing/rules",
-        zone_id.as_ref()
-    );
+    let email_alias: String = email_alias.into();
 
-    let data = issue_get(url, token).await?;
+    check_free(client, &zone_id, &email_alias, Some(email_id.as_ref())).await?;
 
-    let response: CFEmailRouting =
-        serde_json::from_str(&data).with_context(|| format!("Unable to deserialize {data}"))?;
+    let mut route = find_route(client, &zone_id, &email_id).await?;
+    let before = as_json(&route);
+
+    let Some(matcher) = route
+        .matchers
+        .iter_mut()
+        .find(|m| m.is_address() && m.value.is_some())
+    else {
+        bail!("{email_id} has no recipient address to change");
+    };
+
+    matcher.value = Some(email_alias);
+
+    mutate(
+        client,
+        zone_id.as_ref(),
+        RMOperation::Readdress,
+        CFChange::Update(email_id.as_ref(), &route),
+        before,
+    )
+    .await?;
+
+    route.try_into()
+}
+
+/// Forward the rule `email_id` to `email_dests`, replacing every address
+/// of its forward action
+pub async fn retarget_email_route<Z, I>(
+    client: &CFClient,
+    zone_id: Z,
+    email_id: I,
+    email_dests: &[String],
+) -> Result<()>
+where
+    Z: AsRef<str> + Display,
+    I: AsRef<str> + Display,
+{
+    if email_dests.is_empty() {
+        bail!("{email_id}: an alias needs at least one destination");
+    }
+
+    let mut route = find_route(client, &zone_id, &email_id).await?;
+    let before = as_json(&route);
+
+    let Some(action) = route
+        .actions
+        .iter_mut()
+        .find(|a| a.action_type == "forward")
+    else {
+        bail!("{email_id} does not forward mail");
+    };
+
+    action.value = Some(email_dests.to_vec());
+
+    mutate(
+        client,
+        zone_id.as_ref(),
+        RMOperation::Retarget,
+        CFChange::Update(email_id.as_ref(), &route),
+        before,
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub async fn list_email_routes<Z>(client: &CFClient, zone_id: Z) -> Result<Vec<RMAlias>>
+where
+    Z: AsRef<str> + Display,
+{
+    let url = format!("{}/zones/{zone_id}/email/routing/rules", client.api_url());
+
+    let routes: Vec<CFEmailRoute> = issue_get_paged(client, url).await?;
 
     let mut aliases = Vec::new();
 
-    for r in response.result {
+    for r in routes {
         if let Ok(alias) = TryInto::<RMAlias>::try_into(r) {
             aliases.push(alias);
         }
@@ -278,3 +592,205 @@ where
 
     Ok(aliases)
 }
+
+/// A routing rule with every field Cloudflare keeps, unlike `RMAlias`, so
+/// snapshots can put it back as it was
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CFEmailRule {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub id: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub tag: String,
+    #[serde(default)]
+    pub name: String,
+    pub enabled: bool,
+    #[serde(default)]
+    pub priority: i64,
+    pub matchers: Vec<serde_json::Value>,
+    pub actions: Vec<serde_json::Value>,
+}
+
+impl CFEmailRule {
+    /// Address of the literal `to` matcher, when the rule has one
+    #[must_use]
+    pub fn email_alias(&self) -> Option<&str> {
+        self.matchers
+            .iter()
+            .find(|m| {
+                m.get("type").and_then(|t| t.as_str()) == Some("literal")
+                    && m.get("field").and_then(|f| f.as_str()) == Some("to")
+            })
+            .and_then(|m| m.get("value"))
+            .and_then(|v| v.as_str())
+    }
+}
+
+#[derive(Deserialize)]
+struct CFEmailRuleResponse {
+    result: CFEmailRule,
+}
+
+pub async fn list_email_rules<Z>(client: &CFClient, zone_id: Z) -> Result<Vec<CFEmailRule>>
+where
+    Z: AsRef<str> + Display,
+{
+    let url = format!("{}/zones/{zone_id}/email/routing/rules", client.api_url());
+
+    issue_get_paged(client, url).await
+}
+
+pub async fn get_email_rule<Z, I>(client: &CFClient, zone_id: Z, email_id: I) -> Result<CFEmailRule>
+where
+    Z: AsRef<str> + Display,
+    I: AsRef<str> + Display,
+{
+    let url = format!(
+        "{}/zones/{zone_id}/email/routing/rules/{email_id}",
+        client.api_url()
+    );
+
+    let data = issue_get(client, url).await?;
+
+    let response: CFEmailRuleResponse =
+        serde_json::from_str(&data).with_context(|| format!("Unable to deserialize {data}"))?;
+
+    Ok(response.result)
+}
+
+/// Create a copy of `rule`, Cloudflare gives it a new id
+pub async fn create_email_rule<Z>(
+    client: &CFClient,
+    zone_id: Z,
+    rule: &CFEmailRule,
+) -> Result<CFEmailRule>
+where
+    Z: AsRef<str> + Display,
+{
+    let rule = CFEmailRule {
+        id: String::new(),
+        tag: String::new(),
+        ..rule.clone()
+    };
+
+    let result = mutate(
+        client,
+        zone_id.as_ref(),
+        RMOperation::Restore,
+        CFChange::Create(&rule),
+        None,
+    )
+    .await?;
+
+    serde_json::from_value(result).context("Unable to deserialize restored rule")
+}
+
+/// Overwrite the rule `email_id` with the fields of `rule`
+pub async fn replace_email_rule<Z, I>(
+    client: &CFClient,
+    zone_id: Z,
+    email_id: I,
+    rule: &CFEmailRule,
+) -> Result<()>
+where
+    Z: AsRef<str> + Display,
+    I: AsRef<str> + Display,
+{
+    let before = get_email_rule(client, &zone_id, &email_id).await?;
+
+    let rule = CFEmailRule {
+        id: email_id.to_string(),
+        tag: String::new(),
+        ..rule.clone()
+    };
+
+    mutate(
+        client,
+        zone_id.as_ref(),
+        RMOperation::Reset,
+        CFChange::Update(email_id.as_ref(), &rule),
+        as_json(&before),
+    )
+    .await?;
+
+    Ok(())
+}
+
+pub struct CloudflareProvider {
+    client: CFClient,
+    zone: String,
+    zone_id: String,
+}
+
+impl CloudflareProvider {
+    pub fn new<Z, I>(client: CFClient, zone: Z, zone_id: I) -> Self
+    where
+        Z: Into<String>,
+        I: Into<String>,
+    {
+        Self {
+            client,
+            zone: zone.into(),
+            zone_id: zone_id.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl AliasProvider for CloudflareProvider {
+    fn domain(&self) -> &str {
+        &self.zone
+    }
+
+    fn capabilities(&self) -> RMCapabilities {
+        RMCapabilities {
+            toggle: true,
+            rename: true,
+            metadata: false,
+            tags: false,
+            readdress: true,
+            retarget: true,
+            trash: false,
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<RMAlias>> {
+        list_email_routes(&self.client, &self.zone_id).await
+    }
+
+    async fn get(&self, id: &str) -> Result<RMAlias> {
+        get_email_route(&self.client, &self.zone_id, id).await
+    }
+
+    async fn create(&self, name: &str, email_alias: &str, email_dest: &str) -> Result<RMAlias> {
+        add_email_route(&self.client, &self.zone_id, name, email_alias, email_dest).await
+    }
+
+    async fn create_unchecked(
+        &self,
+        name: &str,
+        email_alias: &str,
+        email_dest: &str,
+    ) -> Result<RMAlias> {
+        add_email_route_unchecked(&self.client, &self.zone_id, name, email_alias, email_dest).await
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        delete_email_route(&self.client, &self.zone_id, id).await
+    }
+
+    async fn set_enabled(&self, id: &str, enabled: bool) -> Result<()> {
+        update_email_route(&self.client, &self.zone_id, id, enabled).await
+    }
+
+    async fn readdress(&self, id: &str, email_alias: &str) -> Result<RMAlias> {
+        readdress_email_route(&self.client, &self.zone_id, id, email_alias).await
+    }
+
+    async fn rename(&self, id: &str, name: &str) -> Result<()> {
+        rename_email_route(&self.client, &self.zone_id, id, name).await
+    }
+
+    async fn set_destinations(&self, id: &str, email_dests: &[String]) -> Result<()> {
+        retarget_email_route(&self.client, &self.zone_id, id, email_dests).await
+    }
+}
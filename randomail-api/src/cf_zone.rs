@@ -1,9 +1,7 @@
-use std::fmt::Display;
-
-use anyhow::{Context, Result, bail};
+use anyhow::{Result, bail};
 use serde::Deserialize;
 
-use crate::http::{CF_API_URL, issue_get};
+use crate::http::{CFClient, issue_get_paged};
 
 #[derive(Deserialize)]
 pub struct CFZoneInfo {
@@ -11,24 +9,15 @@ pub struct CFZoneInfo {
     pub name: String,
 }
 
-#[derive(Deserialize)]
-struct CFZoneInfoResponse {
-    result: Vec<CFZoneInfo>,
-}
-
-pub async fn zone_info<D, T>(domain: D, token: T) -> Result<CFZoneInfo>
+pub async fn zone_info<D>(client: &CFClient, domain: D) -> Result<CFZoneInfo>
 where
     D: AsRef<str>,
-    T: AsRef<str> + Display,
 {
-    let url = format!("{CF_API_URL}/zones");
-
-    let data = issue_get(url, token).await?;
+    let url = format!("{}/zones", client.api_url());
 
-    let response: CFZoneInfoResponse =
-        serde_json::from_str(&data).with_context(|| format!("Unable to deserialize {data}"))?;
+    let zones: Vec<CFZoneInfo> = issue_get_paged(client, url).await?;
 
-    for r in response.result {
+    for r in zones {
         if r.name == domain.as_ref() {
             return Ok(r);
         }
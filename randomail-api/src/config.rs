@@ -1,5 +1,5 @@
 use std::{
-    env, fs,
+    env, fmt, fs,
     io::{Read, Write},
     os::unix::fs::{MetadataExt, PermissionsExt},
     path::{Path, PathBuf},
@@ -10,10 +10,55 @@ use log::{error, info};
 use serde::{Deserialize, Serialize};
 use tabled::Tabled;
 
-use crate::{PROJECT_NAME, cf_destination::destination_address, cf_zone::zone_info};
+use crate::{
+    PROJECT_NAME,
+    audit::{AUDIT_FILE_NAME, RMAuditLog},
+    cf_destination::{destination_address, verified_destination_address},
+    cf_email::CloudflareProvider,
+    cf_zone::zone_info,
+    drift::RMKnownRules,
+    expiry::RMExpiryPolicy,
+    generator::RMNameStrategy,
+    http::CFClient,
+    metadata::{METADATA_FILE_NAME, MetadataProvider, RMMetadataStore},
+    postfix::{PostfixProvider, RMPostfixConfig},
+    provider::AliasProvider,
+    site::DEFAULT_SITE_TEMPLATE,
+    tags::TagProvider,
+    trash::{DEFAULT_TRASH_DAYS, RMTrashStore, TrashProvider},
+};
 
 const CONFIG_FILE_NAME: &str = "config.json";
 
+/// The configuration is missing or lacks what's needed to manage aliases
+#[derive(Debug)]
+pub struct RMNotConfigured;
+
+impl fmt::Display for RMNotConfigured {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "configuration file is not ready")
+    }
+}
+
+impl std::error::Error for RMNotConfigured {}
+
+/// `derive` needs a secret and none is configured
+#[derive(Debug)]
+pub struct RMNoDeriveSecret;
+
+impl fmt::Display for RMNoDeriveSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "no derive secret, copy the one of your other machines with \
+             `randomail config --derive-secret <SECRET>` or create one with \
+             `randomail config --new-derive-secret`"
+        )
+    }
+}
+
+impl std::error::Error for RMNoDeriveSecret {}
+
 #[derive(Deserialize, Serialize, Default, Tabled)]
 pub struct RMConfig {
     pub account_id: String,
@@ -22,9 +67,49 @@ pub struct RMConfig {
     pub destination_email_id: String,
     pub zone: String,
     pub zone_id: String,
+    /// Override for the Cloudflare API base URL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[tabled(skip)]
+    pub api_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[tabled(skip)]
+    pub postfix: Option<RMPostfixConfig>,
+    /// Whether expired aliases get disabled or deleted
+    #[serde(default)]
+    #[tabled(skip)]
+    pub expiry_policy: RMExpiryPolicy,
+    /// How `add --random` and the web suggestions build alias names
+    #[serde(default)]
+    #[tabled(skip)]
+    pub name_strategy: RMNameStrategy,
+    /// Template for `add --site` names, `{site}-{rand4}` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[tabled(skip)]
+    pub site_template: Option<String>,
+    /// Secret for `derive`, copy it to every machine that derives aliases
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[tabled(skip)]
+    pub derive_secret: Option<String>,
+    /// Location of the alias metadata database, next to the config by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[tabled(skip)]
+    pub metadata_db: Option<PathBuf>,
+    /// Days deleted aliases can be restored for, 30 by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[tabled(skip)]
+    pub trash_days: Option<u32>,
+    /// Location of the audit log, next to the config by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[tabled(skip)]
+    pub audit_log: Option<PathBuf>,
+    /// The web app runs behind a reverse proxy that authenticates users, so
+    /// the user it passes on can be trusted for the audit log
+    #[serde(default)]
+    #[tabled(skip)]
+    pub trust_proxy_user: bool,
 }
 
-fn get_config_file() -> Result<PathBuf> {
+pub fn get_config_dir() -> Result<PathBuf> {
     let config_root = dirs::config_dir().context("Unable to find config directory")?;
 
     let config_dir = config_root.join(PROJECT_NAME);
@@ -34,7 +119,11 @@ fn get_config_file() -> Result<PathBuf> {
             .with_context(|| format!("Unable to create {}", config_dir.display()))?;
     }
 
-    Ok(config_dir.join(CONFIG_FILE_NAME))
+    Ok(config_dir)
+}
+
+fn get_config_file() -> Result<PathBuf> {
+    Ok(get_config_dir()?.join(CONFIG_FILE_NAME))
 }
 
 impl RMConfig {
@@ -97,6 +186,10 @@ impl RMConfig {
     }
 
     fn ready(&self) -> bool {
+        if self.postfix.is_some() {
+            return self.postfix_ready();
+        }
+
         let mut ready = true;
 
         if self.account_id.is_empty() {
@@ -117,12 +210,29 @@ impl RMConfig {
         ready
     }
 
+    fn postfix_ready(&self) -> bool {
+        let mut ready = true;
+
+        if self.destination_email.is_empty() {
+            eprintln!("destination email is missing from config");
+            ready = false;
+        }
+
+        if self.zone.is_empty() {
+            eprintln!("email domain is missing from config");
+            ready = false;
+        }
+
+        ready
+    }
+
     pub async fn update(
         &mut self,
         account_id: Option<String>,
         token: Option<String>,
         email: Option<String>,
         domain: Option<String>,
+        derive_secret: Option<String>,
     ) -> Result<()> {
         if let Some(account_id) = account_id {
             self.account_id = account_id;
@@ -141,7 +251,7 @@ impl RMConfig {
                 bail!("account_id is missing")
             }
 
-            let dst = destination_address(&self.account_id, &email, &self.token)
+            let dst = destination_address(&self.client(), &self.account_id, &email)
                 .await
                 .with_context(|| format!("Unable to get email id for  {email}"))?;
 
@@ -154,7 +264,7 @@ impl RMConfig {
                 bail!("token is missing")
             }
 
-            let zinfo = zone_info(&zone, &self.token)
+            let zinfo = zone_info(&self.client(), &zone)
                 .await
                 .with_context(|| format!("Unable to get zone info for {zone}"))?;
 
@@ -162,6 +272,14 @@ impl RMConfig {
             self.zone_id = zinfo.id;
         }
 
+        if let Some(derive_secret) = derive_secret {
+            if derive_secret.is_empty() {
+                bail!("derive secret can't be empty")
+            }
+
+            self.derive_secret = Some(derive_secret);
+        }
+
         self.save()
     }
 
@@ -187,12 +305,144 @@ impl RMConfig {
     }
 
     pub fn load() -> Result<Self> {
-        let conf = Self::soft_load()?;
+        let conf = Self::soft_load().context(RMNotConfigured)?;
 
         if !conf.ready() {
-            bail!("configuration file is not ready");
+            return Err(RMNotConfigured.into());
         }
 
         Ok(conf)
     }
+
+    #[must_use]
+    pub fn client(&self) -> CFClient {
+        let mut client = CFClient::new(&self.token);
+
+        if let Some(api_url) = &self.api_url {
+            client = client.with_api_url(api_url);
+        }
+
+        match self.known_rules() {
+            Ok(known) => client = client.with_known_rules(known),
+            Err(e) => error!("Unable to open known rules ({e:#})"),
+        }
+
+        match self.audit_log() {
+            Ok(audit) => client.with_audit(audit),
+            Err(e) => {
+                error!("Unable to open audit log ({e:#})");
+                client
+            }
+        }
+    }
+
+    /// Log of every change made to the routing rules
+    pub fn audit_log(&self) -> Result<RMAuditLog> {
+        let path = match &self.audit_log {
+            Some(path) => path.clone(),
+            None => get_config_dir()?.join(AUDIT_FILE_NAME),
+        };
+
+        Ok(RMAuditLog::new(path))
+    }
+
+    /// Secret for derived aliases. There's no default: an alias derived
+    /// from a made up secret differs from the one other machines derive.
+    pub fn derive_secret(&self) -> Result<&str> {
+        self.derive_secret
+            .as_deref()
+            .ok_or_else(|| RMNoDeriveSecret.into())
+    }
+
+    #[must_use]
+    pub fn site_template(&self) -> &str {
+        self.site_template
+            .as_deref()
+            .unwrap_or(DEFAULT_SITE_TEMPLATE)
+    }
+
+    /// Fail unless aliases can forward to `email`, Cloudflare only forwards
+    /// to verified destination addresses
+    pub async fn check_destination(&self, client: &CFClient, email: &str) -> Result<()> {
+        if self.postfix.is_none() {
+            verified_destination_address(client, &self.account_id, email)
+                .await
+                .with_context(|| format!("Unable to use {email} as destination"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Make `email` the destination of new aliases
+    pub async fn set_destination(&mut self, client: &CFClient, email: &str) -> Result<()> {
+        if self.postfix.is_none() {
+            let dst = verified_destination_address(client, &self.account_id, email)
+                .await
+                .with_context(|| format!("Unable to use {email} as destination"))?;
+
+            self.destination_email_id = dst.id;
+        }
+
+        self.destination_email = email.to_string();
+
+        self.save()
+    }
+
+    fn metadata_path(&self) -> Result<PathBuf> {
+        match &self.metadata_db {
+            Some(path) => Ok(path.clone()),
+            None => Ok(get_config_dir()?.join(METADATA_FILE_NAME)),
+        }
+    }
+
+    pub fn metadata_store(&self) -> Result<RMMetadataStore> {
+        RMMetadataStore::open(self.metadata_path()?)
+    }
+
+    /// Rules as randomail last left them, kept in the metadata database
+    pub fn known_rules(&self) -> Result<RMKnownRules> {
+        Ok(RMKnownRules::new(self.metadata_path()?))
+    }
+
+    /// Trash of deleted aliases, kept in the metadata database
+    pub fn trash_store(&self) -> Result<RMTrashStore> {
+        RMTrashStore::open(
+            self.metadata_path()?,
+            self.trash_days.unwrap_or(DEFAULT_TRASH_DAYS),
+        )
+    }
+
+    pub fn provider(&self) -> Result<Box<dyn AliasProvider>> {
+        self.provider_with(self.client())
+    }
+
+    /// Same as `provider` but Cloudflare requests go through `client`
+    pub fn provider_with(&self, client: CFClient) -> Result<Box<dyn AliasProvider>> {
+        let (backend, rules): (Box<dyn AliasProvider>, _) = match &self.postfix {
+            Some(postfix) => (
+                Box::new(
+                    PostfixProvider::new(&self.zone, postfix.clone()).with_audit(self.audit_log()?),
+                ),
+                None,
+            ),
+            None => (
+                Box::new(CloudflareProvider::new(
+                    client.clone(),
+                    &self.zone,
+                    &self.zone_id,
+                )),
+                Some((client, self.zone_id.clone())),
+            ),
+        };
+
+        let store = self.metadata_store()?;
+
+        let provider = MetadataProvider::new(Box::new(TagProvider::new(backend)), store);
+
+        Ok(Box::new(TrashProvider::new(
+            Box::new(provider),
+            self.trash_store()?,
+            rules,
+        )))
+    }
 }
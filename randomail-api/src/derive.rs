@@ -0,0 +1,103 @@
+use std::{collections::HashSet, hash::BuildHasher};
+
+use anyhow::{Context, Result};
+use data_encoding::{BASE32_NOPAD, HEXLOWER};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Length of a derived local part, 60 bits of the HMAC
+const DERIVED_LEN: usize = 12;
+
+/// Counters looked at when searching for the current alias of a site
+pub const MAX_COUNTER: u32 = 100;
+
+/// A fresh random secret for `derive_secret`
+#[must_use]
+pub fn new_secret() -> String {
+    HEXLOWER.encode(&rand::random::<[u8; 32]>())
+}
+
+/// Local part for `site`, HMAC-SHA256(secret, "site:counter") encoded as
+/// lowercase base32 and truncated. The same inputs give the same alias on
+/// any machine.
+pub fn derive_name(secret: &str, site: &str, counter: u32) -> Result<String> {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).context("Unable to use derive secret")?;
+
+    mac.update(format!("{site}:{counter}").as_bytes());
+
+    let encoded = BASE32_NOPAD
+        .encode(&mac.finalize().into_bytes())
+        .to_lowercase();
+
+    Ok(encoded.chars().take(DERIVED_LEN).collect())
+}
+
+/// Highest counter of `site` whose alias is in `existing`
+pub fn current_counter<S>(
+    existing: &HashSet<String, S>,
+    secret: &str,
+    site: &str,
+) -> Result<Option<u32>>
+where
+    S: BuildHasher,
+{
+    let mut current = None;
+
+    for counter in 0..MAX_COUNTER {
+        if existing.contains(&derive_name(secret, site, counter)?) {
+            current = Some(counter);
+        }
+    }
+
+    Ok(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn derive_name_vectors() -> Result<()> {
+        // computed independently with Python's hmac and base64 modules
+        let cases = [
+            ("github.com", 0, "5h6j4qbe3bkl"),
+            ("github.com", 1, "7fg5xvugx7fq"),
+            ("example.org", 0, "ho5t3yjml4lv"),
+        ];
+
+        for (site, counter, want) in cases {
+            assert_eq!(
+                derive_name(SECRET, site, counter)?,
+                want,
+                "{site}:{counter}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn current_counter_is_the_highest_in_use() -> Result<()> {
+        let mut existing: HashSet<String> = HashSet::new();
+        assert_eq!(current_counter(&existing, SECRET, "github.com")?, None);
+
+        existing.insert("5h6j4qbe3bkl".to_string());
+        existing.insert("ho5t3yjml4lv".to_string());
+        assert_eq!(current_counter(&existing, SECRET, "github.com")?, Some(0));
+
+        existing.insert("7fg5xvugx7fq".to_string());
+        assert_eq!(current_counter(&existing, SECRET, "github.com")?, Some(1));
+
+        assert_eq!(
+            current_counter(&existing, "another secret", "github.com")?,
+            None
+        );
+
+        Ok(())
+    }
+}
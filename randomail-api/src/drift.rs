@@ -0,0 +1,250 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use log::info;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::Serialize;
+
+use crate::{
+    cf_email::{CFEmailRule, list_email_rules},
+    config::RMConfig,
+    http::CFClient,
+    snapshot::{RMRuleChange, compare},
+};
+
+/// The routing rules as randomail last left them, kept in the metadata
+/// database. Every change randomail makes updates them, so anything else
+/// that differs was changed outside of it.
+#[derive(Debug, Clone)]
+pub struct RMKnownRules {
+    path: PathBuf,
+}
+
+impl RMKnownRules {
+    pub fn new<P>(path: P) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        Self { path: path.into() }
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn open(&self) -> Result<Connection> {
+        let conn = Connection::open(&self.path)
+            .with_context(|| format!("Unable to open {}", self.path.display()))?;
+
+        // the CLI and the web app may update the rules at the same time
+        conn.busy_timeout(Duration::from_secs(5))
+            .context("Unable to configure known rules database")?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS known_zones (
+                zone_id TEXT PRIMARY KEY,
+                accepted_at TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS known_rules (
+                zone_id TEXT NOT NULL,
+                rule_id TEXT NOT NULL,
+                known_at TEXT NOT NULL,
+                rule TEXT NOT NULL,
+                PRIMARY KEY (zone_id, rule_id)
+            );",
+        )
+        .context("Unable to create known rules tables")?;
+
+        Ok(conn)
+    }
+
+    /// When the rules of `zone_id` were last accepted as a whole, none when
+    /// they never were
+    pub fn accepted_at(&self, zone_id: &str) -> Result<Option<DateTime<Utc>>> {
+        self.open()?
+            .query_row(
+                "SELECT accepted_at FROM known_zones WHERE zone_id = ?1",
+                params![zone_id],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("Unable to read known rules")
+    }
+
+    pub fn rules(&self, zone_id: &str) -> Result<Vec<CFEmailRule>> {
+        let conn = self.open()?;
+
+        let mut stmt = conn
+            .prepare("SELECT rule FROM known_rules WHERE zone_id = ?1 ORDER BY rule_id")
+            .context("Unable to read known rules")?;
+
+        let rows = stmt
+            .query_map(params![zone_id], |row| row.get::<_, String>(0))
+            .context("Unable to read known rules")?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Unable to read known rules")?;
+
+        rows.iter()
+            .map(|r| serde_json::from_str(r).context("Unable to deserialize known rule"))
+            .collect()
+    }
+
+    /// Take `rules` as the state of `zone_id`, forgetting everything known
+    /// about it before
+    pub fn accept(&self, zone_id: &str, rules: &[CFEmailRule]) -> Result<()> {
+        let mut conn = self.open()?;
+        let now = Utc::now();
+
+        let tx = conn.transaction().context("Unable to update known rules")?;
+
+        tx.execute(
+            "DELETE FROM known_rules WHERE zone_id = ?1",
+            params![zone_id],
+        )
+        .context("Unable to update known rules")?;
+
+        for rule in rules {
+            let json = serde_json::to_string(rule).context("Unable to serialize rule")?;
+
+            tx.execute(
+                "INSERT INTO known_rules (zone_id, rule_id, known_at, rule)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![zone_id, rule.id, now, json],
+            )
+            .with_context(|| format!("Unable to record rule {}", rule.id))?;
+        }
+
+        tx.execute(
+            "INSERT INTO known_zones (zone_id, accepted_at) VALUES (?1, ?2)
+             ON CONFLICT (zone_id) DO UPDATE SET accepted_at = excluded.accepted_at",
+            params![zone_id, now],
+        )
+        .context("Unable to update known rules")?;
+
+        tx.commit().context("Unable to update known rules")?;
+
+        info!("{} rules of {zone_id} accepted as known", rules.len());
+
+        Ok(())
+    }
+
+    /// Record `rule` as randomail just left it
+    pub fn remember(&self, zone_id: &str, rule: &CFEmailRule) -> Result<()> {
+        let json = serde_json::to_string(rule).context("Unable to serialize rule")?;
+
+        self.open()?
+            .execute(
+                "INSERT INTO known_rules (zone_id, rule_id, known_at, rule)
+                 VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (zone_id, rule_id)
+                 DO UPDATE SET known_at = excluded.known_at, rule = excluded.rule",
+                params![zone_id, rule.id, Utc::now(), json],
+            )
+            .with_context(|| format!("Unable to record rule {}", rule.id))?;
+
+        Ok(())
+    }
+
+    /// Record that randomail deleted `rule_id`
+    pub fn forget(&self, zone_id: &str, rule_id: &str) -> Result<()> {
+        self.open()?
+            .execute(
+                "DELETE FROM known_rules WHERE zone_id = ?1 AND rule_id = ?2",
+                params![zone_id, rule_id],
+            )
+            .with_context(|| format!("Unable to forget rule {rule_id}"))?;
+
+        Ok(())
+    }
+}
+
+/// Differences between the rules randomail knows about and the live ones
+#[derive(Debug, Serialize)]
+pub struct RMDrift {
+    pub zone_id: String,
+    /// When the known rules were last accepted as a whole, none when they
+    /// never were and drift isn't tracked yet
+    pub accepted_at: Option<DateTime<Utc>>,
+    pub changes: Vec<RMRuleChange>,
+}
+
+/// Rules added, modified and deleted outside of randomail. Rules are matched
+/// by id, a rule that was recreated by hand shows up as deleted and added.
+#[must_use]
+pub fn drift_changes(known: &[CFEmailRule], live: &[CFEmailRule]) -> Vec<RMRuleChange> {
+    let mut old: HashMap<&str, &CFEmailRule> = known.iter().map(|r| (r.id.as_str(), r)).collect();
+
+    let mut changes = Vec::new();
+
+    for rule in live {
+        match old.remove(rule.id.as_str()) {
+            None => changes.push(RMRuleChange::Added(rule.clone())),
+            Some(prev) => {
+                let fields = compare(prev, rule);
+
+                if !fields.is_empty() {
+                    changes.push(RMRuleChange::Changed {
+                        from: prev.clone(),
+                        to: rule.clone(),
+                        fields,
+                    });
+                }
+            }
+        }
+    }
+
+    for rule in known {
+        if old.remove(rule.id.as_str()).is_some() {
+            changes.push(RMRuleChange::Removed(rule.clone()));
+        }
+    }
+
+    changes
+}
+
+/// Compare the live rules of the configured zone with the known ones. This
+/// only reads: until the rules are accepted a first time with
+/// `accept_drift`, nothing is tracked and no changes are reported.
+pub async fn detect_drift(config: &RMConfig, client: &CFClient) -> Result<RMDrift> {
+    if config.postfix.is_some() {
+        bail!("drift detection is only supported with Cloudflare");
+    }
+
+    let known = config.known_rules()?;
+
+    let Some(accepted_at) = known.accepted_at(&config.zone_id)? else {
+        return Ok(RMDrift {
+            zone_id: config.zone_id.clone(),
+            accepted_at: None,
+            changes: Vec::new(),
+        });
+    };
+
+    let live = list_email_rules(client, &config.zone_id).await?;
+
+    Ok(RMDrift {
+        zone_id: config.zone_id.clone(),
+        accepted_at: Some(accepted_at),
+        changes: drift_changes(&known.rules(&config.zone_id)?, &live),
+    })
+}
+
+/// Accept the live rules of the configured zone as they are, so current
+/// drift is no longer reported. The first accept starts tracking drift.
+pub async fn accept_drift(config: &RMConfig, client: &CFClient) -> Result<usize> {
+    if config.postfix.is_some() {
+        bail!("drift detection is only supported with Cloudflare");
+    }
+
+    let live = list_email_rules(client, &config.zone_id).await?;
+
+    config.known_rules()?.accept(&config.zone_id, &live)?;
+
+    Ok(live.len())
+}
@@ -0,0 +1,137 @@
+use std::fmt;
+
+use crate::{
+    address::RMAddressError,
+    config::{RMNoDeriveSecret, RMNotConfigured},
+    http::CFApiError,
+    lookup::{RMAliasNotFound, RMAmbiguousAlias},
+    provider::{RMAlreadyExists, RMPartialFailure},
+    trash::RMNotInTrash,
+};
+
+/// A value given by the user can't be used, such as a tag, a site or an
+/// expiry date
+#[derive(Debug)]
+pub struct RMInvalidInput(pub String);
+
+impl fmt::Display for RMInvalidInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for RMInvalidInput {}
+
+/// Why an operation failed, shared by the CLI exit codes and the web API
+/// status codes so both report errors the same way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RMErrorKind {
+    Other,
+    NotConfigured,
+    NotFound,
+    AlreadyExists,
+    InvalidAlias,
+    InvalidInput,
+    Cloudflare,
+    PartialFailure,
+}
+
+impl RMErrorKind {
+    #[must_use]
+    pub fn of(err: &anyhow::Error) -> Self {
+        if err.downcast_ref::<RMNotConfigured>().is_some()
+            || err.downcast_ref::<RMNoDeriveSecret>().is_some()
+        {
+            Self::NotConfigured
+        } else if err.downcast_ref::<RMAliasNotFound>().is_some()
+            || err.downcast_ref::<RMNotInTrash>().is_some()
+        {
+            Self::NotFound
+        } else if err.downcast_ref::<RMAlreadyExists>().is_some() {
+            Self::AlreadyExists
+        } else if err.downcast_ref::<RMAddressError>().is_some()
+            || err.downcast_ref::<RMAmbiguousAlias>().is_some()
+        {
+            Self::InvalidAlias
+        } else if err.downcast_ref::<RMInvalidInput>().is_some() {
+            Self::InvalidInput
+        } else if err.downcast_ref::<RMPartialFailure>().is_some() {
+            Self::PartialFailure
+        } else if err.downcast_ref::<CFApiError>().is_some() {
+            Self::Cloudflare
+        } else {
+            Self::Other
+        }
+    }
+}
+
+impl fmt::Display for RMErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            Self::Other => "error",
+            Self::NotConfigured => "not_configured",
+            Self::NotFound => "not_found",
+            Self::AlreadyExists => "already_exists",
+            Self::InvalidAlias => "invalid_alias",
+            Self::InvalidInput => "invalid_input",
+            Self::Cloudflare => "cloudflare",
+            Self::PartialFailure => "partial_failure",
+        };
+
+        f.write_str(kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{Context, anyhow};
+
+    use super::*;
+    use crate::{expiry::parse_expiry, site::normalize_site, tags::normalize_tag};
+
+    #[test]
+    fn kinds_of_typed_errors() {
+        let not_found = anyhow::Error::from(RMAliasNotFound {
+            query: "shop".to_string(),
+        });
+        assert_eq!(RMErrorKind::of(&not_found), RMErrorKind::NotFound);
+
+        let ambiguous = anyhow::Error::from(RMAmbiguousAlias {
+            query: "shop".to_string(),
+            aliases: Vec::new(),
+        });
+        assert_eq!(RMErrorKind::of(&ambiguous), RMErrorKind::InvalidAlias);
+
+        let no_secret = anyhow::Error::from(RMNoDeriveSecret);
+        assert_eq!(RMErrorKind::of(&no_secret), RMErrorKind::NotConfigured);
+
+        let refused = anyhow::Error::from(CFApiError {
+            url: "https://api.example".to_string(),
+            status: 403,
+            body: String::new(),
+        });
+        assert_eq!(RMErrorKind::of(&refused), RMErrorKind::Cloudflare);
+
+        assert_eq!(RMErrorKind::of(&anyhow!("disk full")), RMErrorKind::Other);
+    }
+
+    #[test]
+    fn kinds_survive_context() {
+        let err = Err::<(), _>(anyhow!("no file"))
+            .context(RMNotConfigured)
+            .context("Unable to load config");
+        assert!(err.is_err_and(|e| RMErrorKind::of(&e) == RMErrorKind::NotConfigured));
+    }
+
+    #[test]
+    fn invalid_user_input() {
+        for err in [
+            normalize_tag("1st").err(),
+            normalize_site("not a site").err(),
+            parse_expiry("yesterday").err(),
+            parse_expiry("2020-01-01").err(),
+        ] {
+            assert!(err.is_some_and(|e| RMErrorKind::of(&e) == RMErrorKind::InvalidInput));
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::RMInvalidInput,
+    http::CFApiError,
+    lookup::RMAliasNotFound,
+    metadata::{RMMetadataStore, RMMetadataUpdate},
+    provider::{AliasProvider, RMAlias},
+};
+
+/// What happens to an alias once it expired
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RMExpiryPolicy {
+    #[default]
+    Disable,
+    Delete,
+}
+
+/// Parse either a duration from now (`30d`, `12h`, `2w`) or an absolute
+/// date (`2025-12-31`) or RFC 3339 timestamp, which must be in the future
+pub fn parse_expiry(value: &str) -> Result<DateTime<Utc>> {
+    let value = value.trim();
+    let now = Utc::now();
+
+    let date = if let Ok(date) = DateTime::parse_from_rfc3339(value) {
+        date.with_timezone(&Utc)
+    } else if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        date.and_time(chrono::NaiveTime::MIN).and_utc()
+    } else {
+        let invalid = |what: &str| RMInvalidInput(format!("{value} {what}"));
+
+        let duration = humantime::parse_duration(value)
+            .map_err(|_| invalid("is neither a date nor a duration"))?;
+
+        let duration = chrono::Duration::from_std(duration)
+            .map_err(|_| invalid("is too far in the future"))?;
+
+        now.checked_add_signed(duration)
+            .ok_or_else(|| invalid("is too far in the future"))?
+    };
+
+    if date <= now {
+        return Err(RMInvalidInput(format!("{value} is not in the future")).into());
+    }
+
+    Ok(date)
+}
+
+/// Whether `err` says the alias is gone, deleted outside of randomail
+fn is_gone(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<RMAliasNotFound>().is_some()
+        || err
+            .downcast_ref::<CFApiError>()
+            .is_some_and(|e| e.status == 404)
+}
+
+/// Apply `policy` to every alias whose expiry has passed and return the ones
+/// that changed. Expiries are looked up in `store`, so the backend is only
+/// asked about expired aliases, and not at all when there are none. An
+/// applied expiry is cleared, so an alias enabled again stays enabled.
+pub async fn reap(
+    provider: &dyn AliasProvider,
+    store: &RMMetadataStore,
+    policy: RMExpiryPolicy,
+) -> Result<Vec<RMAlias>> {
+    let mut reaped = Vec::new();
+
+    for id in store.expired(Utc::now())? {
+        let alias = match provider.get(&id).await {
+            Ok(alias) => alias,
+            Err(e) if is_gone(&e) => {
+                info!("{id} expired but no longer exists");
+                store.remove(&id)?;
+                continue;
+            }
+            Err(e) => {
+                error!("Unable to expire {id} ({e})");
+                continue;
+            }
+        };
+
+        let res = match policy {
+            RMExpiryPolicy::Disable if !alias.enabled => Ok(()),
+            RMExpiryPolicy::Disable => provider.set_enabled(&alias.id, false).await,
+            RMExpiryPolicy::Delete => provider.delete(&alias.id).await,
+        };
+
+        if let Err(e) = res {
+            error!("Unable to expire {} ({e})", alias.email_alias);
+            continue;
+        }
+
+        if policy == RMExpiryPolicy::Disable {
+            let update = RMMetadataUpdate {
+                clear_expiry: true,
+                ..RMMetadataUpdate::default()
+            };
+
+            store.update(&alias.id, &update)?;
+        }
+
+        if alias.enabled || policy == RMExpiryPolicy::Delete {
+            info!("{} expired", alias.email_alias);
+            reaped.push(alias);
+        }
+    }
+
+    Ok(reaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn parse_expiry_accepts_future() -> Result<()> {
+        let now = Utc::now();
+
+        let date = parse_expiry("30d")?;
+        assert!(
+            date > now
+                .checked_add_signed(chrono::Duration::days(29))
+                .context("overflow")?
+        );
+
+        assert_eq!(
+            parse_expiry("2999-12-31")?,
+            DateTime::parse_from_rfc3339("2999-12-31T00:00:00Z")?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_expiry_rejects_past() {
+        assert!(parse_expiry("2020-01-01").is_err_and(|e| e.is::<RMInvalidInput>()));
+        assert!(parse_expiry("2020-01-01T12:00:00+02:00").is_err());
+        assert!(parse_expiry("0s").is_err());
+        assert!(parse_expiry("soon").is_err());
+    }
+}
@@ -0,0 +1,166 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::provider::RMAlias;
+
+/// File formats aliases can be exported to
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RMExportFormat {
+    #[default]
+    Json,
+    Csv,
+}
+
+impl RMExportFormat {
+    #[must_use]
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    #[must_use]
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Csv => "csv",
+        }
+    }
+}
+
+impl fmt::Display for RMExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+impl FromStr for RMExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(Self::Json),
+            "csv" => Ok(Self::Csv),
+            _ => bail!("unknown format {s}, use json or csv"),
+        }
+    }
+}
+
+/// One exported alias, every field is always present so the output has the
+/// same shape whatever the backend
+#[derive(Debug, Serialize)]
+pub struct RMExportRow {
+    pub id: String,
+    pub alias: String,
+    pub destinations: Vec<String>,
+    pub description: String,
+    pub tags: Vec<String>,
+    pub enabled: bool,
+    pub created_at: Option<DateTime<Utc>>,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub creator: Option<String>,
+    pub website: Option<String>,
+    pub notes: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub replaces: Option<String>,
+    pub replaced_by: Option<String>,
+}
+
+impl From<&RMAlias> for RMExportRow {
+    fn from(alias: &RMAlias) -> Self {
+        let m = &alias.metadata;
+
+        Self {
+            id: alias.id.clone(),
+            alias: alias.email_alias.clone(),
+            destinations: alias.destinations().map(str::to_string).collect(),
+            description: alias.name.clone(),
+            tags: alias.tags.clone(),
+            enabled: alias.enabled,
+            created_at: m.created_at,
+            updated_at: m.updated_at,
+            creator: m.creator.clone(),
+            website: m.website.clone(),
+            notes: m.notes.clone(),
+            expires_at: m.expires_at,
+            replaces: m.replaces.clone(),
+            replaced_by: m.replaced_by.clone(),
+        }
+    }
+}
+
+const CSV_HEADER: &[&str] = &[
+    "id",
+    "alias",
+    "destinations",
+    "description",
+    "tags",
+    "enabled",
+    "created_at",
+    "updated_at",
+    "creator",
+    "website",
+    "notes",
+    "expires_at",
+    "replaces",
+    "replaced_by",
+];
+
+fn csv_date(date: Option<DateTime<Utc>>) -> String {
+    date.map(|d| d.to_rfc3339_opts(SecondsFormat::Secs, true))
+        .unwrap_or_default()
+}
+
+impl RMExportRow {
+    /// Lists are space separated, missing values are empty
+    fn csv_record(&self) -> Vec<String> {
+        vec![
+            self.id.clone(),
+            self.alias.clone(),
+            self.destinations.join(" "),
+            self.description.clone(),
+            self.tags.join(" "),
+            self.enabled.to_string(),
+            csv_date(self.created_at),
+            csv_date(self.updated_at),
+            self.creator.clone().unwrap_or_default(),
+            self.website.clone().unwrap_or_default(),
+            self.notes.clone().unwrap_or_default(),
+            csv_date(self.expires_at),
+            self.replaces.clone().unwrap_or_default(),
+            self.replaced_by.clone().unwrap_or_default(),
+        ]
+    }
+}
+
+fn export_csv(rows: &[RMExportRow]) -> Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+
+    writer.write_record(CSV_HEADER)?;
+
+    for row in rows {
+        writer.write_record(row.csv_record())?;
+    }
+
+    writer.into_inner().context("Unable to write CSV")
+}
+
+/// Encode `aliases` in `format`, sorted by address so exports can be diffed
+pub fn export(aliases: &[RMAlias], format: RMExportFormat) -> Result<Vec<u8>> {
+    let mut rows: Vec<RMExportRow> = aliases.iter().map(RMExportRow::from).collect();
+    rows.sort_by(|a, b| a.alias.cmp(&b.alias));
+
+    match format {
+        RMExportFormat::Json => {
+            let mut data = serde_json::to_vec_pretty(&rows).context("Unable to serialize data")?;
+            data.push(b'\n');
+            Ok(data)
+        }
+        RMExportFormat::Csv => export_csv(&rows),
+    }
+}
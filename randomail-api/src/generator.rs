@@ -0,0 +1,182 @@
+use std::{collections::HashSet, fmt, hash::BuildHasher, str::FromStr};
+
+use anyhow::{Result, bail};
+use rand::{Rng, RngExt, seq::IndexedRandom};
+use serde::{Deserialize, Serialize};
+
+use crate::provider::AliasProvider;
+
+const ADJECTIVES: &[&str] = &[
+    "quiet", "bold", "swift", "calm", "dark", "bright", "cool", "warm", "deep", "vast", "keen",
+    "slim", "fair", "wild", "soft", "raw", "pale", "pure", "dry", "shy", "odd", "rare", "vivid",
+    "crisp", "brisk", "noble", "sleek", "lucid", "rapid", "stark", "mellow", "fierce", "gentle",
+    "rustic", "frosty", "dusty", "golden", "silent", "hidden", "cosmic", "lunar", "solar",
+    "arctic", "misty", "foggy", "stormy", "snowy", "ashen", "azure", "ivory", "amber", "copper",
+    "silver", "velvet", "rugged", "hollow", "jagged", "mossy", "sandy", "stony", "cloudy",
+    "starry", "windy", "icy", "hazy", "smoky", "thorny", "shady", "dusky", "rosy", "tawny",
+    "murky", "nimble", "steady", "humble", "witty", "jolly", "daring", "clever", "mighty",
+    "ancient", "phantom", "spectral", "digital", "primal", "eternal", "crystal", "woven", "brazen",
+    "placid", "serene", "solemn", "muted", "subtle", "opaque", "barren", "lofty", "quaint",
+    "dapper", "plucky", "zesty", "peppy", "spry", "gruff", "lanky", "meek", "prim", "wry", "coy",
+    "sly",
+];
+
+const NOUNS: &[&str] = &[
+    "fox", "owl", "elm", "oak", "ash", "bay", "gem", "orb", "dew", "fog", "ink", "ray", "sky",
+    "reef", "tide", "peak", "glen", "vale", "moss", "fern", "haze", "dusk", "dawn", "crow", "dove",
+    "wolf", "bear", "lynx", "hare", "pike", "moth", "wren", "hawk", "lark", "sage", "pine", "reed",
+    "cliff", "brook", "spark", "flame", "frost", "stone", "cloud", "trail", "grove", "drift",
+    "ember", "coral", "ridge", "delta", "crest", "bluff", "shard", "prism", "bolt", "arch", "helm",
+    "anvil", "flint", "glyph", "rune", "nexus", "forge", "vault", "spire", "comet", "orbit",
+    "pulse", "echo", "cipher", "haven", "ledge", "canyon", "marsh", "shoal", "dune", "thorn",
+    "bloom", "petal", "cedar", "birch", "maple", "aspen", "willow", "clover", "brine", "finch",
+    "crane", "egret", "otter", "raven", "falcon", "badger", "marten", "stoat", "quartz", "slate",
+    "opal", "jade", "onyx", "node", "pixel", "byte", "relay", "signal", "cinder", "summit",
+    "torrent", "rapids",
+];
+
+const BASE32_ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+const BASE32_LEN: usize = 10;
+
+const CONSONANTS: &[u8] = b"bdfghjklmnprstvz";
+const VOWELS: &[u8] = b"aeiou";
+const PRONOUNCEABLE_SYLLABLES: usize = 4;
+
+/// Give up looking for unused names after this many tries per suggestion
+pub(crate) const MAX_ATTEMPTS: usize = 10;
+
+/// How random alias names are built
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RMNameStrategy {
+    /// `quiet-otter`
+    #[default]
+    Words,
+    /// `k3vq7zt2ma`
+    Base32,
+    /// `9f1c2ab4`, the first group of a random UUID
+    UuidShort,
+    /// `tobakemi`
+    Pronounceable,
+}
+
+impl fmt::Display for RMNameStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Words => "words",
+            Self::Base32 => "base32",
+            Self::UuidShort => "uuid-short",
+            Self::Pronounceable => "pronounceable",
+        };
+
+        f.write_str(name)
+    }
+}
+
+impl FromStr for RMNameStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "words" => Ok(Self::Words),
+            "base32" => Ok(Self::Base32),
+            "uuid-short" => Ok(Self::UuidShort),
+            "pronounceable" => Ok(Self::Pronounceable),
+            _ => bail!("unknown strategy {s}, use words, base32, uuid-short or pronounceable"),
+        }
+    }
+}
+
+fn pick_chars<R>(rng: &mut R, alphabet: &[u8], count: usize) -> String
+where
+    R: Rng + ?Sized,
+{
+    (0..count)
+        .filter_map(|_| alphabet.choose(rng))
+        .map(|c| char::from(*c))
+        .collect()
+}
+
+/// `count` random characters from the base32 alphabet
+pub(crate) fn random_chars(count: usize) -> String {
+    pick_chars(&mut rand::rng(), BASE32_ALPHABET, count)
+}
+
+/// Generate one alias local part, without checking whether it's in use
+#[must_use]
+pub fn generate(strategy: RMNameStrategy) -> String {
+    let mut rng = rand::rng();
+
+    match strategy {
+        RMNameStrategy::Words => {
+            let adjective = ADJECTIVES.choose(&mut rng).unwrap_or(&"quiet");
+            let noun = NOUNS.choose(&mut rng).unwrap_or(&"otter");
+            format!("{adjective}-{noun}")
+        }
+        RMNameStrategy::Base32 => pick_chars(&mut rng, BASE32_ALPHABET, BASE32_LEN),
+        RMNameStrategy::UuidShort => format!("{:08x}", rng.random::<u32>()),
+        RMNameStrategy::Pronounceable => (0..PRONOUNCEABLE_SYLLABLES)
+            .map(|_| {
+                let mut syllable = pick_chars(&mut rng, CONSONANTS, 1);
+                syllable.push_str(&pick_chars(&mut rng, VOWELS, 1));
+                syllable
+            })
+            .collect(),
+    }
+}
+
+/// Generate up to `count` distinct names that aren't in `existing`
+#[must_use]
+pub fn suggestions<S>(
+    strategy: RMNameStrategy,
+    existing: &HashSet<String, S>,
+    count: usize,
+) -> Vec<String>
+where
+    S: BuildHasher,
+{
+    let mut names = Vec::new();
+
+    for _ in 0..count.saturating_mul(MAX_ATTEMPTS) {
+        if names.len() >= count {
+            break;
+        }
+
+        let name = generate(strategy);
+
+        if !existing.contains(&name) && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+
+    names
+}
+
+/// Local parts of the aliases `provider` already has, lowercased
+pub async fn existing_names(provider: &dyn AliasProvider) -> Result<HashSet<String>> {
+    Ok(provider
+        .list()
+        .await?
+        .iter()
+        .filter_map(|a| a.email_alias.split_once('@'))
+        .map(|(local, _)| local.to_lowercase())
+        .collect())
+}
+
+/// Suggest `count` names not used by any alias of `provider`
+pub async fn suggest(
+    provider: &dyn AliasProvider,
+    strategy: RMNameStrategy,
+    count: usize,
+) -> Result<Vec<String>> {
+    let existing = existing_names(provider).await?;
+    Ok(suggestions(strategy, &existing, count))
+}
+
+/// A single unused name, for creating an alias right away
+pub async fn unique_name(provider: &dyn AliasProvider, strategy: RMNameStrategy) -> Result<String> {
+    match suggest(provider, strategy, 1).await?.pop() {
+        Some(name) => Ok(name),
+        None => bail!("Unable to find an unused {strategy} name"),
+    }
+}
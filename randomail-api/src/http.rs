@@ -1,123 +1,302 @@
-use std::fmt::Display;
+pub mod cassette;
 
-use anyhow::{Context, Result, bail};
-use serde::Serialize;
+use std::{
+    fmt::{self, Display},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use log::info;
+use reqwest::{Method, StatusCode, header::RETRY_AFTER};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use cassette::CFCassette;
+
+use crate::{audit::RMAuditLog, drift::RMKnownRules};
 
 pub const CF_API_URL: &str = "https://api.cloudflare.com/client/v4";
 const CF_USER_AGENT: &str = "RandoMail 1.0";
+const CF_PER_PAGE: usize = 50;
+/// Times a rate limited request is retried before giving up
+const CF_MAX_RETRIES: u32 = 5;
+/// Longest `Retry-After` honoured, a rate limited request asking for a
+/// longer wait fails instead of hanging the CLI or the web app
+const CF_MAX_RETRY_WAIT: Duration = Duration::from_mins(1);
+
+/// Cloudflare answered a request with an error status
+#[derive(Debug)]
+pub struct CFApiError {
+    pub url: String,
+    pub status: u16,
+    /// Body of the response, usually JSON listing the errors
+    pub body: String,
+}
+
+impl fmt::Display for CFApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} returned {} {}", self.url, self.status, self.body)
+    }
+}
+
+impl std::error::Error for CFApiError {}
+
+/// How requests reach the Cloudflare API
+#[derive(Clone, Default)]
+pub enum CFTransport {
+    #[default]
+    Live,
+    /// Issue live requests and append every exchange to a cassette
+    Record(Arc<CFCassette>),
+    /// Answer requests from a cassette without touching the network
+    Replay(Arc<CFCassette>),
+}
+
+/// Where and how to reach the Cloudflare API
+#[derive(Clone)]
+pub struct CFClient {
+    api_url: String,
+    token: String,
+    transport: CFTransport,
+    audit: Option<RMAuditLog>,
+    known: Option<RMKnownRules>,
+}
+
+impl CFClient {
+    pub fn new<T>(token: T) -> Self
+    where
+        T: Into<String>,
+    {
+        Self {
+            api_url: CF_API_URL.to_string(),
+            token: token.into(),
+            transport: CFTransport::Live,
+            audit: None,
+            known: None,
+        }
+    }
+
+    /// Talk to `api_url` instead of the public Cloudflare API
+    #[must_use]
+    pub fn with_api_url<U>(mut self, api_url: U) -> Self
+    where
+        U: Into<String>,
+    {
+        self.api_url = api_url.into().trim_end_matches('/').to_string();
+        self
+    }
+
+    #[must_use]
+    pub fn with_transport(mut self, transport: CFTransport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Record every change made to routing rules in `audit`
+    #[must_use]
+    pub fn with_audit(mut self, audit: RMAuditLog) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    #[must_use]
+    pub fn audit(&self) -> Option<&RMAuditLog> {
+        self.audit.as_ref()
+    }
+
+    /// Keep `known` up to date with every change made to routing rules
+    #[must_use]
+    pub fn with_known_rules(mut self, known: RMKnownRules) -> Self {
+        self.known = Some(known);
+        self
+    }
+
+    #[must_use]
+    pub fn known_rules(&self) -> Option<&RMKnownRules> {
+        self.known.as_ref()
+    }
+
+    #[must_use]
+    pub fn api_url(&self) -> &str {
+        &self.api_url
+    }
+
+    #[must_use]
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+}
+
+#[derive(Deserialize)]
+struct CFResultInfo {
+    page: Option<usize>,
+    total_pages: Option<usize>,
+    total_count: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct CFPagedResponse<R> {
+    result: Vec<R>,
+    result_info: Option<CFResultInfo>,
+}
+
+async fn issue_live(
+    client: &CFClient,
+    method: Method,
+    url: &str,
+    body: Option<&str>,
+) -> Result<(u16, String)> {
+    let mut attempt: u32 = 0;
+
+    loop {
+        let mut req = reqwest::Client::new()
+            .request(method.clone(), url)
+            .bearer_auth(&client.token)
+            .header("User-Agent", CF_USER_AGENT);
+
+        if let Some(body) = body {
+            req = req
+                .header("Content-Type", "application/json")
+                .body(body.to_string());
+        }
+
+        let res = req
+            .send()
+            .await
+            .with_context(|| format!("Unable to issue {method} to {url}"))?;
+
+        // rate limited, wait as long as asked to (or back off) and try again,
+        // unless that's longer than we're willing to wait
+        if res.status() == StatusCode::TOO_MANY_REQUESTS && attempt < CF_MAX_RETRIES {
+            let wait = res
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .map_or_else(
+                    || Duration::from_secs(2_u64.saturating_pow(attempt)),
+                    Duration::from_secs,
+                );
+
+            if wait <= CF_MAX_RETRY_WAIT {
+                info!("{url} is rate limited, retrying in {}s", wait.as_secs());
+
+                tokio::time::sleep(wait).await;
+                attempt = attempt.saturating_add(1);
+                continue;
+            }
+
+            info!("{url} is rate limited for {}s, giving up", wait.as_secs());
+        }
+
+        let status = res.status().as_u16();
+
+        let data = res
+            .text()
+            .await
+            .with_context(|| format!("Unable to read {method} response from {url}"))?;
+
+        return Ok((status, data));
+    }
+}
 
-pub async fn issue_put<D, U, T>(url: U, api_token: T, data: &D) -> Result<()>
+async fn issue<U>(client: &CFClient, method: Method, url: U, body: Option<&str>) -> Result<String>
 where
-    T: AsRef<str> + Display,
     U: AsRef<str> + Display,
-    D: Serialize,
 {
-    let client = reqwest::Client::new();
-
-    let res = client
-        .put(url.as_ref())
-        .bearer_auth(api_token)
-        .header("User-Agent", CF_USER_AGENT)
-        .header("Content-Type", "application/json")
-        .json(data)
-        .send()
-        .await
-        .with_context(|| format!("Unable to issue PUT to {}", url.as_ref()))?;
-
-    let status = res.status();
-
-    if !res.status().is_success() {
-        if let Ok(data) = res.text().await {
-            bail!("{} returned {} {data}", url.as_ref(), status);
+    let (status, data) = match &client.transport {
+        CFTransport::Live => issue_live(client, method, url.as_ref(), body).await?,
+        CFTransport::Record(cassette) => {
+            let (status, data) = issue_live(client, method.clone(), url.as_ref(), body).await?;
+            cassette
+                .record(client, &method, url.as_ref(), body, status, &data)
+                .await?;
+            (status, data)
+        }
+        CFTransport::Replay(cassette) => cassette.replay(client, &method, url.as_ref(), body)?,
+    };
+
+    if !(200..300).contains(&status) {
+        return Err(CFApiError {
+            url: url.to_string(),
+            status,
+            body: data,
         }
-        bail!("{} returned {}", url.as_ref(), status);
+        .into());
     }
 
-    Ok(())
+    Ok(data)
 }
 
-pub async fn issue_delete<U, T>(url: U, api_token: T) -> Result<()>
+pub async fn issue_put<D, U>(client: &CFClient, url: U, data: &D) -> Result<String>
 where
-    T: AsRef<str> + Display,
     U: AsRef<str> + Display,
+    D: Serialize,
 {
-    let client = reqwest::Client::new();
-
-    let res = client
-        .delete(url.as_ref())
-        .bearer_auth(api_token)
-        .header("User-Agent", CF_USER_AGENT)
-        .header("Content-Type", "application/json")
-        .send()
-        .await
-        .with_context(|| format!("Unable to issue DELETE to {}", url.as_ref()))?;
-
-    let status = res.status();
-
-    if !res.status().is_success() {
-        if let Ok(data) = res.text().await {
-            bail!("{} returned {} {data}", url.as_ref(), status);
-        }
-        bail!("{} returned {}", url.as_ref(), status);
-    }
+    let body = serde_json::to_string(data).context("Unable to serialize data")?;
+    issue(client, Method::PUT, url, Some(&body)).await
+}
 
-    Ok(())
+pub async fn issue_delete<U>(client: &CFClient, url: U) -> Result<String>
+where
+    U: AsRef<str> + Display,
+{
+    issue(client, Method::DELETE, url, None).await
 }
 
-pub async fn issue_post<D, U, T>(url: U, api_token: T, data: &D) -> Result<()>
+pub async fn issue_post<D, U>(client: &CFClient, url: U, data: &D) -> Result<String>
 where
-    T: AsRef<str> + Display,
     U: AsRef<str> + Display,
     D: Serialize,
 {
-    let client = reqwest::Client::new();
-
-    let res = client
-        .post(url.as_ref())
-        .bearer_auth(api_token)
-        .header("User-Agent", CF_USER_AGENT)
-        .header("Content-Type", "application/json")
-        .json(data)
-        .send()
-        .await
-        .with_context(|| format!("Unable to issue POST to {}", url.as_ref()))?;
-
-    let status = res.status();
-
-    if !res.status().is_success() {
-        if let Ok(data) = res.text().await {
-            bail!("{} returned {} {data}", url.as_ref(), status);
-        }
-        bail!("{} returned {}", url.as_ref(), status);
-    }
+    let body = serde_json::to_string(data).context("Unable to serialize data")?;
+    issue(client, Method::POST, url, Some(&body)).await
+}
 
-    Ok(())
+pub async fn issue_get<U>(client: &CFClient, url: U) -> Result<String>
+where
+    U: AsRef<str> + Display,
+{
+    issue(client, Method::GET, url, None).await
 }
 
-pub async fn issue_get<U, T>(url: U, api_token: T) -> Result<String>
+pub async fn issue_get_paged<R, U>(client: &CFClient, url: U) -> Result<Vec<R>>
 where
-    T: AsRef<str> + Display,
+    R: DeserializeOwned,
     U: AsRef<str> + Display,
 {
-    let client = reqwest::Client::new();
-
-    let res = client
-        .get(url.as_ref())
-        .bearer_auth(api_token)
-        .header("User-Agent", CF_USER_AGENT)
-        .send()
-        .await
-        .with_context(|| format!("Unable to issue GET {}", url.as_ref()))?;
-
-    let status = res.status();
-    let data = res
-        .text()
-        .await
-        .with_context(|| format!("Unable to GET data from{}", url.as_ref()))?;
-
-    if !status.is_success() {
-        bail!("{url} returned {status} {data}");
+    let mut results = Vec::new();
+    let mut page: usize = 1;
+
+    loop {
+        let page_url = format!("{url}?page={page}&per_page={CF_PER_PAGE}");
+
+        let data = issue_get(client, &page_url).await?;
+
+        let response: CFPagedResponse<R> =
+            serde_json::from_str(&data).with_context(|| format!("Unable to deserialize {data}"))?;
+
+        let count = response.result.len();
+        results.extend(response.result);
+
+        let Some(info) = response.result_info else {
+            break;
+        };
+
+        let cur_page = info.page.unwrap_or(page);
+
+        let more = match (info.total_pages, info.total_count) {
+            (Some(total_pages), _) => cur_page < total_pages,
+            (None, Some(total_count)) => results.len() < total_count,
+            (None, None) => count == CF_PER_PAGE,
+        };
+
+        if !more || count == 0 {
+            break;
+        }
+
+        page = cur_page.saturating_add(1);
     }
 
-    Ok(data)
+    Ok(results)
 }
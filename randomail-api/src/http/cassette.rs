@@ -0,0 +1,176 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use anyhow::{Context, Result, bail};
+use log::info;
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+
+use super::CFClient;
+
+const REDACTED: &str = "<REDACTED>";
+
+/// One request/response exchange with the Cloudflare API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CFInteraction {
+    pub method: String,
+    /// Request URL relative to the API base URL
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<String>,
+    pub status: u16,
+    pub response: String,
+}
+
+#[derive(Default)]
+struct CFCassetteState {
+    interactions: Vec<CFInteraction>,
+    used: Vec<bool>,
+}
+
+/// Recorded Cloudflare API exchanges, stored as one JSON object per line.
+/// The API token is never written to the file.
+pub struct CFCassette {
+    path: PathBuf,
+    state: Mutex<CFCassetteState>,
+    /// File exchanges are appended to, only when recording
+    recorder: Option<tokio::sync::Mutex<tokio::fs::File>>,
+}
+
+impl CFCassette {
+    /// Start an empty cassette at `path`, replacing any previous one
+    pub fn create<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+
+        let file = fs::File::create(path)
+            .with_context(|| format!("Unable to create {}", path.display()))?;
+
+        info!("recording to {}", path.display());
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            state: Mutex::new(CFCassetteState::default()),
+            recorder: Some(tokio::sync::Mutex::new(tokio::fs::File::from_std(file))),
+        })
+    }
+
+    /// Load a previously recorded cassette for replay
+    pub fn open<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read {}", path.display()))?;
+
+        let interactions = data
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<CFInteraction>, _>>()
+            .with_context(|| format!("Unable to deserialize {}", path.display()))?;
+
+        let used = vec![false; interactions.len()];
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            state: Mutex::new(CFCassetteState { interactions, used }),
+            recorder: None,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CFCassetteState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Append an exchange to the cassette, so what was recorded so far is
+    /// kept even if the command doesn't finish
+    pub(super) async fn record(
+        &self,
+        client: &CFClient,
+        method: &Method,
+        url: &str,
+        request: Option<&str>,
+        status: u16,
+        response: &str,
+    ) -> Result<()> {
+        let interaction = CFInteraction {
+            method: method.to_string(),
+            path: redact(client, relative_path(client, url)),
+            request: request.map(|r| redact(client, r)),
+            status,
+            response: redact(client, response),
+        };
+
+        let Some(recorder) = &self.recorder else {
+            bail!("{} was opened for replay", self.path.display());
+        };
+
+        let mut line =
+            serde_json::to_string(&interaction).context("Unable to serialize cassette")?;
+        line.push('\n');
+
+        let mut file = recorder.lock().await;
+
+        file.write_all(line.as_bytes())
+            .await
+            .with_context(|| format!("Unable to write to {}", self.path.display()))?;
+
+        file.flush()
+            .await
+            .with_context(|| format!("Unable to write to {}", self.path.display()))
+    }
+
+    pub(super) fn replay(
+        &self,
+        client: &CFClient,
+        method: &Method,
+        url: &str,
+        request: Option<&str>,
+    ) -> Result<(u16, String)> {
+        let path = redact(client, relative_path(client, url));
+        let request = request.map(|r| redact(client, r));
+
+        let mut state = self.lock();
+        let CFCassetteState { interactions, used } = &mut *state;
+
+        for (interaction, used) in interactions.iter().zip(used.iter_mut()) {
+            if !*used
+                && interaction.method == method.as_str()
+                && interaction.path == path
+                && (request.is_none() || interaction.request == request)
+            {
+                *used = true;
+                return Ok((interaction.status, interaction.response.clone()));
+            }
+        }
+
+        bail!(
+            "no recorded response for {method} {path} in {}",
+            self.path.display()
+        )
+    }
+}
+
+fn relative_path<'a>(client: &CFClient, url: &'a str) -> &'a str {
+    url.strip_prefix(client.api_url()).unwrap_or(url)
+}
+
+fn redact<S>(client: &CFClient, data: S) -> String
+where
+    S: AsRef<str>,
+{
+    if client.token().is_empty() {
+        return data.as_ref().to_string();
+    }
+
+    data.as_ref().replace(client.token(), REDACTED)
+}
@@ -0,0 +1,578 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use anyhow::{Context, Result, bail};
+use futures_util::{StreamExt, stream};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tabled::Tabled;
+
+use crate::{
+    address::parse_address,
+    config::RMConfig,
+    expiry::parse_expiry,
+    export::RMExportFormat,
+    http::CFClient,
+    metadata::RMMetadataUpdate,
+    provider::{AliasProvider, RMAlreadyExists},
+    tags::{encode_tags, normalize_tag},
+};
+
+/// Aliases created at the same time, the HTTP layer backs off on top of
+/// this when Cloudflare rate limits us
+pub const IMPORT_CONCURRENCY: usize = 4;
+
+const fn default_enabled() -> bool {
+    true
+}
+
+/// One alias read from an import file, the same fields `export` writes
+#[derive(Debug, Default, Deserialize)]
+pub struct RMImportRow {
+    #[serde(default)]
+    pub alias: String,
+    #[serde(default, alias = "name")]
+    pub description: String,
+    /// Defaults to the configured destination
+    #[serde(default)]
+    pub destinations: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub website: Option<String>,
+    #[serde(default)]
+    pub notes: Option<String>,
+    /// Duration or date, see `parse_expiry`
+    #[serde(default)]
+    pub expires_at: Option<String>,
+}
+
+/// What happened to a row of the import file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RMImportStatus {
+    Created,
+    /// Would be created, this was a dry run
+    Planned,
+    /// The address already has an alias, or appears earlier in the file
+    Duplicate,
+    /// The row can't be turned into an alias
+    Invalid,
+    /// The backend refused to create the alias
+    Failed,
+}
+
+impl fmt::Display for RMImportStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            Self::Created => "created",
+            Self::Planned => "planned",
+            Self::Duplicate => "duplicate",
+            Self::Invalid => "invalid",
+            Self::Failed => "failed",
+        };
+
+        f.write_str(status)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Tabled)]
+pub struct RMImportResult {
+    /// Position in the file, starting at 1 with the first alias
+    pub row: usize,
+    pub alias: String,
+    pub status: RMImportStatus,
+    pub message: String,
+}
+
+/// A row that passed validation
+struct RMImportAlias {
+    row: usize,
+    email_alias: String,
+    name: String,
+    email_dest: String,
+    enabled: bool,
+    update: RMMetadataUpdate,
+}
+
+pub(crate) fn split_list(value: &str) -> Vec<String> {
+    value.split_whitespace().map(str::to_string).collect()
+}
+
+pub(crate) fn non_empty(value: Option<&str>) -> Option<String> {
+    value
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_string)
+}
+
+/// `true`, `false` and the usual spellings spreadsheets use for them
+pub(crate) fn parse_bool(value: Option<&str>, default: bool) -> Result<bool> {
+    match value.map(|v| v.trim().to_lowercase()).as_deref() {
+        None | Some("") => Ok(default),
+        Some("true" | "1" | "yes" | "y") => Ok(true),
+        Some("false" | "0" | "no" | "n") => Ok(false),
+        Some(v) => bail!("{v} is not true or false"),
+    }
+}
+
+/// A CSV row whose fields are looked up by column name
+pub(crate) struct CsvRecord<'a> {
+    headers: &'a csv::StringRecord,
+    record: &'a csv::StringRecord,
+}
+
+impl CsvRecord<'_> {
+    pub(crate) fn get(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .position(|h| h.trim().eq_ignore_ascii_case(name))
+            .and_then(|c| self.record.get(c))
+    }
+}
+
+/// Turn every row of a CSV file with a header into an alias with `f`,
+/// failing early when one of the `required` columns is missing
+pub(crate) fn read_csv<F>(data: &[u8], required: &[&str], f: F) -> Result<Vec<Result<RMImportRow>>>
+where
+    F: Fn(&CsvRecord<'_>) -> Result<RMImportRow>,
+{
+    let mut reader = csv::Reader::from_reader(data);
+
+    let headers = reader
+        .headers()
+        .context("Unable to read CSV header")?
+        .clone();
+
+    for name in required {
+        if !headers.iter().any(|h| h.trim().eq_ignore_ascii_case(name)) {
+            bail!("CSV header has no {name} column");
+        }
+    }
+
+    let mut rows = Vec::new();
+
+    for record in reader.records() {
+        let row = record.context("Unable to read CSV row").and_then(|record| {
+            f(&CsvRecord {
+                headers: &headers,
+                record: &record,
+            })
+        });
+
+        rows.push(row);
+    }
+
+    Ok(rows)
+}
+
+fn parse_csv(data: &[u8]) -> Result<Vec<Result<RMImportRow>>> {
+    read_csv(data, &["alias"], |r| {
+        Ok(RMImportRow {
+            alias: r.get("alias").unwrap_or_default().to_string(),
+            description: r
+                .get("description")
+                .or_else(|| r.get("name"))
+                .unwrap_or_default()
+                .to_string(),
+            destinations: r.get("destinations").map(split_list).unwrap_or_default(),
+            tags: r.get("tags").map(split_list).unwrap_or_default(),
+            enabled: parse_bool(r.get("enabled"), true)?,
+            website: non_empty(r.get("website")),
+            notes: non_empty(r.get("notes")),
+            expires_at: non_empty(r.get("expires_at")),
+        })
+    })
+}
+
+fn parse_json(data: &[u8]) -> Result<Vec<Result<RMImportRow>>> {
+    let values: Vec<serde_json::Value> =
+        serde_json::from_slice(data).context("Unable to parse JSON, expected a list of aliases")?;
+
+    Ok(values
+        .into_iter()
+        .map(|v| serde_json::from_value(v).context("Unable to read alias"))
+        .collect())
+}
+
+/// Read the aliases of an import file, guessing the format when `format`
+/// is missing. Rows that can't be read are kept as errors so they show in
+/// the report next to the others.
+pub fn parse_import(
+    data: &[u8],
+    format: Option<RMExportFormat>,
+) -> Result<Vec<Result<RMImportRow>>> {
+    let format = format.unwrap_or_else(|| {
+        if data.trim_ascii_start().starts_with(b"[") {
+            RMExportFormat::Json
+        } else {
+            RMExportFormat::Csv
+        }
+    });
+
+    match format {
+        RMExportFormat::Json => parse_json(data),
+        RMExportFormat::Csv => parse_csv(data),
+    }
+}
+
+fn validate(row: usize, r: RMImportRow, domain: &str, default_dest: &str) -> Result<RMImportAlias> {
+    let email_alias = parse_address(&r.alias, domain)?;
+
+    if !email_alias.ends_with(&format!("@{domain}")) {
+        bail!("{email_alias} is not under {domain}");
+    }
+
+    let email_dest = match r.destinations.as_slice() {
+        [] => default_dest.to_string(),
+        [dest] => dest.trim().to_string(),
+        _ => bail!("only one destination per alias is supported"),
+    };
+
+    let tags = r
+        .tags
+        .iter()
+        .map(|t| normalize_tag(t))
+        .collect::<Result<Vec<_>>>()?;
+
+    let expires_at = r.expires_at.as_deref().map(parse_expiry).transpose()?;
+
+    Ok(RMImportAlias {
+        row,
+        email_alias,
+        name: encode_tags(&r.description, &tags)?,
+        email_dest,
+        enabled: r.enabled,
+        update: RMMetadataUpdate {
+            website: r.website,
+            notes: r.notes,
+            expires_at,
+            ..RMMetadataUpdate::default()
+        },
+    })
+}
+
+async fn create(provider: &dyn AliasProvider, alias: &RMImportAlias) -> Result<()> {
+    // `import` checked every address against a single listing
+    let new = provider
+        .create_unchecked(&alias.name, &alias.email_alias, &alias.email_dest)
+        .await?;
+
+    if !alias.enabled {
+        provider.set_enabled(&new.id, false).await?;
+    }
+
+    if !alias.update.is_empty() {
+        provider.annotate(&new.id, &alias.update).await?;
+    }
+
+    Ok(())
+}
+
+/// Addresses taken, by existing aliases or by earlier rows of the file
+struct RMTakenAddresses {
+    existing: HashSet<String>,
+    rows: HashMap<String, usize>,
+}
+
+impl RMTakenAddresses {
+    fn new<I>(existing: I) -> Self
+    where
+        I: IntoIterator<Item = String>,
+    {
+        Self {
+            existing: existing.into_iter().map(|a| a.to_lowercase()).collect(),
+            rows: HashMap::new(),
+        }
+    }
+
+    /// Take `email_alias` for `row`, or say why it's a duplicate
+    fn claim(&mut self, email_alias: &str, row: usize) -> Result<(), String> {
+        let key = email_alias.to_lowercase();
+
+        if let Some(first) = self.rows.get(&key) {
+            return Err(format!("same address as row {first}"));
+        }
+
+        if self.existing.contains(&key) {
+            return Err("already exists".to_string());
+        }
+
+        self.rows.insert(key, row);
+
+        Ok(())
+    }
+}
+
+fn result<M>(row: usize, alias: &str, status: RMImportStatus, message: M) -> RMImportResult
+where
+    M: Into<String>,
+{
+    RMImportResult {
+        row,
+        alias: alias.to_string(),
+        status,
+        message: message.into(),
+    }
+}
+
+/// Create the validated aliases, `IMPORT_CONCURRENCY` at a time
+async fn create_all<P>(
+    provider: &dyn AliasProvider,
+    pending: Vec<RMImportAlias>,
+    mut progress: P,
+) -> Vec<RMImportResult>
+where
+    P: FnMut(&RMImportResult),
+{
+    let mut results = Vec::new();
+
+    info!("creating {} aliases", pending.len());
+
+    let mut creations = stream::iter(pending)
+        .map(|alias| async move {
+            let res = create(provider, &alias).await;
+            (alias, res)
+        })
+        .buffer_unordered(IMPORT_CONCURRENCY);
+
+    while let Some((alias, res)) = creations.next().await {
+        let res = match res {
+            Ok(()) => result(alias.row, &alias.email_alias, RMImportStatus::Created, ""),
+            Err(e) if e.is::<RMAlreadyExists>() => result(
+                alias.row,
+                &alias.email_alias,
+                RMImportStatus::Duplicate,
+                "already exists",
+            ),
+            Err(e) => {
+                error!("Unable to import {} ({e})", alias.email_alias);
+                result(
+                    alias.row,
+                    &alias.email_alias,
+                    RMImportStatus::Failed,
+                    e.to_string(),
+                )
+            }
+        };
+
+        progress(&res);
+        results.push(res);
+    }
+
+    results
+}
+
+/// Create the aliases of `rows`, `IMPORT_CONCURRENCY` at a time, skipping
+/// invalid rows and addresses that already exist. Returns one result per
+/// row, in file order, and calls `progress` as each alias is created.
+/// With `dry_run` nothing is created and valid rows are reported as planned.
+pub async fn import<P>(
+    provider: &dyn AliasProvider,
+    config: &RMConfig,
+    client: &CFClient,
+    rows: Vec<Result<RMImportRow>>,
+    dry_run: bool,
+    progress: P,
+) -> Result<Vec<RMImportResult>>
+where
+    P: FnMut(&RMImportResult),
+{
+    let domain = provider.domain();
+
+    let mut taken =
+        RMTakenAddresses::new(provider.list().await?.into_iter().map(|a| a.email_alias));
+
+    let mut checked: HashMap<String, Option<String>> = HashMap::new();
+    let mut results = Vec::new();
+    let mut pending = Vec::new();
+
+    for (i, row) in rows.into_iter().enumerate() {
+        let n = i.saturating_add(1);
+
+        let row = match row {
+            Ok(row) => row,
+            Err(e) => {
+                results.push(result(n, "", RMImportStatus::Invalid, format!("{e:#}")));
+                continue;
+            }
+        };
+
+        let raw_alias = row.alias.clone();
+
+        let alias = match validate(n, row, domain, &config.destination_email) {
+            Ok(alias) => alias,
+            Err(e) => {
+                results.push(result(
+                    n,
+                    &raw_alias,
+                    RMImportStatus::Invalid,
+                    e.to_string(),
+                ));
+                continue;
+            }
+        };
+
+        if let Err(message) = taken.claim(&alias.email_alias, n) {
+            results.push(result(
+                n,
+                &alias.email_alias,
+                RMImportStatus::Duplicate,
+                message,
+            ));
+            continue;
+        }
+
+        if !checked.contains_key(&alias.email_dest) {
+            let res = config.check_destination(client, &alias.email_dest).await;
+            checked.insert(
+                alias.email_dest.clone(),
+                res.err().map(|e| format!("{e:#}")),
+            );
+        }
+
+        if let Some(Some(e)) = checked.get(&alias.email_dest) {
+            results.push(result(n, &alias.email_alias, RMImportStatus::Invalid, e));
+            continue;
+        }
+
+        pending.push(alias);
+    }
+
+    if dry_run {
+        results.extend(
+            pending
+                .iter()
+                .map(|a| result(a.row, &a.email_alias, RMImportStatus::Planned, "")),
+        );
+    } else {
+        results.extend(create_all(provider, pending, progress).await);
+    }
+
+    results.sort_by_key(|r| r.row);
+
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(data: &str, format: Option<RMExportFormat>) -> Result<Vec<RMImportRow>> {
+        parse_import(data.as_bytes(), format)?.into_iter().collect()
+    }
+
+    #[test]
+    fn parse_csv_rows() -> Result<()> {
+        let data = "\
+alias,description,destinations,tags,enabled,website,notes,expires_at
+shop@example.com,Shop,you@example.net,shopping work,no,shop.example,,
+news,,,,,,\"weekly, maybe\",
+";
+
+        let rows = rows(data, None)?;
+        let [shop, news] = rows.as_slice() else {
+            bail!("expected 2 rows, got {}", rows.len());
+        };
+
+        assert_eq!(shop.alias, "shop@example.com");
+        assert_eq!(shop.description, "Shop");
+        assert_eq!(shop.destinations, ["you@example.net"]);
+        assert_eq!(shop.tags, ["shopping", "work"]);
+        assert!(!shop.enabled);
+        assert_eq!(shop.website.as_deref(), Some("shop.example"));
+        assert_eq!(shop.notes, None);
+
+        assert_eq!(news.alias, "news");
+        assert!(news.destinations.is_empty());
+        assert!(news.enabled);
+        assert_eq!(news.notes.as_deref(), Some("weekly, maybe"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_csv_needs_an_alias_column() {
+        assert!(parse_import(b"name,destinations\nShop,you@example.net\n", None).is_err());
+    }
+
+    #[test]
+    fn parse_json_rows() -> Result<()> {
+        let data = r#"[
+            {"alias": "shop@example.com", "name": "Shop", "tags": ["work"], "enabled": false},
+            {"alias": "news", "destinations": ["you@example.net"], "expires_at": "30d"},
+            {"alias": 12}
+        ]"#;
+
+        let parsed = parse_import(data.as_bytes(), None)?;
+        assert_eq!(parsed.len(), 3);
+
+        let mut parsed = parsed.into_iter();
+        let shop = parsed.next().context("no first row")??;
+        assert_eq!(shop.description, "Shop");
+        assert_eq!(shop.tags, ["work"]);
+        assert!(!shop.enabled);
+
+        let news = parsed.next().context("no second row")??;
+        assert!(news.enabled);
+        assert_eq!(news.destinations, ["you@example.net"]);
+        assert_eq!(news.expires_at.as_deref(), Some("30d"));
+
+        // a bad row is reported without failing the others
+        assert!(parsed.next().context("no third row")?.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn rows_are_validated() -> Result<()> {
+        let valid = |alias: &str| {
+            validate(
+                1,
+                RMImportRow {
+                    alias: alias.to_string(),
+                    enabled: true,
+                    ..RMImportRow::default()
+                },
+                "example.com",
+                "you@example.net",
+            )
+        };
+
+        let alias = valid("Shop")?;
+        assert_eq!(alias.email_alias, "shop@example.com");
+        assert_eq!(alias.email_dest, "you@example.net");
+
+        assert!(valid("shop@other.example").is_err());
+        assert!(valid("postmaster").is_err());
+
+        let two_dests = RMImportRow {
+            alias: "shop".to_string(),
+            destinations: vec!["a@example.net".to_string(), "b@example.net".to_string()],
+            ..RMImportRow::default()
+        };
+        assert!(validate(1, two_dests, "example.com", "you@example.net").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn duplicates_of_existing_aliases_and_earlier_rows() {
+        let mut taken = RMTakenAddresses::new(["Shop@example.com".to_string()]);
+
+        assert_eq!(
+            taken.claim("shop@example.com", 1),
+            Err("already exists".to_string())
+        );
+        assert_eq!(taken.claim("news@example.com", 2), Ok(()));
+        assert_eq!(
+            taken.claim("NEWS@example.com", 3),
+            Err("same address as row 2".to_string())
+        );
+        assert_eq!(taken.claim("travel@example.com", 4), Ok(()));
+    }
+}
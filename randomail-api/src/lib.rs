@@ -1,7 +1,28 @@
+pub mod address;
+pub mod audit;
 pub mod cf_destination;
 pub mod cf_email;
 pub mod cf_zone;
 pub mod config;
+pub mod derive;
+pub mod drift;
+pub mod error;
+pub mod expiry;
+pub mod export;
+pub mod generator;
 pub mod http;
+pub mod import;
+pub mod lookup;
+pub mod metadata;
+pub mod migrate;
+pub mod postfix;
+pub mod provider;
+pub mod retarget;
+pub mod rotate;
+pub mod site;
+pub mod snapshot;
+pub mod sync;
+pub mod tags;
+pub mod trash;
 
 pub const PROJECT_NAME: &str = "randomail";
@@ -0,0 +1,222 @@
+use std::fmt;
+
+use anyhow::Result;
+
+use crate::provider::{AliasProvider, RMAlias};
+
+/// No alias matches what was asked for
+#[derive(Debug)]
+pub struct RMAliasNotFound {
+    /// Address, local part or rule id that was looked up
+    pub query: String,
+}
+
+impl fmt::Display for RMAliasNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no alias matches {}", self.query)
+    }
+}
+
+impl std::error::Error for RMAliasNotFound {}
+
+/// A bare local part matches aliases under several domains
+#[derive(Debug)]
+pub struct RMAmbiguousAlias {
+    pub query: String,
+    pub aliases: Vec<RMAlias>,
+}
+
+impl fmt::Display for RMAmbiguousAlias {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} matches several aliases:", self.query)?;
+
+        for alias in &self.aliases {
+            write!(f, "\n  {} ({})", alias.email_alias, alias.id)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for RMAmbiguousAlias {}
+
+fn local_part(email: &str) -> &str {
+    email.split_once('@').map_or(email, |(local, _)| local)
+}
+
+/// Aliases `query` may refer to: a rule id, a full address (any case), or a
+/// bare local part under the provider's domain. A bare local part falls back
+/// to any domain when the provider's has no match.
+#[must_use]
+pub fn matching_aliases<'a>(aliases: &'a [RMAlias], query: &str, domain: &str) -> Vec<&'a RMAlias> {
+    let query = query.trim();
+
+    let address = if query.contains('@') {
+        query.to_string()
+    } else {
+        format!("{query}@{domain}")
+    };
+
+    let mut found: Vec<&RMAlias> = aliases
+        .iter()
+        .filter(|a| a.id == query || a.email_alias.eq_ignore_ascii_case(&address))
+        .collect();
+
+    if found.is_empty() && !query.contains('@') {
+        found = aliases
+            .iter()
+            .filter(|a| local_part(&a.email_alias).eq_ignore_ascii_case(query))
+            .collect();
+    }
+
+    found
+}
+
+/// The single alias `query` refers to, see `matching_aliases`
+pub async fn find_alias(provider: &dyn AliasProvider, query: &str) -> Result<RMAlias> {
+    let aliases = provider.list().await?;
+
+    match matching_aliases(&aliases, query, provider.domain()).as_slice() {
+        [] => Err(RMAliasNotFound {
+            query: query.to_string(),
+        }
+        .into()),
+        [alias] => Ok((*alias).clone()),
+        found => Err(RMAmbiguousAlias {
+            query: query.to_string(),
+            aliases: found.iter().map(|a| (*a).clone()).collect(),
+        }
+        .into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{Context, bail};
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::provider::RMCapabilities;
+
+    fn alias(id: &str, email_alias: &str) -> RMAlias {
+        RMAlias {
+            id: id.to_string(),
+            email_alias: email_alias.to_string(),
+            ..RMAlias::default()
+        }
+    }
+
+    fn aliases() -> Vec<RMAlias> {
+        vec![
+            alias("r1", "shop@example.com"),
+            alias("r2", "news@example.com"),
+            alias("r3", "news@other.example"),
+            alias("r4", "travel@other.example"),
+            alias("r5", "travel@third.example"),
+        ]
+    }
+
+    fn ids(found: &[&RMAlias]) -> Vec<String> {
+        found.iter().map(|a| a.id.clone()).collect()
+    }
+
+    /// Serves a fixed listing under example.com
+    struct Listing(Vec<RMAlias>);
+
+    #[async_trait]
+    impl AliasProvider for Listing {
+        fn domain(&self) -> &'static str {
+            "example.com"
+        }
+
+        fn capabilities(&self) -> RMCapabilities {
+            RMCapabilities::default()
+        }
+
+        async fn list(&self) -> Result<Vec<RMAlias>> {
+            Ok(self.0.clone())
+        }
+
+        async fn get(&self, id: &str) -> Result<RMAlias> {
+            find_alias(self, id).await
+        }
+
+        async fn create(&self, _: &str, _: &str, _: &str) -> Result<RMAlias> {
+            bail!("read only")
+        }
+
+        async fn delete(&self, _: &str) -> Result<()> {
+            bail!("read only")
+        }
+
+        async fn set_enabled(&self, _: &str, _: bool) -> Result<()> {
+            bail!("read only")
+        }
+
+        async fn rename(&self, _: &str, _: &str) -> Result<()> {
+            bail!("read only")
+        }
+    }
+
+    #[test]
+    fn matching_cases() {
+        let aliases = aliases();
+
+        let cases: &[(&str, &[&str])] = &[
+            // rule id
+            ("r3", &["r3"]),
+            // full address, in any case
+            ("news@other.example", &["r3"]),
+            ("NEWS@Example.com", &["r2"]),
+            // local part under the provider's domain first
+            ("news", &["r2"]),
+            (" Shop ", &["r1"]),
+            // then under any domain
+            ("travel", &["r4", "r5"]),
+            // a full address doesn't fall back to its local part
+            ("travel@example.com", &[]),
+            ("missing", &[]),
+        ];
+
+        for (query, want) in cases {
+            assert_eq!(
+                ids(&matching_aliases(&aliases, query, "example.com")),
+                *want,
+                "{query}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn find_one_alias() -> Result<()> {
+        let provider = Listing(aliases());
+
+        assert_eq!(find_alias(&provider, "news").await?.id, "r2");
+        assert_eq!(find_alias(&provider, "r4").await?.id, "r4");
+
+        let err = find_alias(&provider, "missing")
+            .await
+            .err()
+            .context("found an alias that doesn't exist")?;
+        assert!(err.is::<RMAliasNotFound>());
+
+        let err = find_alias(&provider, "travel")
+            .await
+            .err()
+            .context("picked one of several aliases")?;
+        let ambiguous = err
+            .downcast_ref::<RMAmbiguousAlias>()
+            .context("not an ambiguous alias")?;
+        assert_eq!(ambiguous.query, "travel");
+        assert_eq!(
+            ambiguous
+                .aliases
+                .iter()
+                .map(|a| a.id.as_str())
+                .collect::<Vec<_>>(),
+            ["r4", "r5"]
+        );
+
+        Ok(())
+    }
+}
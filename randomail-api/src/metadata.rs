@@ -0,0 +1,475 @@
+use std::{
+    collections::HashMap,
+    env,
+    path::Path,
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::info;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+use tabled::Tabled;
+
+use crate::{
+    audit::current_actor,
+    provider::{AliasProvider, RMAlias, RMAlreadyExists, RMCapabilities},
+};
+
+pub const METADATA_FILE_NAME: &str = "metadata.db";
+
+/// Information randomail keeps about an alias that the backend can't store
+#[derive(Debug, Default, Clone, Serialize, Deserialize, Tabled)]
+pub struct RMMetadata {
+    #[tabled(rename = "created", display = "display_date")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    #[tabled(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<DateTime<Utc>>,
+    #[tabled(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creator: Option<String>,
+    #[tabled(display = "display_option")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub website: Option<String>,
+    #[tabled(display = "display_option")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+    #[tabled(rename = "expires", display = "display_date")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Address this alias was rotated from
+    #[tabled(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replaces: Option<String>,
+    /// Address this alias was rotated to
+    #[tabled(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub replaced_by: Option<String>,
+}
+
+/// Changes to the metadata of an alias, `None` fields are left unchanged
+#[derive(Debug, Default, Clone)]
+pub struct RMMetadataUpdate {
+    /// Empty to clear
+    pub website: Option<String>,
+    /// Empty to clear
+    pub notes: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub clear_expiry: bool,
+    pub replaces: Option<String>,
+    pub replaced_by: Option<String>,
+    /// History carried over to a new id, such as an alias restored from
+    /// the trash
+    pub created_at: Option<DateTime<Utc>>,
+    pub creator: Option<String>,
+}
+
+impl RMMetadataUpdate {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.website.is_none()
+            && self.notes.is_none()
+            && self.expires_at.is_none()
+            && !self.clear_expiry
+            && self.replaces.is_none()
+            && self.replaced_by.is_none()
+            && self.created_at.is_none()
+            && self.creator.is_none()
+    }
+}
+
+// tabled hands display functions a reference to the field
+#[allow(clippy::ref_option)]
+fn display_date(date: &Option<DateTime<Utc>>) -> String {
+    date.map_or_else(String::new, |d| d.format("%Y-%m-%d").to_string())
+}
+
+#[allow(clippy::ref_option)]
+fn display_option(value: &Option<String>) -> String {
+    value.clone().unwrap_or_default()
+}
+
+/// Name of the OS user running randomail
+#[must_use]
+pub fn current_user() -> String {
+    env::var("USER")
+        .or_else(|_| env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+const METADATA_COLUMNS: &str =
+    "created_at, updated_at, creator, website, notes, expires_at, replaces, replaced_by";
+
+fn read_metadata(row: &rusqlite::Row<'_>, first: usize) -> rusqlite::Result<RMMetadata> {
+    let col = |i: usize| first.saturating_add(i);
+
+    Ok(RMMetadata {
+        created_at: row.get(col(0))?,
+        updated_at: row.get(col(1))?,
+        creator: row.get(col(2))?,
+        website: row.get(col(3))?,
+        notes: row.get(col(4))?,
+        expires_at: row.get(col(5))?,
+        replaces: row.get(col(6))?,
+        replaced_by: row.get(col(7))?,
+    })
+}
+
+fn non_empty(value: &str) -> Option<&str> {
+    if value.is_empty() { None } else { Some(value) }
+}
+
+/// Bring databases created by older versions up to date
+fn migrate(conn: &Connection) -> Result<()> {
+    let version: i64 = conn
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .context("Unable to read metadata schema version")?;
+
+    if version < 1 {
+        conn.execute_batch(
+            "ALTER TABLE alias_metadata ADD COLUMN expires_at TEXT;
+             PRAGMA user_version = 1;",
+        )
+        .context("Unable to migrate metadata table")?;
+    }
+
+    if version < 2 {
+        conn.execute_batch(
+            "ALTER TABLE alias_metadata ADD COLUMN replaces TEXT;
+             ALTER TABLE alias_metadata ADD COLUMN replaced_by TEXT;
+             PRAGMA user_version = 2;",
+        )
+        .context("Unable to migrate metadata table")?;
+    }
+
+    Ok(())
+}
+
+/// Local `SQLite` database of alias metadata, keyed by rule id
+pub struct RMMetadataStore {
+    conn: Mutex<Connection>,
+}
+
+impl RMMetadataStore {
+    pub fn open<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        info!("opening {}", path.as_ref().display());
+
+        let conn = Connection::open(&path)
+            .with_context(|| format!("Unable to open {}", path.as_ref().display()))?;
+
+        // the web app reaps expired aliases on a connection of its own
+        conn.busy_timeout(Duration::from_secs(5))
+            .context("Unable to configure metadata database")?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS alias_metadata (
+                id TEXT PRIMARY KEY,
+                created_at TEXT,
+                updated_at TEXT,
+                creator TEXT,
+                website TEXT,
+                notes TEXT
+            );",
+        )
+        .context("Unable to create metadata table")?;
+
+        migrate(&conn)?;
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get(&self, id: &str) -> Result<Option<RMMetadata>> {
+        self.lock()
+            .query_row(
+                &format!("SELECT {METADATA_COLUMNS} FROM alias_metadata WHERE id = ?1"),
+                params![id],
+                |row| read_metadata(row, 0),
+            )
+            .optional()
+            .with_context(|| format!("Unable to read metadata for {id}"))
+    }
+
+    pub fn all(&self) -> Result<HashMap<String, RMMetadata>> {
+        let conn = self.lock();
+
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT id, {METADATA_COLUMNS} FROM alias_metadata"
+            ))
+            .context("Unable to read metadata")?;
+
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, read_metadata(row, 1)?))
+            })
+            .context("Unable to read metadata")?;
+
+        rows.collect::<rusqlite::Result<_>>()
+            .context("Unable to read metadata")
+    }
+
+    /// Ids of the aliases that expire at `now` or earlier
+    pub fn expired(&self, now: DateTime<Utc>) -> Result<Vec<String>> {
+        let conn = self.lock();
+
+        let mut stmt = conn
+            .prepare("SELECT id FROM alias_metadata WHERE expires_at <= ?1 ORDER BY expires_at")
+            .context("Unable to read metadata")?;
+
+        stmt.query_map(params![now], |row| row.get(0))
+            .context("Unable to read metadata")?
+            .collect::<rusqlite::Result<_>>()
+            .context("Unable to read metadata")
+    }
+
+    /// Record that `creator` just created `id`
+    pub fn created(&self, id: &str, creator: &str) -> Result<()> {
+        let now = Utc::now();
+
+        self.lock()
+            .execute(
+                "INSERT INTO alias_metadata (id, created_at, updated_at, creator)
+                 VALUES (?1, ?2, ?2, ?3)
+                 ON CONFLICT(id) DO UPDATE SET
+                    created_at = excluded.created_at,
+                    updated_at = excluded.updated_at,
+                    creator = excluded.creator",
+                params![id, now, creator],
+            )
+            .with_context(|| format!("Unable to write metadata for {id}"))?;
+
+        Ok(())
+    }
+
+    /// Record that `id` was just modified
+    pub fn touch(&self, id: &str) -> Result<()> {
+        self.lock()
+            .execute(
+                "INSERT INTO alias_metadata (id, updated_at) VALUES (?1, ?2)
+                 ON CONFLICT(id) DO UPDATE SET updated_at = excluded.updated_at",
+                params![id, Utc::now()],
+            )
+            .with_context(|| format!("Unable to write metadata for {id}"))?;
+
+        Ok(())
+    }
+
+    pub fn update(&self, id: &str, update: &RMMetadataUpdate) -> Result<()> {
+        self.touch(id)?;
+
+        let conn = self.lock();
+
+        let write = |column: &str, value: &dyn rusqlite::ToSql| {
+            conn.execute(
+                &format!("UPDATE alias_metadata SET {column} = ?2 WHERE id = ?1"),
+                params![id, value],
+            )
+            .with_context(|| format!("Unable to write metadata for {id}"))
+        };
+
+        if let Some(website) = &update.website {
+            write("website", &non_empty(website))?;
+        }
+
+        if let Some(notes) = &update.notes {
+            write("notes", &non_empty(notes))?;
+        }
+
+        if update.clear_expiry {
+            write("expires_at", &None::<DateTime<Utc>>)?;
+        } else if let Some(expires_at) = &update.expires_at {
+            write("expires_at", expires_at)?;
+        }
+
+        if let Some(replaces) = &update.replaces {
+            write("replaces", &non_empty(replaces))?;
+        }
+
+        if let Some(replaced_by) = &update.replaced_by {
+            write("replaced_by", &non_empty(replaced_by))?;
+        }
+
+        if let Some(created_at) = &update.created_at {
+            write("created_at", created_at)?;
+        }
+
+        if let Some(creator) = &update.creator {
+            write("creator", &non_empty(creator))?;
+        }
+
+        Ok(())
+    }
+
+    /// Move the metadata of `id` to `new_id`, for backends keyed by address
+    pub fn rekey(&self, id: &str, new_id: &str) -> Result<()> {
+        self.lock()
+            .execute(
+                "UPDATE alias_metadata SET id = ?2 WHERE id = ?1",
+                params![id, new_id],
+            )
+            .with_context(|| format!("Unable to move metadata of {id} to {new_id}"))?;
+
+        Ok(())
+    }
+
+    pub fn remove(&self, id: &str) -> Result<()> {
+        self.lock()
+            .execute("DELETE FROM alias_metadata WHERE id = ?1", params![id])
+            .with_context(|| format!("Unable to delete metadata for {id}"))?;
+
+        Ok(())
+    }
+}
+
+/// Wraps a backend and keeps the metadata store in sync with its changes
+pub struct MetadataProvider {
+    inner: Box<dyn AliasProvider>,
+    store: RMMetadataStore,
+}
+
+impl MetadataProvider {
+    pub fn new(inner: Box<dyn AliasProvider>, store: RMMetadataStore) -> Self {
+        Self { inner, store }
+    }
+}
+
+impl MetadataProvider {
+    /// Record the creation of `alias` by whoever is making the change, and
+    /// return it with its metadata
+    fn created(&self, mut alias: RMAlias) -> Result<RMAlias> {
+        self.store.created(&alias.id, &current_actor())?;
+
+        if let Some(m) = self.store.get(&alias.id)? {
+            alias.metadata = m;
+        }
+
+        Ok(alias)
+    }
+}
+
+#[async_trait]
+impl AliasProvider for MetadataProvider {
+    fn domain(&self) -> &str {
+        self.inner.domain()
+    }
+
+    fn capabilities(&self) -> RMCapabilities {
+        RMCapabilities {
+            metadata: true,
+            ..self.inner.capabilities()
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<RMAlias>> {
+        let mut aliases = self.inner.list().await?;
+        let mut metadata = self.store.all()?;
+
+        for alias in &mut aliases {
+            if let Some(m) = metadata.remove(&alias.id) {
+                alias.metadata = m;
+            }
+        }
+
+        Ok(aliases)
+    }
+
+    async fn get(&self, id: &str) -> Result<RMAlias> {
+        let mut alias = self.inner.get(id).await?;
+
+        if let Some(m) = self.store.get(id)? {
+            alias.metadata = m;
+        }
+
+        Ok(alias)
+    }
+
+    async fn create(&self, name: &str, email_alias: &str, email_dest: &str) -> Result<RMAlias> {
+        let alias = self
+            .inner
+            .create(name, email_alias, email_dest)
+            .await
+            .map_err(|e| {
+                RMAlreadyExists::map(e, |mut existing| {
+                    if let Ok(Some(m)) = self.store.get(&existing.id) {
+                        existing.metadata = m;
+                    }
+                    existing
+                })
+            })?;
+
+        self.created(alias)
+    }
+
+    async fn create_unchecked(
+        &self,
+        name: &str,
+        email_alias: &str,
+        email_dest: &str,
+    ) -> Result<RMAlias> {
+        let alias = self
+            .inner
+            .create_unchecked(name, email_alias, email_dest)
+            .await?;
+
+        self.created(alias)
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        self.inner.delete(id).await?;
+        self.store.remove(id)
+    }
+
+    async fn set_enabled(&self, id: &str, enabled: bool) -> Result<()> {
+        self.inner.set_enabled(id, enabled).await?;
+        self.store.touch(id)
+    }
+
+    async fn rename(&self, id: &str, name: &str) -> Result<()> {
+        self.inner.rename(id, name).await?;
+        self.store.touch(id)
+    }
+
+    async fn readdress(&self, id: &str, email_alias: &str) -> Result<RMAlias> {
+        let mut alias = self.inner.readdress(id, email_alias).await?;
+
+        if alias.id != id {
+            self.store.rekey(id, &alias.id)?;
+        }
+
+        self.store.touch(&alias.id)?;
+
+        if let Some(m) = self.store.get(&alias.id)? {
+            alias.metadata = m;
+        }
+
+        Ok(alias)
+    }
+
+    async fn set_destinations(&self, id: &str, email_dests: &[String]) -> Result<()> {
+        self.inner.set_destinations(id, email_dests).await?;
+        self.store.touch(id)
+    }
+
+    async fn annotate(&self, id: &str, update: &RMMetadataUpdate) -> Result<()> {
+        self.store.update(id, update)
+    }
+
+    async fn set_tags(&self, id: &str, tags: &[String]) -> Result<()> {
+        self.inner.set_tags(id, tags).await?;
+        self.store.touch(id)
+    }
+}
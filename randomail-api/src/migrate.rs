@@ -0,0 +1,332 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    export::RMExportFormat,
+    import::{RMImportRow, non_empty, parse_bool, parse_import, read_csv},
+};
+
+/// Where an import file comes from. Exports of hosted alias services are
+/// mapped onto aliases with the same local part under our own domain.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum RMImportSource {
+    /// `randomail export`
+    #[default]
+    #[serde(rename = "randomail")]
+    Randomail,
+    /// Alias CSV of simplelogin.io: `alias,note,enabled,mailboxes`
+    #[serde(rename = "simplelogin")]
+    SimpleLogin,
+    /// Alias CSV of addy.io: `email,description,active,recipients`, ...
+    #[serde(rename = "addy")]
+    Addy,
+    /// Firefox Relay's JSON list of masks, as its API returns them
+    #[serde(rename = "relay")]
+    FirefoxRelay,
+}
+
+impl fmt::Display for RMImportSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Randomail => "randomail",
+            Self::SimpleLogin => "simplelogin",
+            Self::Addy => "addy",
+            Self::FirefoxRelay => "relay",
+        };
+
+        f.write_str(name)
+    }
+}
+
+impl FromStr for RMImportSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "randomail" => Ok(Self::Randomail),
+            "simplelogin" => Ok(Self::SimpleLogin),
+            "addy" | "addy.io" | "anonaddy" => Ok(Self::Addy),
+            "relay" | "firefox-relay" => Ok(Self::FirefoxRelay),
+            _ => bail!("unknown source {s}, use randomail, simplelogin, addy or relay"),
+        }
+    }
+}
+
+/// Alias with the local part of `address` on our domain, along with a note
+/// remembering where it came from
+fn hosted_row(service: &str, address: &str) -> Result<RMImportRow> {
+    let address = address.trim();
+
+    let Some((local, _)) = address.rsplit_once('@') else {
+        bail!("{address} is not an email address");
+    };
+
+    Ok(RMImportRow {
+        alias: local.to_string(),
+        notes: Some(format!("imported from {service} ({address})")),
+        ..RMImportRow::default()
+    })
+}
+
+fn split_mailboxes(value: Option<&str>) -> Vec<String> {
+    value
+        .unwrap_or_default()
+        .split(|c: char| c == ',' || c == ';' || c.is_whitespace())
+        .filter(|m| !m.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn parse_simplelogin(data: &[u8]) -> Result<Vec<Result<RMImportRow>>> {
+    read_csv(data, &["alias"], |r| {
+        Ok(RMImportRow {
+            description: r.get("note").unwrap_or_default().trim().to_string(),
+            enabled: parse_bool(r.get("enabled"), true)?,
+            destinations: split_mailboxes(r.get("mailboxes")),
+            ..hosted_row("SimpleLogin", r.get("alias").unwrap_or_default())?
+        })
+    })
+}
+
+fn parse_addy(data: &[u8]) -> Result<Vec<Result<RMImportRow>>> {
+    read_csv(data, &["email"], |r| {
+        Ok(RMImportRow {
+            description: r.get("description").unwrap_or_default().trim().to_string(),
+            enabled: parse_bool(r.get("active").or_else(|| r.get("enabled")), true)?,
+            destinations: split_mailboxes(r.get("recipients")),
+            ..hosted_row("addy.io", r.get("email").unwrap_or_default())?
+        })
+    })
+}
+
+#[derive(Deserialize)]
+struct RelayMask {
+    full_address: Option<String>,
+    /// Local part of the mask
+    address: Option<String>,
+    /// Relay domain of the mask, 1 for relay.firefox.com and 2 for mozmail.com
+    domain: Option<u8>,
+    #[serde(default)]
+    description: String,
+    #[serde(default = "relay_enabled")]
+    enabled: bool,
+    generated_for: Option<String>,
+    used_on: Option<String>,
+}
+
+const fn relay_enabled() -> bool {
+    true
+}
+
+impl RelayMask {
+    fn into_row(self) -> Result<RMImportRow> {
+        let address = match (self.full_address, self.address) {
+            (Some(full), _) => full,
+            // older exports leave out the full address
+            (None, Some(local)) => {
+                let domain = if self.domain == Some(1) {
+                    "relay.firefox.com"
+                } else {
+                    "mozmail.com"
+                };
+
+                format!("{local}@{domain}")
+            }
+            (None, None) => bail!("mask has neither full_address nor address"),
+        };
+
+        let website = non_empty(self.generated_for.as_deref()).or_else(|| {
+            self.used_on
+                .as_deref()
+                .and_then(|u| u.split(',').map(str::trim).find(|u| !u.is_empty()))
+                .map(str::to_string)
+        });
+
+        Ok(RMImportRow {
+            description: self.description.trim().to_string(),
+            enabled: self.enabled,
+            website,
+            ..hosted_row("Firefox Relay", &address)?
+        })
+    }
+}
+
+fn parse_relay(data: &[u8]) -> Result<Vec<Result<RMImportRow>>> {
+    let values: Vec<serde_json::Value> = serde_json::from_slice(data)
+        .context("Unable to parse JSON, expected a list of Relay masks")?;
+
+    Ok(values
+        .into_iter()
+        .map(|v| {
+            serde_json::from_value::<RelayMask>(v)
+                .context("Unable to read mask")
+                .and_then(RelayMask::into_row)
+        })
+        .collect())
+}
+
+/// Read an import file exported by `source`. Mailboxes of hosted services
+/// are only kept with `keep_mailboxes`, otherwise the aliases forward to
+/// the configured destination.
+pub fn parse_source(
+    data: &[u8],
+    source: RMImportSource,
+    format: Option<RMExportFormat>,
+    keep_mailboxes: bool,
+) -> Result<Vec<Result<RMImportRow>>> {
+    let mut rows = match source {
+        RMImportSource::Randomail => return parse_import(data, format),
+        RMImportSource::SimpleLogin => parse_simplelogin(data)?,
+        RMImportSource::Addy => parse_addy(data)?,
+        RMImportSource::FirefoxRelay => parse_relay(data)?,
+    };
+
+    if !keep_mailboxes {
+        for row in rows.iter_mut().flatten() {
+            row.destinations.clear();
+        }
+    }
+
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(data: &str, source: RMImportSource, keep_mailboxes: bool) -> Result<Vec<RMImportRow>> {
+        parse_source(data.as_bytes(), source, None, keep_mailboxes)?
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn simplelogin_aliases() -> Result<()> {
+        let data = "\
+alias,note,enabled,mailboxes
+shop.x7k2@simplelogin.com,Shop,True,you@example.net other@example.net
+news_42@aleeas.com,,False,you@example.net
+";
+
+        let rows = rows(data, RMImportSource::SimpleLogin, true)?;
+        let [shop, news] = rows.as_slice() else {
+            bail!("expected 2 rows, got {}", rows.len());
+        };
+
+        assert_eq!(shop.alias, "shop.x7k2");
+        assert_eq!(shop.description, "Shop");
+        assert!(shop.enabled);
+        assert_eq!(shop.destinations, ["you@example.net", "other@example.net"]);
+        assert_eq!(
+            shop.notes.as_deref(),
+            Some("imported from SimpleLogin (shop.x7k2@simplelogin.com)")
+        );
+
+        assert_eq!(news.alias, "news_42");
+        assert!(!news.enabled);
+
+        Ok(())
+    }
+
+    #[test]
+    fn addy_aliases() -> Result<()> {
+        let data = "\
+id,email,description,active,recipients
+1,shop@you.anonaddy.com,Shop,1,you@example.net;other@example.net
+2,news@you.anonaddy.me,,0,
+";
+
+        let rows = rows(data, RMImportSource::Addy, false)?;
+        let [shop, news] = rows.as_slice() else {
+            bail!("expected 2 rows, got {}", rows.len());
+        };
+
+        assert_eq!(shop.alias, "shop");
+        assert_eq!(shop.description, "Shop");
+        assert!(shop.enabled);
+        // mailboxes are dropped unless asked for
+        assert!(shop.destinations.is_empty());
+
+        assert_eq!(news.alias, "news");
+        assert!(!news.enabled);
+        assert_eq!(
+            news.notes.as_deref(),
+            Some("imported from addy.io (news@you.anonaddy.me)")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn relay_masks() -> Result<()> {
+        let data = r#"[
+            {"full_address": "abc123@mozmail.com", "description": "Shop", "enabled": false,
+             "generated_for": "shop.example", "used_on": "other.example"},
+            {"address": "def456", "domain": 1, "used_on": ",news.example,other.example"},
+            {"address": "ghi789", "domain": 2}
+        ]"#;
+
+        let rows = rows(data, RMImportSource::FirefoxRelay, false)?;
+        let [shop, news, other] = rows.as_slice() else {
+            bail!("expected 3 rows, got {}", rows.len());
+        };
+
+        assert_eq!(shop.alias, "abc123");
+        assert_eq!(shop.description, "Shop");
+        assert!(!shop.enabled);
+        assert_eq!(shop.website.as_deref(), Some("shop.example"));
+
+        assert_eq!(news.alias, "def456");
+        assert!(news.enabled);
+        assert_eq!(news.website.as_deref(), Some("news.example"));
+        assert_eq!(
+            news.notes.as_deref(),
+            Some("imported from Firefox Relay (def456@relay.firefox.com)")
+        );
+
+        assert_eq!(
+            other.notes.as_deref(),
+            Some("imported from Firefox Relay (ghi789@mozmail.com)")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn bad_rows_are_kept_as_errors() -> Result<()> {
+        let relay = parse_source(
+            br#"[{"description": "no address"}]"#,
+            RMImportSource::FirefoxRelay,
+            None,
+            false,
+        )?;
+        assert!(relay.iter().all(Result::is_err));
+
+        let simplelogin = parse_source(
+            b"alias,enabled\nnot-an-address,true\nshop@simplelogin.com,maybe\n",
+            RMImportSource::SimpleLogin,
+            None,
+            false,
+        )?;
+        assert_eq!(simplelogin.len(), 2);
+        assert!(simplelogin.iter().all(Result::is_err));
+
+        assert!(parse_source(b"note\nShop\n", RMImportSource::SimpleLogin, None, false).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn source_names() -> Result<()> {
+        assert_eq!("anonaddy".parse::<RMImportSource>()?, RMImportSource::Addy);
+        assert_eq!(
+            "firefox-relay".parse::<RMImportSource>()?,
+            RMImportSource::FirefoxRelay
+        );
+        assert!("bitwarden".parse::<RMImportSource>().is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,848 @@
+use std::{
+    ffi::OsStr,
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use chrono::Utc;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    io::AsyncWriteExt,
+    process::Command,
+    sync::{Mutex, MutexGuard},
+    task,
+};
+
+use crate::{
+    audit::{RMAuditEntry, RMAuditLog, RMOperation, current_actor},
+    lookup::RMAliasNotFound,
+    provider::{AliasProvider, RMAlias, RMAlreadyExists, RMCapabilities},
+};
+
+const NAME_MARKER: &str = "# randomail-name:";
+const DISABLED_MARKER: &str = "#randomail-disabled ";
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RMPostfixConfig {
+    /// Path to the text file referenced by `virtual_alias_maps`
+    pub map_file: PathBuf,
+    /// Shell command to run after the map changed (e.g. postmap + reload)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reload_command: Option<String>,
+}
+
+struct PostfixEntry {
+    name: Option<String>,
+    alias: String,
+    value: String,
+    enabled: bool,
+}
+
+impl PostfixEntry {
+    fn parse(line: &str, enabled: bool, name: Option<String>) -> Option<Self> {
+        let (alias, value) = line.trim().split_once(char::is_whitespace)?;
+
+        Some(Self {
+            name,
+            alias: alias.to_string(),
+            value: value.trim().to_string(),
+            enabled,
+        })
+    }
+
+    fn destinations(&self) -> impl Iterator<Item = &str> {
+        self.value
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|d| !d.is_empty())
+    }
+
+    fn set_destinations(&mut self, email_dests: &[String]) {
+        self.value = email_dests.join(", ");
+    }
+
+    fn to_alias(&self) -> RMAlias {
+        let mut destinations = self.destinations().map(str::to_string);
+
+        RMAlias {
+            id: self.alias.clone(),
+            email_destination: destinations.next().unwrap_or_default(),
+            extra_destinations: destinations.collect(),
+            email_alias: self.alias.clone(),
+            name: self.name.clone().unwrap_or_default(),
+            enabled: self.enabled,
+            ..RMAlias::default()
+        }
+    }
+}
+
+enum PostfixLine {
+    Raw(String),
+    Entry(PostfixEntry),
+}
+
+struct PostfixMap {
+    lines: Vec<PostfixLine>,
+}
+
+impl PostfixMap {
+    fn parse(data: &str) -> Self {
+        let mut lines = Vec::new();
+        let mut name: Option<String> = None;
+
+        for line in data.lines() {
+            if let Some(n) = line.strip_prefix(NAME_MARKER) {
+                if let Some(prev) = name.replace(n.trim().to_string()) {
+                    lines.push(PostfixLine::Raw(format!("{NAME_MARKER} {prev}")));
+                }
+                continue;
+            }
+
+            let entry = if let Some(disabled) = line.strip_prefix(DISABLED_MARKER) {
+                PostfixEntry::parse(disabled, false, name.take())
+            } else if line.starts_with(char::is_whitespace)
+                && !line.trim().is_empty()
+                && let Some(PostfixLine::Entry(prev)) = lines.last_mut()
+            {
+                // postfix continuation line, belongs to the previous entry
+                prev.value = format!("{} {}", prev.value, line.trim());
+                continue;
+            } else if line.starts_with(char::is_whitespace)
+                || line.trim().is_empty()
+                || line.trim_start().starts_with('#')
+            {
+                // an indented line after a comment or a blank line is kept as
+                // it is, it doesn't start an entry of its own
+                None
+            } else {
+                PostfixEntry::parse(line, true, name.take())
+            };
+
+            if let Some(prev) = name.take() {
+                lines.push(PostfixLine::Raw(format!("{NAME_MARKER} {prev}")));
+            }
+
+            match entry {
+                Some(e) => lines.push(PostfixLine::Entry(e)),
+                None => lines.push(PostfixLine::Raw(line.to_string())),
+            }
+        }
+
+        if let Some(prev) = name {
+            lines.push(PostfixLine::Raw(format!("{NAME_MARKER} {prev}")));
+        }
+
+        Self { lines }
+    }
+
+    fn encode(&self) -> String {
+        let mut data = String::new();
+
+        for line in &self.lines {
+            match line {
+                PostfixLine::Raw(l) => {
+                    let _ = writeln!(data, "{l}");
+                }
+                PostfixLine::Entry(e) => {
+                    if let Some(name) = &e.name {
+                        let name = name.replace(['\r', '\n'], " ");
+                        let _ = writeln!(data, "{NAME_MARKER} {name}");
+                    }
+
+                    let prefix = if e.enabled { "" } else { DISABLED_MARKER };
+                    let _ = writeln!(data, "{prefix}{}\t{}", e.alias, e.value);
+                }
+            }
+        }
+
+        data
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &PostfixEntry> {
+        self.lines.iter().filter_map(|l| match l {
+            PostfixLine::Entry(e) => Some(e),
+            PostfixLine::Raw(_) => None,
+        })
+    }
+
+    fn find_mut(&mut self, id: &str) -> Result<&mut PostfixEntry> {
+        for line in &mut self.lines {
+            if let PostfixLine::Entry(e) = line
+                && e.alias == id
+            {
+                return Ok(e);
+            }
+        }
+
+        Err(RMAliasNotFound {
+            query: id.to_string(),
+        }
+        .into())
+    }
+}
+
+/// Held while the map is read, changed and written back
+struct MapLock<'a> {
+    _guard: MutexGuard<'a, ()>,
+    _file: File,
+}
+
+pub struct PostfixProvider {
+    domain: String,
+    config: RMPostfixConfig,
+    lock: Mutex<()>,
+    audit: Option<RMAuditLog>,
+}
+
+impl PostfixProvider {
+    pub fn new<D>(domain: D, config: RMPostfixConfig) -> Self
+    where
+        D: Into<String>,
+    {
+        Self {
+            domain: domain.into(),
+            config,
+            lock: Mutex::new(()),
+            audit: None,
+        }
+    }
+
+    /// Record every change made to the map in `audit`, as Cloudflare rule
+    /// changes are
+    #[must_use]
+    pub fn with_audit(mut self, audit: RMAuditLog) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Write an audit entry for a change from `before` to `after` that
+    /// ended with `res`, the domain stands in for the zone
+    fn record<T>(
+        &self,
+        operation: RMOperation,
+        before: Option<&RMAlias>,
+        after: Option<&RMAlias>,
+        res: &Result<T>,
+    ) {
+        let Some(audit) = &self.audit else {
+            return;
+        };
+
+        let as_json = |alias: &RMAlias| serde_json::to_value(alias).ok();
+
+        let entry = RMAuditEntry {
+            timestamp: Utc::now(),
+            actor: current_actor(),
+            operation,
+            alias: after.or(before).map(|a| a.email_alias.clone()),
+            zone_id: self.domain.clone(),
+            response_id: None,
+            before: before.and_then(as_json),
+            after: after.filter(|_| res.is_ok()).and_then(as_json),
+            error: res.as_ref().err().map(|e| format!("{e:#}")),
+        };
+
+        if let Err(e) = audit.append(&entry) {
+            error!("Unable to write audit log ({e:#})");
+        }
+    }
+
+    /// Lock the map against other writers. The mutex covers this process,
+    /// the file lock other ones, such as the CLI while the web app runs. The
+    /// map is replaced on every write, so the file lock is taken on a lock
+    /// file next to it rather than on the map itself.
+    async fn lock_map(&self) -> Result<MapLock<'_>> {
+        let guard = self.lock.lock().await;
+
+        let path = with_suffix(&self.config.map_file, ".randomail.lock")?;
+
+        let file = task::spawn_blocking(move || {
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+                .with_context(|| format!("Unable to open {}", path.display()))?;
+
+            file.lock()
+                .with_context(|| format!("Unable to lock {}", path.display()))?;
+
+            Ok::<_, anyhow::Error>(file)
+        })
+        .await
+        .context("Unable to lock postfix map")??;
+
+        Ok(MapLock {
+            _guard: guard,
+            _file: file,
+        })
+    }
+
+    async fn load(&self) -> Result<PostfixMap> {
+        let path = &self.config.map_file;
+
+        if !path.exists() {
+            return Ok(PostfixMap { lines: Vec::new() });
+        }
+
+        let data = fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Unable to read {}", path.display()))?;
+
+        Ok(PostfixMap::parse(&data))
+    }
+
+    async fn save(&self, map: &PostfixMap) -> Result<()> {
+        write_atomic(&self.config.map_file, map.encode()).await?;
+
+        if let Some(cmd) = &self.config.reload_command {
+            info!("running {cmd}");
+
+            let status = Command::new("sh")
+                .arg("-c")
+                .arg(cmd)
+                .status()
+                .await
+                .with_context(|| format!("Unable to run {cmd}"))?;
+
+            if !status.success() {
+                bail!("{cmd} returned {status}");
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn modify<F>(&self, id: &str, operation: RMOperation, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut PostfixEntry) + Send,
+    {
+        let _lock = self.lock_map().await?;
+
+        let mut map = self.load().await?;
+
+        let entry = map.find_mut(id)?;
+        let before = entry.to_alias();
+
+        f(entry);
+        let after = entry.to_alias();
+
+        let res = self.save(&map).await;
+        self.record(operation, Some(&before), Some(&after), &res);
+
+        res
+    }
+}
+
+/// `path` with `suffix` appended to its file name
+fn with_suffix<S>(path: &Path, suffix: S) -> Result<PathBuf>
+where
+    S: AsRef<OsStr>,
+{
+    let mut file_name = path
+        .file_name()
+        .with_context(|| format!("{} is not a file", path.display()))?
+        .to_os_string();
+
+    file_name.push(suffix);
+
+    Ok(path.with_file_name(file_name))
+}
+
+async fn write_atomic<P, D>(path: P, data: D) -> Result<()>
+where
+    P: AsRef<Path>,
+    D: AsRef<[u8]>,
+{
+    let path = path.as_ref();
+
+    // unique per writer, so that no two of them share a temporary file
+    let tmp_file = with_suffix(
+        path,
+        format!(
+            ".randomail.{}.{:08x}.tmp",
+            std::process::id(),
+            rand::random::<u32>()
+        ),
+    )?;
+
+    let res = write_synced(&tmp_file, data.as_ref(), path).await;
+
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp_file).await;
+    }
+
+    res
+}
+
+/// Write `data` to `tmp_file` and flush it to disk, then move it over `path`
+async fn write_synced(tmp_file: &Path, data: &[u8], path: &Path) -> Result<()> {
+    let mut file = fs::File::create(tmp_file)
+        .await
+        .with_context(|| format!("Unable to create {}", tmp_file.display()))?;
+
+    file.write_all(data)
+        .await
+        .with_context(|| format!("Unable to write to {}", tmp_file.display()))?;
+
+    if let Ok(stat) = fs::metadata(path).await {
+        file.set_permissions(stat.permissions())
+            .await
+            .with_context(|| format!("Unable to set mode on {}", tmp_file.display()))?;
+    }
+
+    file.sync_all()
+        .await
+        .with_context(|| format!("Unable to sync {}", tmp_file.display()))?;
+
+    info!("writing {}", path.display());
+
+    fs::rename(tmp_file, path)
+        .await
+        .with_context(|| format!("Unable to rename {}", tmp_file.display()))
+}
+
+#[async_trait]
+impl AliasProvider for PostfixProvider {
+    fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    fn capabilities(&self) -> RMCapabilities {
+        RMCapabilities {
+            toggle: true,
+            rename: true,
+            metadata: false,
+            tags: false,
+            readdress: true,
+            retarget: true,
+            trash: false,
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<RMAlias>> {
+        let map = self.load().await?;
+        Ok(map.entries().map(PostfixEntry::to_alias).collect())
+    }
+
+    async fn get(&self, id: &str) -> Result<RMAlias> {
+        let map = self.load().await?;
+
+        map.entries()
+            .find(|e| e.alias == id)
+            .map(PostfixEntry::to_alias)
+            .ok_or_else(|| {
+                RMAliasNotFound {
+                    query: id.to_string(),
+                }
+                .into()
+            })
+    }
+
+    async fn create(&self, name: &str, email_alias: &str, email_dest: &str) -> Result<RMAlias> {
+        let _lock = self.lock_map().await?;
+
+        let mut map = self.load().await?;
+
+        if let Some(existing) = map
+            .entries()
+            .find(|e| e.alias.eq_ignore_ascii_case(email_alias))
+        {
+            return Err(RMAlreadyExists {
+                alias: existing.to_alias(),
+            }
+            .into());
+        }
+
+        let entry = PostfixEntry {
+            name: (!name.is_empty()).then(|| name.to_string()),
+            alias: email_alias.to_string(),
+            value: email_dest.to_string(),
+            enabled: true,
+        };
+
+        let alias = entry.to_alias();
+
+        map.lines.push(PostfixLine::Entry(entry));
+
+        let res = self.save(&map).await;
+        self.record(RMOperation::Create, None, Some(&alias), &res);
+        res?;
+
+        Ok(alias)
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        let _lock = self.lock_map().await?;
+
+        let mut map = self.load().await?;
+
+        let before = map.find_mut(id)?.to_alias();
+
+        map.lines
+            .retain(|l| !matches!(l, PostfixLine::Entry(e) if e.alias == id));
+
+        let res = self.save(&map).await;
+        self.record(RMOperation::Delete, Some(&before), None, &res);
+
+        res
+    }
+
+    async fn set_enabled(&self, id: &str, enabled: bool) -> Result<()> {
+        let operation = if enabled {
+            RMOperation::Enable
+        } else {
+            RMOperation::Disable
+        };
+
+        self.modify(id, operation, |e| e.enabled = enabled).await
+    }
+
+    async fn rename(&self, id: &str, name: &str) -> Result<()> {
+        let name = (!name.is_empty()).then(|| name.to_string());
+        self.modify(id, RMOperation::Rename, |e| e.name = name)
+            .await
+    }
+
+    async fn set_destinations(&self, id: &str, email_dests: &[String]) -> Result<()> {
+        if email_dests.is_empty() {
+            bail!("{id}: an alias needs at least one destination");
+        }
+
+        self.modify(id, RMOperation::Retarget, |e| {
+            e.set_destinations(email_dests);
+        })
+        .await
+    }
+
+    async fn readdress(&self, id: &str, email_alias: &str) -> Result<RMAlias> {
+        let _lock = self.lock_map().await?;
+
+        let mut map = self.load().await?;
+
+        if let Some(existing) = map
+            .entries()
+            .find(|e| e.alias != id && e.alias.eq_ignore_ascii_case(email_alias))
+        {
+            return Err(RMAlreadyExists {
+                alias: existing.to_alias(),
+            }
+            .into());
+        }
+
+        let entry = map.find_mut(id)?;
+        let before = entry.to_alias();
+
+        entry.alias = email_alias.to_string();
+
+        let alias = entry.to_alias();
+
+        let res = self.save(&map).await;
+        self.record(RMOperation::Readdress, Some(&before), Some(&alias), &res);
+        res?;
+
+        Ok(alias)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: &str = "\
+# virtual aliases, edited by hand too
+postmaster@example.com\troot
+
+# randomail-name: Shop
+shop@example.com\tyou@example.com
+#randomail-disabled news@example.com\tyou@example.com
+multi@example.com\tyou@example.com,
+    other@example.com
+";
+
+    /// A map file in a directory of its own, removed when dropped
+    struct TempMap {
+        dir: PathBuf,
+    }
+
+    impl TempMap {
+        fn new(data: &str) -> Result<Self> {
+            let dir = std::env::temp_dir().join(format!(
+                "randomail-postfix-{}-{:08x}",
+                std::process::id(),
+                rand::random::<u32>()
+            ));
+
+            std::fs::create_dir_all(&dir)?;
+            std::fs::write(dir.join("virtual"), data)?;
+
+            Ok(Self { dir })
+        }
+
+        fn path(&self) -> PathBuf {
+            self.dir.join("virtual")
+        }
+
+        fn provider(&self, reload_command: Option<&str>) -> PostfixProvider {
+            PostfixProvider::new(
+                "example.com",
+                RMPostfixConfig {
+                    map_file: self.path(),
+                    reload_command: reload_command.map(str::to_string),
+                },
+            )
+        }
+
+        fn read(&self) -> Result<String> {
+            Ok(std::fs::read_to_string(self.path())?)
+        }
+    }
+
+    impl Drop for TempMap {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn alias<'a>(aliases: &'a [RMAlias], email_alias: &str) -> Result<&'a RMAlias> {
+        aliases
+            .iter()
+            .find(|a| a.email_alias == email_alias)
+            .with_context(|| format!("{email_alias} missing"))
+    }
+
+    #[test]
+    fn parse_reads_markers() -> Result<()> {
+        let map = PostfixMap::parse(MAP);
+        let aliases: Vec<RMAlias> = map.entries().map(PostfixEntry::to_alias).collect();
+
+        assert_eq!(aliases.len(), 4);
+
+        let shop = alias(&aliases, "shop@example.com")?;
+        assert_eq!(shop.name, "Shop");
+        assert!(shop.enabled);
+
+        let news = alias(&aliases, "news@example.com")?;
+        assert_eq!(news.name, "");
+        assert!(!news.enabled);
+
+        let multi = alias(&aliases, "multi@example.com")?;
+        assert_eq!(multi.email_destination, "you@example.com");
+        assert_eq!(multi.extra_destinations, ["other@example.com"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn encode_keeps_raw_lines() {
+        let encoded = PostfixMap::parse(MAP).encode();
+
+        assert!(encoded.starts_with("# virtual aliases, edited by hand too\n"));
+        assert!(encoded.contains("postmaster@example.com\troot\n\n"));
+        assert!(encoded.contains("# randomail-name: Shop\nshop@example.com\tyou@example.com\n"));
+        assert!(encoded.contains("#randomail-disabled news@example.com\tyou@example.com\n"));
+
+        // encoding is stable once the continuation line has been folded in
+        assert_eq!(PostfixMap::parse(&encoded).encode(), encoded);
+    }
+
+    #[test]
+    fn orphan_name_marker_is_kept() {
+        let data = "# randomail-name: Lost\n# a comment\n";
+
+        assert_eq!(PostfixMap::parse(data).encode(), data);
+    }
+
+    #[test]
+    fn indented_line_after_comment_is_not_an_entry() {
+        let data = "shop@example.com\tyou@example.com\n# a comment\n  stray@example.com\tyou@example.com\n";
+        let map = PostfixMap::parse(data);
+
+        let aliases: Vec<&str> = map.entries().map(|e| e.alias.as_str()).collect();
+        assert_eq!(aliases, ["shop@example.com"]);
+        assert!(map.entries().all(|e| e.value == "you@example.com"));
+
+        assert_eq!(map.encode(), data);
+    }
+
+    #[tokio::test]
+    async fn create_disable_enable_rename() -> Result<()> {
+        let map = TempMap::new(MAP)?;
+        let provider = map.provider(None);
+
+        let created = provider
+            .create("Travel", "travel@example.com", "you@example.com")
+            .await?;
+        assert_eq!(created.id, "travel@example.com");
+        assert!(
+            map.read()?
+                .ends_with("# randomail-name: Travel\ntravel@example.com\tyou@example.com\n")
+        );
+
+        provider.set_enabled("travel@example.com", false).await?;
+        assert!(
+            map.read()?
+                .contains("#randomail-disabled travel@example.com\tyou@example.com\n")
+        );
+        assert!(!provider.get("travel@example.com").await?.enabled);
+
+        provider.set_enabled("travel@example.com", true).await?;
+        provider.rename("travel@example.com", "Trips").await?;
+
+        let travel = provider.get("travel@example.com").await?;
+        assert!(travel.enabled);
+        assert_eq!(travel.name, "Trips");
+
+        let data = map.read()?;
+        assert!(data.starts_with("# virtual aliases, edited by hand too\n"));
+        assert!(data.contains("# randomail-name: Trips\ntravel@example.com\tyou@example.com\n"));
+
+        provider.delete("travel@example.com").await?;
+        assert!(!map.read()?.contains("travel@example.com"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn empty_name_writes_no_marker() -> Result<()> {
+        let map = TempMap::new("")?;
+        let provider = map.provider(None);
+
+        provider
+            .create("", "travel@example.com", "you@example.com")
+            .await?;
+        assert_eq!(map.read()?, "travel@example.com\tyou@example.com\n");
+
+        provider.rename("travel@example.com", "Trips").await?;
+        provider.rename("travel@example.com", "").await?;
+        assert_eq!(map.read()?, "travel@example.com\tyou@example.com\n");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_rejects_existing_address() -> Result<()> {
+        let map = TempMap::new(MAP)?;
+        let provider = map.provider(None);
+
+        let err = provider
+            .create("Shop", "SHOP@example.com", "you@example.com")
+            .await
+            .err()
+            .context("created a duplicate")?;
+
+        let exists = err
+            .downcast_ref::<RMAlreadyExists>()
+            .context("not an RMAlreadyExists")?;
+        assert_eq!(exists.alias.email_alias, "shop@example.com");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn missing_alias_is_not_found() -> Result<()> {
+        let map = TempMap::new(MAP)?;
+        let provider = map.provider(None);
+
+        let err = provider
+            .set_enabled("nobody@example.com", false)
+            .await
+            .err()
+            .context("changed a missing alias")?;
+
+        assert!(err.downcast_ref::<RMAliasNotFound>().is_some());
+        assert_eq!(map.read()?, MAP);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failing_reload_command_is_reported() -> Result<()> {
+        let map = TempMap::new(MAP)?;
+        let provider = map.provider(Some("exit 3"));
+
+        let err = provider
+            .set_enabled("shop@example.com", false)
+            .await
+            .err()
+            .context("reload failure ignored")?;
+
+        assert!(err.to_string().starts_with("exit 3 returned"));
+
+        // the map itself was written before the reload
+        assert!(!provider.get("shop@example.com").await?.enabled);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn changes_are_audited() -> Result<()> {
+        let map = TempMap::new(MAP)?;
+        let audit = RMAuditLog::new(map.dir.join("audit.jsonl"));
+        let provider = map.provider(None).with_audit(audit.clone());
+
+        provider.rename("shop@example.com", "Shopping").await?;
+        provider.delete("news@example.com").await?;
+
+        let entries = audit.read(&crate::audit::RMAuditFilter::default())?;
+        let operations: Vec<RMOperation> = entries.iter().map(|e| e.operation).collect();
+        assert_eq!(operations, [RMOperation::Rename, RMOperation::Delete]);
+
+        let rename = entries.first().context("no entries")?;
+        assert_eq!(rename.alias.as_deref(), Some("shop@example.com"));
+        assert_eq!(rename.zone_id, "example.com");
+        assert_eq!(
+            rename.after.as_ref().and_then(|a| a.get("name")),
+            Some(&serde_json::json!("Shopping"))
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn concurrent_writers_lose_nothing() -> Result<()> {
+        let map = TempMap::new(MAP)?;
+
+        // separate providers only share the file lock, like two processes
+        let tasks: Vec<_> = (0..8)
+            .map(|i| {
+                let provider = map.provider(None);
+
+                tokio::spawn(async move {
+                    provider
+                        .create("", &format!("a{i}@example.com"), "you@example.com")
+                        .await
+                })
+            })
+            .collect();
+
+        for task in tasks {
+            task.await??;
+        }
+
+        assert_eq!(map.provider(None).list().await?.len(), 12);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn writes_leave_no_temporary_file() -> Result<()> {
+        let map = TempMap::new(MAP)?;
+        let provider = map.provider(None);
+
+        provider.rename("shop@example.com", "Shopping").await?;
+
+        let mut names: Vec<String> = std::fs::read_dir(&map.dir)?
+            .map(|e| Ok(e?.file_name().to_string_lossy().into_owned()))
+            .collect::<Result<_>>()?;
+        names.sort();
+
+        assert_eq!(names, ["virtual", "virtual.randomail.lock"]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+use std::fmt;
+
+use anyhow::{Result, bail};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tabled::Tabled;
+
+use crate::{
+    metadata::{RMMetadata, RMMetadataUpdate},
+    trash::RMTrashEntry,
+};
+
+#[derive(Debug, Default, Clone, Tabled, Serialize, Deserialize)]
+pub struct RMAlias {
+    #[tabled(skip)]
+    pub id: String,
+    #[tabled(skip)]
+    pub email_destination: String,
+    /// Recipients besides `email_destination`, Postfix can forward to several
+    #[tabled(skip)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extra_destinations: Vec<String>,
+    #[tabled(rename = "alias")]
+    pub email_alias: String,
+    pub name: String,
+    #[tabled(display = "display_tags")]
+    pub tags: Vec<String>,
+    pub enabled: bool,
+    #[tabled(inline)]
+    #[serde(flatten)]
+    pub metadata: RMMetadata,
+}
+
+impl RMAlias {
+    /// Every address the alias forwards to
+    pub fn destinations(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.email_destination.as_str())
+            .chain(self.extra_destinations.iter().map(String::as_str))
+    }
+
+    #[must_use]
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t.eq_ignore_ascii_case(tag))
+    }
+}
+
+/// `create` was asked for an address that already has an alias
+#[derive(Debug)]
+pub struct RMAlreadyExists {
+    pub alias: RMAlias,
+}
+
+impl RMAlreadyExists {
+    /// Run `f` on the alias carried by `err` when it's an `RMAlreadyExists`,
+    /// so decorators can complete it like any other alias
+    pub fn map<F>(err: anyhow::Error, f: F) -> anyhow::Error
+    where
+        F: FnOnce(RMAlias) -> RMAlias,
+    {
+        match err.downcast::<Self>() {
+            Ok(exists) => Self {
+                alias: f(exists.alias),
+            }
+            .into(),
+            Err(err) => err,
+        }
+    }
+}
+
+impl fmt::Display for RMAlreadyExists {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} already exists", self.alias.email_alias)
+    }
+}
+
+impl std::error::Error for RMAlreadyExists {}
+
+/// Some items of a bulk change failed, the others went through
+#[derive(Debug)]
+pub struct RMPartialFailure {
+    pub failed: usize,
+    pub total: usize,
+    /// What happened to the failed items, e.g. "changes failed"
+    pub what: String,
+}
+
+impl fmt::Display for RMPartialFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of {} {}", self.failed, self.total, self.what)
+    }
+}
+
+impl std::error::Error for RMPartialFailure {}
+
+/// What a backend is able to do beyond listing, creating and deleting aliases
+// independent flags, serialized as is for the web UI
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct RMCapabilities {
+    pub toggle: bool,
+    pub rename: bool,
+    /// Website and notes can be attached with `annotate`
+    pub metadata: bool,
+    /// Tags can be changed with `set_tags`
+    pub tags: bool,
+    /// The address of an alias can be changed with `readdress`
+    pub readdress: bool,
+    /// Where an alias forwards to can be changed with `set_destinations`
+    pub retarget: bool,
+    /// Deleted aliases can be brought back with `untrash`
+    pub trash: bool,
+}
+
+fn display_tags(tags: &[String]) -> String {
+    tags.join(", ")
+}
+
+/// A mail backend that stores email aliases
+#[async_trait]
+pub trait AliasProvider: Send + Sync {
+    /// Domain aliases are created under
+    fn domain(&self) -> &str;
+
+    fn capabilities(&self) -> RMCapabilities;
+
+    async fn list(&self) -> Result<Vec<RMAlias>>;
+
+    async fn get(&self, id: &str) -> Result<RMAlias>;
+
+    /// Create `email_alias` forwarding to `email_dest` and return the new alias.
+    /// Fails with `RMAlreadyExists` when the address is taken, whatever its case.
+    async fn create(&self, name: &str, email_alias: &str, email_dest: &str) -> Result<RMAlias>;
+
+    /// Like `create`, for callers that already checked `email_alias` is free
+    /// against a `list` of their own, such as bulk changes. Backends where
+    /// the check costs a listing skip it, so a batch lists once instead of
+    /// once per alias.
+    async fn create_unchecked(
+        &self,
+        name: &str,
+        email_alias: &str,
+        email_dest: &str,
+    ) -> Result<RMAlias> {
+        self.create(name, email_alias, email_dest).await
+    }
+
+    async fn delete(&self, id: &str) -> Result<()>;
+
+    async fn set_enabled(&self, id: &str, enabled: bool) -> Result<()>;
+
+    async fn rename(&self, id: &str, name: &str) -> Result<()>;
+
+    /// Change the address of an alias, keeping its description, destination
+    /// and state. Returns the updated alias, whose id may have changed.
+    async fn readdress(&self, id: &str, email_alias: &str) -> Result<RMAlias> {
+        let _ = email_alias;
+        bail!("{id}: this backend can't change alias addresses")
+    }
+
+    /// Forward the alias to `email_dests` instead of its current
+    /// destinations. The first one becomes its `email_destination`.
+    async fn set_destinations(&self, id: &str, email_dests: &[String]) -> Result<()> {
+        let _ = email_dests;
+        bail!("{id}: this backend can't change alias destinations")
+    }
+
+    /// Change the metadata randomail keeps about an alias
+    async fn annotate(&self, id: &str, update: &RMMetadataUpdate) -> Result<()> {
+        let _ = update;
+        bail!("{id}: this backend does not store metadata")
+    }
+
+    /// Replace the tags of an alias
+    async fn set_tags(&self, id: &str, tags: &[String]) -> Result<()> {
+        let _ = tags;
+        bail!("{id}: this backend does not store tags")
+    }
+
+    /// Deleted aliases that can still be restored, most recent first
+    async fn trashed(&self) -> Result<Vec<RMTrashEntry>> {
+        bail!("this backend has no trash")
+    }
+
+    /// Recreate the most recently deleted alias, or the latest one with the
+    /// address `email_alias`, and return it
+    async fn untrash(&self, email_alias: Option<&str>) -> Result<RMAlias> {
+        let _ = email_alias;
+        bail!("this backend has no trash")
+    }
+}
@@ -0,0 +1,178 @@
+use anyhow::Result;
+use futures_util::{StreamExt, stream};
+use log::{error, info};
+
+use crate::provider::{AliasProvider, RMAlias, RMPartialFailure};
+
+/// Rules updated at the same time, enough to hide the latency of the API
+/// without running into its rate limit
+pub const RETARGET_CONCURRENCY: usize = 4;
+
+/// Aliases forwarding to `from`, the ones `retarget` would change
+pub async fn retarget_plan(provider: &dyn AliasProvider, from: &str) -> Result<Vec<RMAlias>> {
+    let mut aliases = provider.list().await?;
+    aliases.retain(|a| a.destinations().any(|d| d.eq_ignore_ascii_case(from)));
+    Ok(aliases)
+}
+
+/// Destinations of `alias` with `from` replaced by `to`, leaving its other
+/// recipients alone
+fn replace_destination(alias: &RMAlias, from: &str, to: &str) -> Vec<String> {
+    let mut dests: Vec<String> = Vec::new();
+
+    for dest in alias.destinations() {
+        let dest = if dest.eq_ignore_ascii_case(from) {
+            to
+        } else {
+            dest
+        };
+
+        if !dests.iter().any(|d| d.eq_ignore_ascii_case(dest)) {
+            dests.push(dest.to_string());
+        }
+    }
+
+    dests
+}
+
+/// Forward every alias to `dests(alias)`, `RETARGET_CONCURRENCY` at a time,
+/// calling `progress` as each one completes
+async fn update_all<'a, D, P>(
+    provider: &dyn AliasProvider,
+    aliases: &'a [RMAlias],
+    dests: D,
+    mut progress: P,
+) -> Vec<(&'a RMAlias, Result<()>)>
+where
+    D: Fn(&RMAlias) -> Vec<String>,
+    P: FnMut(&RMAlias, &Result<()>),
+{
+    let dests = &dests;
+
+    let mut updates = stream::iter(aliases)
+        .map(|alias| async move {
+            let res = provider.set_destinations(&alias.id, &dests(alias)).await;
+            (alias, res)
+        })
+        .buffer_unordered(RETARGET_CONCURRENCY);
+
+    let mut done = Vec::new();
+
+    while let Some((alias, res)) = updates.next().await {
+        progress(alias, &res);
+        done.push((alias, res));
+    }
+
+    done
+}
+
+/// Forward `aliases` to `to` instead of `from`, keeping their other
+/// destinations. When any of them fails, the ones already changed get back
+/// every destination they had so the rules aren't left half moved.
+pub async fn retarget<P>(
+    provider: &dyn AliasProvider,
+    aliases: &[RMAlias],
+    from: &str,
+    to: &str,
+    progress: P,
+) -> Result<()>
+where
+    P: FnMut(&RMAlias, &Result<()>),
+{
+    let results = update_all(
+        provider,
+        aliases,
+        |a| replace_destination(a, from, to),
+        progress,
+    )
+    .await;
+
+    let (changed, failed): (Vec<_>, Vec<_>) = results.into_iter().partition(|(_, r)| r.is_ok());
+
+    if failed.is_empty() {
+        info!("{} aliases now forward to {to}", changed.len());
+        return Ok(());
+    }
+
+    for (alias, res) in &failed {
+        if let Err(e) = res {
+            error!("Unable to retarget {} ({e})", alias.email_alias);
+        }
+    }
+
+    let changed: Vec<RMAlias> = changed.into_iter().map(|(a, _)| a.clone()).collect();
+
+    info!("moving {} aliases back", changed.len());
+
+    let restored = update_all(
+        provider,
+        &changed,
+        |a| a.destinations().map(str::to_string).collect(),
+        |alias, res| {
+            if let Err(e) = res {
+                error!(
+                    "Unable to restore {} to {} ({e})",
+                    alias.email_alias,
+                    alias.destinations().collect::<Vec<_>>().join(", ")
+                );
+            }
+        },
+    )
+    .await;
+
+    let stuck = restored.iter().filter(|(_, r)| r.is_err()).count();
+
+    let what = if stuck > 0 {
+        format!(
+            "aliases could not be retargeted and {stuck} could not be moved back to their old destination"
+        )
+    } else {
+        "aliases could not be retargeted, the others were moved back".to_string()
+    };
+
+    Err(RMPartialFailure {
+        failed: failed.len(),
+        total: aliases.len(),
+        what,
+    }
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alias(dests: &[&str]) -> RMAlias {
+        let mut dests = dests.iter().map(ToString::to_string);
+
+        RMAlias {
+            email_destination: dests.next().unwrap_or_default(),
+            extra_destinations: dests.collect(),
+            ..RMAlias::default()
+        }
+    }
+
+    #[test]
+    fn replace_only_from() {
+        let cases: &[(&[&str], &[&str])] = &[
+            (&["old@x.example"], &["new@x.example"]),
+            (
+                &["old@x.example", "other@y.example"],
+                &["new@x.example", "other@y.example"],
+            ),
+            (
+                &["other@y.example", "OLD@x.example"],
+                &["other@y.example", "new@x.example"],
+            ),
+            // already forwarding to `to` as well
+            (&["old@x.example", "new@x.example"], &["new@x.example"]),
+        ];
+
+        for (dests, want) in cases {
+            assert_eq!(
+                replace_destination(&alias(dests), "old@x.example", "new@x.example"),
+                *want
+            );
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::{error, info};
+
+use crate::{
+    generator::{RMNameStrategy, unique_name},
+    metadata::RMMetadataUpdate,
+    provider::{AliasProvider, RMAlias},
+    site::{normalize_site, site_alias_name},
+    tags::encode_tags,
+};
+
+/// What happens to the old alias once its replacement exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RMRetirePolicy {
+    Disable,
+    Delete,
+    /// Keep it working until then, the expiry policy applies afterwards
+    After(DateTime<Utc>),
+}
+
+async fn retire(
+    provider: &dyn AliasProvider,
+    old: &RMAlias,
+    new: &RMAlias,
+    policy: RMRetirePolicy,
+) -> Result<()> {
+    if policy == RMRetirePolicy::Delete {
+        return provider.delete(&old.id).await;
+    }
+
+    let update = RMMetadataUpdate {
+        replaced_by: Some(new.email_alias.clone()),
+        expires_at: match policy {
+            RMRetirePolicy::After(date) => Some(date),
+            _ => None,
+        },
+        ..RMMetadataUpdate::default()
+    };
+
+    provider.annotate(&old.id, &update).await?;
+
+    if policy == RMRetirePolicy::Disable {
+        provider.set_enabled(&old.id, false).await?;
+    }
+
+    Ok(())
+}
+
+/// A fresh name for the replacement of `old`, from the site template when
+/// `old` belongs to a website and from `strategy` otherwise
+pub async fn replacement_name(
+    provider: &dyn AliasProvider,
+    old: &RMAlias,
+    strategy: RMNameStrategy,
+    site_template: &str,
+) -> Result<String> {
+    let site = old.metadata.website.as_deref().map(normalize_site);
+
+    match site {
+        Some(Ok(site)) => site_alias_name(provider, site_template, &site).await,
+        _ => unique_name(provider, strategy).await,
+    }
+}
+
+/// Undo what `retire` may have done to `old` before failing
+async fn restore(provider: &dyn AliasProvider, old: &RMAlias) -> Result<()> {
+    let update = RMMetadataUpdate {
+        replaced_by: Some(String::new()),
+        expires_at: old.metadata.expires_at,
+        clear_expiry: old.metadata.expires_at.is_none(),
+        ..RMMetadataUpdate::default()
+    };
+
+    provider.annotate(&old.id, &update).await?;
+    provider.set_enabled(&old.id, old.enabled).await
+}
+
+/// Replace `old` with a new alias at `email_alias` that has the same
+/// description, tags, destination and metadata, then retire `old` following
+/// `policy`. The new alias is removed again when any step fails, so `old`
+/// keeps working.
+pub async fn rotate(
+    provider: &dyn AliasProvider,
+    old: &RMAlias,
+    email_alias: &str,
+    policy: RMRetirePolicy,
+) -> Result<RMAlias> {
+    let new = provider
+        .create(
+            &encode_tags(&old.name, &old.tags)?,
+            email_alias,
+            &old.email_destination,
+        )
+        .await?;
+
+    let update = RMMetadataUpdate {
+        website: old.metadata.website.clone(),
+        notes: old.metadata.notes.clone(),
+        replaces: Some(old.email_alias.clone()),
+        ..RMMetadataUpdate::default()
+    };
+
+    let res = match provider.annotate(&new.id, &update).await {
+        Ok(()) => retire(provider, old, &new, policy).await,
+        Err(e) => Err(e),
+    };
+
+    if let Err(e) = res {
+        error!(
+            "Unable to rotate {}, removing {}",
+            old.email_alias, new.email_alias
+        );
+
+        if let Err(e) = provider.delete(&new.id).await {
+            error!("Unable to remove {} ({e})", new.email_alias);
+        }
+
+        if policy != RMRetirePolicy::Delete
+            && let Err(e) = restore(provider, old).await
+        {
+            error!("Unable to restore {} ({e})", old.email_alias);
+        }
+
+        return Err(e);
+    }
+
+    info!("{} rotated to {}", old.email_alias, new.email_alias);
+
+    provider.get(&new.id).await
+}
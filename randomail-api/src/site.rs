@@ -0,0 +1,104 @@
+use anyhow::{Result, bail};
+
+use crate::{
+    error::RMInvalidInput,
+    generator::{MAX_ATTEMPTS, existing_names, random_chars},
+    provider::{AliasProvider, RMAlias},
+};
+
+pub const DEFAULT_SITE_TEMPLATE: &str = "{site}-{rand4}";
+
+/// Longest `{randN}` placeholder allowed in a site template
+const MAX_RAND_LEN: usize = 16;
+
+/// Reduce a URL or host to a bare host, `https://www.Amazon.com/cart` becomes
+/// `amazon.com`
+pub fn normalize_site(site: &str) -> Result<String> {
+    let lower = site.trim().to_lowercase();
+
+    let host = lower.split_once("://").map_or(lower.as_str(), |(_, r)| r);
+    let host = host.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = host.rsplit_once('@').map_or(host, |(_, h)| h);
+    let host = host.split(':').next().unwrap_or_default();
+    let host = host.strip_prefix("www.").unwrap_or(host);
+
+    let valid = host
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+
+    if host.is_empty() || !valid || host.starts_with('.') || host.ends_with('.') {
+        return Err(RMInvalidInput(format!("{site} is not a valid site")).into());
+    }
+
+    Ok(host.to_string())
+}
+
+/// Part of the host that goes in alias names, `mail.google.com` becomes
+/// `mail-google`
+fn site_label(host: &str) -> String {
+    let base = host.rsplit_once('.').map_or(host, |(base, _)| base);
+    base.replace('.', "-")
+}
+
+/// Fill in the `{site}` and `{randN}` placeholders of `template`
+pub fn expand_template(template: &str, site: &str) -> Result<String> {
+    let mut name = String::new();
+    let mut rest = template;
+
+    while let Some((before, after)) = rest.split_once('{') {
+        name.push_str(before);
+
+        let Some((placeholder, after)) = after.split_once('}') else {
+            bail!("unterminated placeholder in {template}");
+        };
+
+        if placeholder == "site" {
+            name.push_str(&site_label(site));
+        } else {
+            match placeholder.strip_prefix("rand").map(str::parse::<usize>) {
+                Some(Ok(len)) if (1..=MAX_RAND_LEN).contains(&len) => {
+                    name.push_str(&random_chars(len));
+                }
+                _ => bail!("unknown placeholder {{{placeholder}}} in {template}"),
+            }
+        }
+
+        rest = after;
+    }
+
+    name.push_str(rest);
+
+    Ok(name)
+}
+
+/// An unused alias name for `site` built from `template`
+pub async fn site_alias_name(
+    provider: &dyn AliasProvider,
+    template: &str,
+    site: &str,
+) -> Result<String> {
+    let existing = existing_names(provider).await?;
+
+    for _ in 0..MAX_ATTEMPTS {
+        let name = expand_template(template, site)?;
+
+        if !existing.contains(&name) {
+            return Ok(name);
+        }
+    }
+
+    bail!("Unable to find an unused name for {site} with {template}")
+}
+
+/// Whether `alias` is used for `site` (or one of its subdomains), going by
+/// its website or its description
+#[must_use]
+pub fn matches_site(alias: &RMAlias, site: &str) -> bool {
+    let subdomain = format!(".{site}");
+
+    [alias.metadata.website.as_deref(), Some(alias.name.as_str())]
+        .into_iter()
+        .flatten()
+        .filter_map(|s| normalize_site(s).ok())
+        .any(|s| s == site || s.ends_with(&subdomain))
+}
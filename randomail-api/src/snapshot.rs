@@ -0,0 +1,593 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tabled::Tabled;
+
+use crate::{
+    cf_email::{CFEmailRule, create_email_rule, list_email_rules, replace_email_rule},
+    config::{RMConfig, get_config_dir},
+    http::CFClient,
+    provider::RMPartialFailure,
+};
+
+pub const SNAPSHOT_DIR_NAME: &str = "snapshots";
+
+/// Automatic snapshots kept, older ones are deleted as new ones are taken
+pub const AUTO_SNAPSHOTS_KEPT: usize = 50;
+
+/// Every routing rule of a zone at some point in time
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RMSnapshot {
+    pub zone: String,
+    pub zone_id: String,
+    pub taken_at: DateTime<Utc>,
+    /// Command the snapshot was taken before, none when taken by hand
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub rules: Vec<CFEmailRule>,
+}
+
+impl RMSnapshot {
+    /// Fetch the current rules of the configured zone, only Cloudflare has
+    /// rules to snapshot
+    pub async fn take(config: &RMConfig, client: &CFClient, reason: Option<&str>) -> Result<Self> {
+        if config.postfix.is_some() {
+            bail!("snapshots are only supported with Cloudflare, back up the Postfix map instead");
+        }
+
+        let rules = list_email_rules(client, &config.zone_id).await?;
+
+        Ok(Self {
+            zone: config.zone.clone(),
+            zone_id: config.zone_id.clone(),
+            taken_at: Utc::now(),
+            reason: reason.map(str::to_string),
+            rules,
+        })
+    }
+
+    pub fn load<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+
+        let data = fs::read(path).with_context(|| format!("Unable to read {}", path.display()))?;
+
+        serde_json::from_slice(&data).with_context(|| format!("Unable to parse {}", path.display()))
+    }
+
+    pub fn save<P>(&self, path: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+
+        let data = serde_json::to_vec_pretty(self).context("Unable to serialize snapshot")?;
+
+        info!("writing {}", path.display());
+
+        fs::write(path, data).with_context(|| format!("Unable to write {}", path.display()))
+    }
+
+    /// `<zone>-<time>.json`, automatic snapshots add `-auto-<reason>`
+    fn file_name(&self) -> String {
+        let time = self.taken_at.format("%Y%m%dT%H%M%S%.3fZ");
+
+        match &self.reason {
+            Some(reason) => format!("{}-{time}-auto-{reason}.json", self.zone),
+            None => format!("{}-{time}.json", self.zone),
+        }
+    }
+}
+
+pub fn get_snapshot_dir() -> Result<PathBuf> {
+    let dir = get_config_dir()?.join(SNAPSHOT_DIR_NAME);
+
+    if !dir.exists() {
+        fs::create_dir_all(&dir).with_context(|| format!("Unable to create {}", dir.display()))?;
+    }
+
+    Ok(dir)
+}
+
+/// Snapshot files, oldest first
+fn snapshot_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = fs::read_dir(dir).with_context(|| format!("Unable to read {}", dir.display()))?;
+
+    let mut files = Vec::new();
+
+    for entry in entries {
+        let path = entry
+            .with_context(|| format!("Unable to read {}", dir.display()))?
+            .path();
+
+        if path.extension().is_some_and(|e| e == "json") {
+            files.push(path);
+        }
+    }
+
+    files.sort_by_key(|p| fs::metadata(p).and_then(|m| m.modified()).ok());
+
+    Ok(files)
+}
+
+/// Save `snapshot` in the snapshot directory and return its path. Taking an
+/// automatic snapshot deletes the oldest ones beyond `AUTO_SNAPSHOTS_KEPT`.
+pub fn store_snapshot(snapshot: &RMSnapshot) -> Result<PathBuf> {
+    let dir = get_snapshot_dir()?;
+    let path = dir.join(snapshot.file_name());
+
+    snapshot.save(&path)?;
+
+    if snapshot.reason.is_some() {
+        let auto: Vec<PathBuf> = snapshot_files(&dir)?
+            .into_iter()
+            .filter(|p| p.to_string_lossy().contains("-auto-"))
+            .collect();
+
+        let excess = auto.len().saturating_sub(AUTO_SNAPSHOTS_KEPT);
+
+        for old in auto.iter().take(excess) {
+            info!("removing {}", old.display());
+            fs::remove_file(old).with_context(|| format!("Unable to remove {}", old.display()))?;
+        }
+    }
+
+    Ok(path)
+}
+
+/// Snapshot the rules before `command` changes them, does nothing with
+/// Postfix
+pub async fn auto_snapshot(
+    config: &RMConfig,
+    client: &CFClient,
+    command: &str,
+) -> Result<Option<PathBuf>> {
+    if config.postfix.is_some() {
+        return Ok(None);
+    }
+
+    let snapshot = RMSnapshot::take(config, client, Some(command))
+        .await
+        .with_context(|| format!("Unable to take a snapshot before {command}"))?;
+
+    store_snapshot(&snapshot).map(Some)
+}
+
+/// A snapshot file given as a path or as a name in the snapshot directory,
+/// with or without `.json`
+pub fn resolve_snapshot(name: &str) -> Result<PathBuf> {
+    let path = PathBuf::from(name);
+
+    if path.exists() {
+        return Ok(path);
+    }
+
+    let dir = get_snapshot_dir()?;
+
+    for candidate in [dir.join(name), dir.join(format!("{name}.json"))] {
+        if candidate.exists() {
+            return Ok(candidate);
+        }
+    }
+
+    bail!("no snapshot named {name} in {}", dir.display())
+}
+
+#[derive(Debug, Serialize, Tabled)]
+pub struct RMSnapshotInfo {
+    pub name: String,
+    #[tabled(skip)]
+    pub path: PathBuf,
+    pub taken_at: DateTime<Utc>,
+    #[tabled(display = "display_reason")]
+    pub reason: Option<String>,
+    pub rules: usize,
+}
+
+impl RMSnapshotInfo {
+    #[must_use]
+    pub fn new(path: &Path, snapshot: &RMSnapshot) -> Self {
+        Self {
+            name: path
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            path: path.to_path_buf(),
+            taken_at: snapshot.taken_at,
+            reason: snapshot.reason.clone(),
+            rules: snapshot.rules.len(),
+        }
+    }
+}
+
+// tabled hands display functions a reference to the field
+#[allow(clippy::ref_option)]
+fn display_reason(reason: &Option<String>) -> String {
+    reason.clone().unwrap_or_default()
+}
+
+/// Stored snapshots, oldest first
+pub fn list_snapshots() -> Result<Vec<RMSnapshotInfo>> {
+    let mut infos = Vec::new();
+
+    for path in snapshot_files(&get_snapshot_dir()?)? {
+        let snapshot = match RMSnapshot::load(&path) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                error!("{e:#}");
+                continue;
+            }
+        };
+
+        infos.push(RMSnapshotInfo::new(&path, &snapshot));
+    }
+
+    Ok(infos)
+}
+
+/// A field of a rule that differs between two snapshots
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RMRuleField {
+    Name,
+    Enabled,
+    Priority,
+    Matchers,
+    Actions,
+}
+
+impl fmt::Display for RMRuleField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let field = match self {
+            Self::Name => "name",
+            Self::Enabled => "enabled",
+            Self::Priority => "priority",
+            Self::Matchers => "matchers",
+            Self::Actions => "actions",
+        };
+
+        f.write_str(field)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RMRuleFieldChange {
+    pub field: RMRuleField,
+    pub from: String,
+    pub to: String,
+}
+
+impl fmt::Display for RMRuleFieldChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} -> {}", self.field, self.from, self.to)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "change", rename_all = "lowercase")]
+pub enum RMRuleChange {
+    /// Only in the newer rule set
+    Added(CFEmailRule),
+    Changed {
+        from: CFEmailRule,
+        to: CFEmailRule,
+        fields: Vec<RMRuleFieldChange>,
+    },
+    /// Only in the older rule set
+    Removed(CFEmailRule),
+}
+
+/// How a rule is shown in diffs, its address when it has one
+fn label(rule: &CFEmailRule) -> &str {
+    rule.email_alias()
+        .or_else(|| (!rule.name.is_empty()).then_some(rule.name.as_str()))
+        .unwrap_or(&rule.id)
+}
+
+impl RMRuleChange {
+    /// Address, name or id of the rule the change is about
+    #[must_use]
+    pub fn label(&self) -> &str {
+        label(change_rule(self))
+    }
+}
+
+impl fmt::Display for RMRuleChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Added(rule) => write!(f, "+ {}", label(rule)),
+            Self::Changed { to, fields, .. } => {
+                write!(f, "~ {}", label(to))?;
+
+                for field in fields {
+                    write!(f, "\n    {field}")?;
+                }
+
+                Ok(())
+            }
+            Self::Removed(rule) => write!(f, "- {}", label(rule)),
+        }
+    }
+}
+
+/// What identifies a rule across snapshots. Ids change when a rule is
+/// recreated, so rules are matched by address, or by their matchers when
+/// they have no address.
+fn rule_key(rule: &CFEmailRule) -> String {
+    match rule.email_alias() {
+        Some(alias) => alias.to_lowercase(),
+        None => serde_json::to_string(&rule.matchers).unwrap_or_else(|_| rule.id.clone()),
+    }
+}
+
+fn json(value: &[serde_json::Value]) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+pub(crate) fn compare(from: &CFEmailRule, to: &CFEmailRule) -> Vec<RMRuleFieldChange> {
+    let mut fields = Vec::new();
+
+    let mut push = |field, a: String, b: String| {
+        if a != b {
+            fields.push(RMRuleFieldChange {
+                field,
+                from: a,
+                to: b,
+            });
+        }
+    };
+
+    push(
+        RMRuleField::Name,
+        format!("{:?}", from.name),
+        format!("{:?}", to.name),
+    );
+    push(
+        RMRuleField::Enabled,
+        from.enabled.to_string(),
+        to.enabled.to_string(),
+    );
+    push(
+        RMRuleField::Priority,
+        from.priority.to_string(),
+        to.priority.to_string(),
+    );
+    push(
+        RMRuleField::Matchers,
+        json(&from.matchers),
+        json(&to.matchers),
+    );
+    push(RMRuleField::Actions, json(&from.actions), json(&to.actions));
+
+    fields
+}
+
+/// Rules added, changed and removed going from `from` to `to`
+#[must_use]
+pub fn diff(from: &[CFEmailRule], to: &[CFEmailRule]) -> Vec<RMRuleChange> {
+    let mut old: HashMap<String, &CFEmailRule> = HashMap::new();
+
+    for rule in from {
+        old.entry(rule_key(rule)).or_insert(rule);
+    }
+
+    let mut changes = Vec::new();
+
+    for rule in to {
+        match old.remove(&rule_key(rule)) {
+            None => changes.push(RMRuleChange::Added(rule.clone())),
+            Some(prev) => {
+                let fields = compare(prev, rule);
+
+                if !fields.is_empty() {
+                    changes.push(RMRuleChange::Changed {
+                        from: prev.clone(),
+                        to: rule.clone(),
+                        fields,
+                    });
+                }
+            }
+        }
+    }
+
+    for rule in from {
+        if old.remove(&rule_key(rule)).is_some() {
+            changes.push(RMRuleChange::Removed(rule.clone()));
+        }
+    }
+
+    changes
+}
+
+/// Changes bringing `current` back to `snapshot`: rules missing now are
+/// recreated and changed ones reset. Rules added since are kept.
+#[must_use]
+pub fn restore_plan(current: &[CFEmailRule], snapshot: &RMSnapshot) -> Vec<RMRuleChange> {
+    diff(current, &snapshot.rules)
+        .into_iter()
+        .filter(|c| !matches!(c, RMRuleChange::Removed(_)))
+        .collect()
+}
+
+/// Carry out a `restore_plan` one rule at a time, calling `progress` after
+/// each. Failures don't stop the other rules.
+pub async fn restore<P>(
+    client: &CFClient,
+    zone_id: &str,
+    changes: &[RMRuleChange],
+    mut progress: P,
+) -> Result<()>
+where
+    P: FnMut(&RMRuleChange, &Result<()>),
+{
+    let mut failed = 0_usize;
+
+    for change in changes {
+        let res = match change {
+            RMRuleChange::Added(rule) => create_email_rule(client, zone_id, rule).await.map(|_| ()),
+            RMRuleChange::Changed { from, to, .. } => {
+                replace_email_rule(client, zone_id, &from.id, to).await
+            }
+            RMRuleChange::Removed(_) => Ok(()),
+        };
+
+        if let Err(e) = &res {
+            error!("Unable to restore {} ({e})", label(change_rule(change)));
+            failed = failed.saturating_add(1);
+        }
+
+        progress(change, &res);
+    }
+
+    if failed > 0 {
+        return Err(RMPartialFailure {
+            failed,
+            total: changes.len(),
+            what: "rules could not be restored".to_string(),
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+fn change_rule(change: &RMRuleChange) -> &CFEmailRule {
+    match change {
+        RMRuleChange::Added(rule) | RMRuleChange::Removed(rule) => rule,
+        RMRuleChange::Changed { to, .. } => to,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn rule(id: &str, email_alias: &str, name: &str, dest: &str) -> CFEmailRule {
+        CFEmailRule {
+            id: id.to_string(),
+            tag: String::new(),
+            name: name.to_string(),
+            enabled: true,
+            priority: 0,
+            matchers: vec![json!({ "type": "literal", "field": "to", "value": email_alias })],
+            actions: vec![json!({ "type": "forward", "value": [dest] })],
+        }
+    }
+
+    fn catch_all() -> CFEmailRule {
+        CFEmailRule {
+            id: "catch".to_string(),
+            tag: String::new(),
+            name: String::new(),
+            enabled: true,
+            priority: 0,
+            matchers: vec![json!({ "type": "all" })],
+            actions: vec![json!({ "type": "drop" })],
+        }
+    }
+
+    fn snapshot(rules: Vec<CFEmailRule>) -> RMSnapshot {
+        RMSnapshot {
+            zone: "example.com".to_string(),
+            zone_id: "zone".to_string(),
+            taken_at: Utc::now(),
+            reason: None,
+            rules,
+        }
+    }
+
+    fn summary(changes: &[RMRuleChange]) -> Vec<String> {
+        changes.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn diff_matches_rules_by_address() {
+        let before = vec![
+            rule("r1", "shop@example.com", "Shop", "you@example.net"),
+            rule("r2", "news@example.com", "News", "you@example.net"),
+            catch_all(),
+        ];
+
+        let mut disabled = catch_all();
+        disabled.id = "catch2".to_string();
+        disabled.enabled = false;
+
+        let after = vec![
+            // recreated under a new id, same address and content
+            rule("r9", "shop@example.com", "Shop", "you@example.net"),
+            rule("r3", "travel@example.com", "", "you@example.net"),
+            disabled,
+        ];
+
+        assert_eq!(
+            summary(&diff(&before, &after)),
+            [
+                "+ travel@example.com",
+                "~ catch2\n    enabled: true -> false",
+                "- news@example.com",
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_lists_changed_fields() {
+        let from = rule("r1", "shop@example.com", "Shop", "you@example.net");
+        let mut to = rule("r1", "shop@example.com", "Store", "other@example.net");
+        to.priority = 5;
+
+        let changes = diff(std::slice::from_ref(&from), std::slice::from_ref(&to));
+        assert_eq!(
+            summary(&changes),
+            [concat!(
+                "~ shop@example.com\n",
+                "    name: \"Shop\" -> \"Store\"\n",
+                "    priority: 0 -> 5\n",
+                "    actions: [{\"type\":\"forward\",\"value\":[\"you@example.net\"]}]",
+                " -> [{\"type\":\"forward\",\"value\":[\"other@example.net\"]}]"
+            )]
+        );
+
+        assert!(diff(std::slice::from_ref(&from), std::slice::from_ref(&from)).is_empty());
+    }
+
+    #[test]
+    fn restore_recreates_and_resets_but_keeps_new_rules() {
+        let taken = snapshot(vec![
+            rule("r1", "shop@example.com", "Shop", "you@example.net"),
+            rule("r2", "news@example.com", "News", "you@example.net"),
+        ]);
+
+        let current = vec![
+            rule("r1", "shop@example.com", "Renamed", "you@example.net"),
+            rule("r3", "travel@example.com", "Travel", "you@example.net"),
+        ];
+
+        let plan = restore_plan(&current, &taken);
+        assert_eq!(
+            summary(&plan),
+            [
+                "~ shop@example.com\n    name: \"Renamed\" -> \"Shop\"",
+                "+ news@example.com",
+            ]
+        );
+
+        // the rule is replaced in place, under its current id
+        assert!(matches!(
+            plan.first(),
+            Some(RMRuleChange::Changed { from, .. }) if from.id == "r1"
+        ));
+
+        assert!(restore_plan(&taken.rules, &taken).is_empty());
+    }
+}
@@ -0,0 +1,643 @@
+use std::{collections::HashMap, fmt, path::Path};
+
+use anyhow::{Context, Result, bail};
+use log::{error, info};
+use serde::Deserialize;
+
+use crate::{
+    address::parse_address,
+    config::RMConfig,
+    http::CFClient,
+    metadata::RMMetadataUpdate,
+    provider::{AliasProvider, RMAlias, RMPartialFailure},
+    tags::{encode_tags, normalize_tag},
+};
+
+const fn default_enabled() -> bool {
+    true
+}
+
+/// An alias as the alias file wants it. Fields left out are not managed
+/// and stay as they are, except `destination` and `enabled`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RMDesiredAlias {
+    /// Local part or full address
+    pub alias: String,
+    pub description: Option<String>,
+    /// Defaults to the destination of the file, then of the config
+    pub destination: Option<String>,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    pub tags: Option<Vec<String>>,
+    /// Empty to clear
+    pub website: Option<String>,
+    /// Empty to clear
+    pub notes: Option<String>,
+}
+
+/// Aliases kept under version control, as YAML, TOML or JSON
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RMAliasFile {
+    /// Destination of aliases that don't name one
+    pub destination: Option<String>,
+    #[serde(default)]
+    pub aliases: Vec<RMDesiredAlias>,
+}
+
+impl RMAliasFile {
+    /// Parse `path`, the format follows its extension
+    pub fn load<P>(path: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("Unable to read {}", path.display()))?;
+
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+
+        match ext.as_str() {
+            "yaml" | "yml" => serde_norway::from_str(&data)
+                .with_context(|| format!("Unable to parse {}", path.display())),
+            "toml" => {
+                toml::from_str(&data).with_context(|| format!("Unable to parse {}", path.display()))
+            }
+            "json" => serde_json::from_str(&data)
+                .with_context(|| format!("Unable to parse {}", path.display())),
+            _ => bail!(
+                "{} should end in .yaml, .yml, .toml or .json",
+                path.display()
+            ),
+        }
+    }
+}
+
+/// A validated alias of the file
+#[derive(Debug, Clone)]
+pub struct RMTarget {
+    pub email_alias: String,
+    pub description: Option<String>,
+    pub email_dest: String,
+    pub enabled: bool,
+    pub tags: Option<Vec<String>>,
+    pub website: Option<String>,
+    pub notes: Option<String>,
+}
+
+/// What the alias file manages about an alias
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RMField {
+    Description,
+    Destination,
+    Enabled,
+    Tags,
+    Website,
+    Notes,
+}
+
+impl fmt::Display for RMField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Description => "description",
+            Self::Destination => "destination",
+            Self::Enabled => "enabled",
+            Self::Tags => "tags",
+            Self::Website => "website",
+            Self::Notes => "notes",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// One field of an existing alias that differs from the file
+#[derive(Debug, Clone)]
+pub struct RMFieldChange {
+    pub field: RMField,
+    pub from: String,
+    pub to: String,
+}
+
+impl fmt::Display for RMFieldChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: \"{}\" -> \"{}\"", self.field, self.from, self.to)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum RMChange {
+    Create(RMTarget),
+    Update {
+        current: RMAlias,
+        target: RMTarget,
+        fields: Vec<RMFieldChange>,
+    },
+    Delete(RMAlias),
+}
+
+impl fmt::Display for RMChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Create(target) => write!(f, "+ {}", target.email_alias),
+            Self::Update {
+                current, fields, ..
+            } => {
+                write!(f, "~ {}", current.email_alias)?;
+
+                for field in fields {
+                    write!(f, "\n    {field}")?;
+                }
+
+                Ok(())
+            }
+            Self::Delete(current) => write!(f, "- {}", current.email_alias),
+        }
+    }
+}
+
+/// What `apply` would do to bring the backend in line with the file
+#[derive(Debug, Default)]
+pub struct RMPlan {
+    pub changes: Vec<RMChange>,
+    /// Aliases missing from the file that are kept because pruning is off
+    pub unmanaged: Vec<RMAlias>,
+}
+
+impl RMPlan {
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Number of creations, updates and deletions
+    #[must_use]
+    pub fn counts(&self) -> (usize, usize, usize) {
+        self.changes
+            .iter()
+            .fold((0, 0, 0), |(c, u, d), change| match change {
+                RMChange::Create(_) => (c.saturating_add(1), u, d),
+                RMChange::Update { .. } => (c, u.saturating_add(1), d),
+                RMChange::Delete(_) => (c, u, d.saturating_add(1)),
+            })
+    }
+}
+
+fn validate(desired: &RMDesiredAlias, domain: &str, default_dest: &str) -> Result<RMTarget> {
+    let email_alias = parse_address(&desired.alias, domain)?;
+
+    if !email_alias.ends_with(&format!("@{domain}")) {
+        bail!("{email_alias} is not under {domain}");
+    }
+
+    let tags = desired
+        .tags
+        .as_ref()
+        .map(|tags| {
+            let mut normalized: Vec<String> = Vec::new();
+
+            for tag in tags {
+                let tag = normalize_tag(tag)?;
+                if !normalized.contains(&tag) {
+                    normalized.push(tag);
+                }
+            }
+
+            Ok::<_, anyhow::Error>(normalized)
+        })
+        .transpose()?;
+
+    Ok(RMTarget {
+        email_alias,
+        description: desired.description.clone(),
+        email_dest: desired
+            .destination
+            .clone()
+            .unwrap_or_else(|| default_dest.to_string()),
+        enabled: desired.enabled,
+        tags,
+        website: desired.website.clone(),
+        notes: desired.notes.clone(),
+    })
+}
+
+fn change<V>(fields: &mut Vec<RMFieldChange>, field: RMField, from: &V, to: &V)
+where
+    V: ToString + ?Sized,
+{
+    fields.push(RMFieldChange {
+        field,
+        from: from.to_string(),
+        to: to.to_string(),
+    });
+}
+
+/// Fields of `current` that differ from `target`
+fn diff(current: &RMAlias, target: &RMTarget) -> Vec<RMFieldChange> {
+    let mut fields = Vec::new();
+
+    if let Some(desc) = &target.description
+        && *desc != current.name
+    {
+        change(&mut fields, RMField::Description, &current.name, desc);
+    }
+
+    if !current
+        .email_destination
+        .eq_ignore_ascii_case(&target.email_dest)
+    {
+        change(
+            &mut fields,
+            RMField::Destination,
+            &current.email_destination,
+            &target.email_dest,
+        );
+    }
+
+    if current.enabled != target.enabled {
+        change(
+            &mut fields,
+            RMField::Enabled,
+            &current.enabled,
+            &target.enabled,
+        );
+    }
+
+    if let Some(tags) = &target.tags {
+        let mut have = current.tags.clone();
+        let mut want = tags.clone();
+        have.sort();
+        want.sort();
+
+        if have != want {
+            change(
+                &mut fields,
+                RMField::Tags,
+                &current.tags.join(" "),
+                &tags.join(" "),
+            );
+        }
+    }
+
+    let metadata = [
+        (RMField::Website, &current.metadata.website, &target.website),
+        (RMField::Notes, &current.metadata.notes, &target.notes),
+    ];
+
+    for (field, have, want) in metadata {
+        if let Some(want) = want
+            && have.as_deref().unwrap_or_default() != want
+        {
+            change(
+                &mut fields,
+                field,
+                have.as_deref().unwrap_or_default(),
+                want,
+            );
+        }
+    }
+
+    fields
+}
+
+/// Validate every alias of `file`, refusing addresses listed twice
+fn validate_file(file: &RMAliasFile, domain: &str, default_dest: &str) -> Result<Vec<RMTarget>> {
+    let mut targets: Vec<RMTarget> = Vec::new();
+    let mut seen: HashMap<String, &str> = HashMap::new();
+
+    for desired in &file.aliases {
+        let target = validate(desired, domain, default_dest)
+            .with_context(|| format!("Invalid alias {}", desired.alias))?;
+
+        let key = target.email_alias.to_lowercase();
+
+        if let Some(first) = seen.insert(key, &desired.alias) {
+            bail!("{} is listed twice ({first})", target.email_alias);
+        }
+
+        targets.push(target);
+    }
+
+    Ok(targets)
+}
+
+/// Changes bringing the `current` aliases in line with `targets`
+fn compare(targets: Vec<RMTarget>, current: Vec<RMAlias>, prune: bool) -> RMPlan {
+    let mut current: HashMap<String, RMAlias> = current
+        .into_iter()
+        .map(|a| (a.email_alias.to_lowercase(), a))
+        .collect();
+
+    let mut result = RMPlan::default();
+
+    for target in targets {
+        match current.remove(&target.email_alias.to_lowercase()) {
+            None => result.changes.push(RMChange::Create(target)),
+            Some(alias) => {
+                let fields = diff(&alias, &target);
+
+                if !fields.is_empty() {
+                    result.changes.push(RMChange::Update {
+                        current: alias,
+                        target,
+                        fields,
+                    });
+                }
+            }
+        }
+    }
+
+    let mut rest: Vec<RMAlias> = current.into_values().collect();
+    rest.sort_by(|a, b| a.email_alias.cmp(&b.email_alias));
+
+    if prune {
+        result
+            .changes
+            .extend(rest.into_iter().map(RMChange::Delete));
+    } else {
+        result.unmanaged = rest;
+    }
+
+    result
+}
+
+/// Compare `file` with the aliases of the backend. Aliases missing from the
+/// file are deleted with `prune`, and listed as unmanaged otherwise.
+pub async fn plan(
+    provider: &dyn AliasProvider,
+    config: &RMConfig,
+    client: &CFClient,
+    file: &RMAliasFile,
+    prune: bool,
+) -> Result<RMPlan> {
+    let default_dest = file
+        .destination
+        .as_deref()
+        .unwrap_or(&config.destination_email);
+
+    let targets = validate_file(file, provider.domain(), default_dest)?;
+
+    let mut dests: Vec<&str> = targets.iter().map(|t| t.email_dest.as_str()).collect();
+    dests.sort_unstable();
+    dests.dedup();
+
+    for dest in dests {
+        config.check_destination(client, dest).await?;
+    }
+
+    Ok(compare(targets, provider.list().await?, prune))
+}
+
+fn metadata_update(target: &RMTarget) -> RMMetadataUpdate {
+    RMMetadataUpdate {
+        website: target.website.clone(),
+        notes: target.notes.clone(),
+        ..RMMetadataUpdate::default()
+    }
+}
+
+async fn create(provider: &dyn AliasProvider, target: &RMTarget) -> Result<()> {
+    let name = encode_tags(
+        target.description.as_deref().unwrap_or_default(),
+        target.tags.as_deref().unwrap_or_default(),
+    )?;
+
+    // the plan was made from a single listing, the address was free then
+    let alias = provider
+        .create_unchecked(&name, &target.email_alias, &target.email_dest)
+        .await?;
+
+    if !target.enabled {
+        provider.set_enabled(&alias.id, false).await?;
+    }
+
+    let update = metadata_update(target);
+
+    if !update.is_empty() {
+        provider.annotate(&alias.id, &update).await?;
+    }
+
+    Ok(())
+}
+
+async fn update(
+    provider: &dyn AliasProvider,
+    current: &RMAlias,
+    target: &RMTarget,
+    fields: &[RMFieldChange],
+) -> Result<()> {
+    let id = &current.id;
+
+    for field in fields {
+        match field.field {
+            RMField::Description => provider.rename(id, &field.to).await?,
+            RMField::Destination => {
+                // alias files name the main destination, other recipients stay
+                let dests: Vec<String> = std::iter::once(target.email_dest.clone())
+                    .chain(current.extra_destinations.iter().cloned())
+                    .collect();
+                provider.set_destinations(id, &dests).await?;
+            }
+            RMField::Enabled => provider.set_enabled(id, target.enabled).await?,
+            RMField::Tags => {
+                let tags = target.tags.as_deref().unwrap_or_default();
+                provider.set_tags(id, tags).await?;
+            }
+            // annotated together below
+            RMField::Website | RMField::Notes => {}
+        }
+    }
+
+    if fields
+        .iter()
+        .any(|f| matches!(f.field, RMField::Website | RMField::Notes))
+    {
+        provider.annotate(id, &metadata_update(target)).await?;
+    }
+
+    Ok(())
+}
+
+/// Carry out the changes of `plan` one at a time, calling `progress` after
+/// each. Changes after a failure are still attempted, the error lists how
+/// many failed.
+pub async fn apply<P>(provider: &dyn AliasProvider, plan: &RMPlan, mut progress: P) -> Result<()>
+where
+    P: FnMut(&RMChange, &Result<()>),
+{
+    let mut failed: usize = 0;
+
+    for change in &plan.changes {
+        let res = match change {
+            RMChange::Create(target) => create(provider, target).await,
+            RMChange::Update {
+                current,
+                target,
+                fields,
+            } => update(provider, current, target, fields).await,
+            RMChange::Delete(current) => provider.delete(&current.id).await,
+        };
+
+        if let Err(e) = &res {
+            error!("Unable to apply {change} ({e})");
+            failed = failed.saturating_add(1);
+        }
+
+        progress(change, &res);
+    }
+
+    if failed > 0 {
+        return Err(RMPartialFailure {
+            failed,
+            total: plan.changes.len(),
+            what: "changes failed".to_string(),
+        }
+        .into());
+    }
+
+    info!("{} changes applied", plan.changes.len());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::RMMetadata;
+
+    const DEST: &str = "you@example.net";
+
+    fn desired(alias: &str) -> RMDesiredAlias {
+        RMDesiredAlias {
+            alias: alias.to_string(),
+            description: None,
+            destination: None,
+            enabled: true,
+            tags: None,
+            website: None,
+            notes: None,
+        }
+    }
+
+    fn current(email_alias: &str, name: &str) -> RMAlias {
+        RMAlias {
+            id: email_alias.to_string(),
+            email_destination: DEST.to_string(),
+            email_alias: email_alias.to_string(),
+            name: name.to_string(),
+            enabled: true,
+            ..RMAlias::default()
+        }
+    }
+
+    fn targets(aliases: Vec<RMDesiredAlias>) -> Result<Vec<RMTarget>> {
+        let file = RMAliasFile {
+            destination: None,
+            aliases,
+        };
+
+        validate_file(&file, "example.com", DEST)
+    }
+
+    fn summary(plan: &RMPlan) -> Vec<String> {
+        plan.changes.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn plan_creates_updates_and_prunes() -> Result<()> {
+        let file = vec![
+            RMDesiredAlias {
+                description: Some("Shop".to_string()),
+                ..desired("shop")
+            },
+            RMDesiredAlias {
+                description: Some("News".to_string()),
+                enabled: false,
+                tags: Some(vec!["Work".to_string()]),
+                website: Some("news.example".to_string()),
+                ..desired("News@Example.com")
+            },
+            desired("travel"),
+        ];
+
+        let existing = vec![
+            current("shop@example.com", "Shop"),
+            current("news@example.com", "Old news"),
+            current("old@example.com", ""),
+        ];
+
+        let plan = compare(targets(file.clone())?, existing.clone(), true);
+        assert_eq!(plan.counts(), (1, 1, 1));
+        assert!(plan.unmanaged.is_empty());
+        assert_eq!(
+            summary(&plan),
+            [
+                "~ news@example.com\n    description: \"Old news\" -> \"News\"\n    enabled: \"true\" -> \"false\"\n    tags: \"\" -> \"work\"\n    website: \"\" -> \"news.example\"",
+                "+ travel@example.com",
+                "- old@example.com",
+            ]
+        );
+
+        let plan = compare(targets(file)?, existing, false);
+        assert_eq!(plan.counts(), (1, 1, 0));
+        assert_eq!(
+            plan.unmanaged
+                .iter()
+                .map(|a| a.email_alias.as_str())
+                .collect::<Vec<_>>(),
+            ["old@example.com"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn fields_left_out_are_not_managed() -> Result<()> {
+        let mut alias = current("shop@example.com", "Shop");
+        alias.tags = vec!["work".to_string()];
+        alias.metadata = RMMetadata {
+            notes: Some("kept".to_string()),
+            ..RMMetadata::default()
+        };
+
+        let plan = compare(targets(vec![desired("shop")])?, vec![alias.clone()], true);
+        assert!(plan.is_empty());
+
+        // tags compare in any order, an empty note clears it
+        let file = vec![RMDesiredAlias {
+            tags: Some(vec!["work".to_string()]),
+            notes: Some(String::new()),
+            destination: Some("OTHER@example.net".to_string()),
+            ..desired("shop")
+        }];
+        assert_eq!(
+            summary(&compare(targets(file)?, vec![alias], true)),
+            [
+                "~ shop@example.com\n    destination: \"you@example.net\" -> \"OTHER@example.net\"\n    notes: \"kept\" -> \"\""
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn file_is_validated() {
+        let twice = targets(vec![desired("shop"), desired("SHOP@example.com")]);
+        assert!(twice.is_err_and(|e| e.to_string().contains("listed twice")));
+
+        let elsewhere = targets(vec![desired("shop@other.example")]);
+        assert!(elsewhere.is_err());
+
+        let bad_tag = targets(vec![RMDesiredAlias {
+            tags: Some(vec!["1st".to_string()]),
+            ..desired("shop")
+        }]);
+        assert!(bad_tag.is_err());
+    }
+}
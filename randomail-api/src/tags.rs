@@ -0,0 +1,283 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use async_trait::async_trait;
+
+use crate::{
+    error::RMInvalidInput,
+    metadata::RMMetadataUpdate,
+    provider::{AliasProvider, RMAlias, RMAlreadyExists, RMCapabilities},
+};
+
+const TAG_PREFIX: char = '#';
+
+/// Lowercase `tag` and make sure it starts with a letter and only holds
+/// letters, digits, `-` and `_`. Requiring a letter first keeps names such
+/// as "Order #12345" from being read as tags.
+pub fn normalize_tag(tag: &str) -> Result<String> {
+    let tag = tag.trim().trim_start_matches(TAG_PREFIX).to_lowercase();
+
+    let starts_with_letter = tag.starts_with(|c: char| c.is_ascii_alphabetic());
+
+    let valid = tag
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if !starts_with_letter || !valid {
+        return Err(RMInvalidInput(format!(
+            "invalid tag \"{tag}\", start with a letter and use letters, digits, - and _"
+        ))
+        .into());
+    }
+
+    Ok(tag)
+}
+
+/// Split a stored rule name into its description and trailing `#tags`
+#[must_use]
+pub fn decode_tags(name: &str) -> (String, Vec<String>) {
+    let mut desc = name.trim_end();
+    let mut tags = Vec::new();
+
+    loop {
+        let (rest, token) = match desc.rsplit_once(char::is_whitespace) {
+            Some((rest, token)) => (rest, token),
+            None => ("", desc),
+        };
+
+        let Some(tag) = token.strip_prefix(TAG_PREFIX) else {
+            break;
+        };
+
+        // only tokens already in normalized form are tags, "#1" or "#Fan" aren't
+        match normalize_tag(tag) {
+            Ok(normalized) if normalized == tag => tags.push(normalized),
+            _ => break,
+        }
+
+        desc = rest.trim_end();
+
+        if desc.is_empty() {
+            break;
+        }
+    }
+
+    tags.reverse();
+
+    let mut seen = HashSet::new();
+    tags.retain(|t| seen.insert(t.clone()));
+
+    (desc.to_string(), tags)
+}
+
+/// Build the rule name stored by the backend from a description and tags.
+/// A description ending in something that reads as a tag, such as "ticket
+/// #backend", is refused since it would come back as a tag.
+pub fn encode_tags(desc: &str, tags: &[String]) -> Result<String> {
+    let mut name = desc.trim().to_string();
+
+    if let Some(tag) = decode_tags(&name).1.first() {
+        return Err(RMInvalidInput(format!(
+            "\"{name}\" would be read as tagged #{tag}, add it as a tag or reword the name"
+        ))
+        .into());
+    }
+
+    for tag in tags {
+        if !name.is_empty() {
+            name.push(' ');
+        }
+        name.push(TAG_PREFIX);
+        name.push_str(tag);
+    }
+
+    Ok(name)
+}
+
+fn decode_alias(mut alias: RMAlias) -> RMAlias {
+    let (name, tags) = decode_tags(&alias.name);
+    alias.name = name;
+    alias.tags = tags;
+    alias
+}
+
+/// Wraps a backend and keeps tags in the rule name, so they live with the
+/// alias itself rather than on the local machine
+pub struct TagProvider {
+    inner: Box<dyn AliasProvider>,
+}
+
+impl TagProvider {
+    #[must_use]
+    pub fn new(inner: Box<dyn AliasProvider>) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait]
+impl AliasProvider for TagProvider {
+    fn domain(&self) -> &str {
+        self.inner.domain()
+    }
+
+    fn capabilities(&self) -> RMCapabilities {
+        let caps = self.inner.capabilities();
+
+        RMCapabilities {
+            tags: caps.rename,
+            ..caps
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<RMAlias>> {
+        Ok(self
+            .inner
+            .list()
+            .await?
+            .into_iter()
+            .map(decode_alias)
+            .collect())
+    }
+
+    async fn get(&self, id: &str) -> Result<RMAlias> {
+        Ok(decode_alias(self.inner.get(id).await?))
+    }
+
+    async fn create(&self, name: &str, email_alias: &str, email_dest: &str) -> Result<RMAlias> {
+        let alias = self
+            .inner
+            .create(name, email_alias, email_dest)
+            .await
+            .map_err(|e| RMAlreadyExists::map(e, decode_alias))?;
+        Ok(decode_alias(alias))
+    }
+
+    async fn create_unchecked(
+        &self,
+        name: &str,
+        email_alias: &str,
+        email_dest: &str,
+    ) -> Result<RMAlias> {
+        let alias = self
+            .inner
+            .create_unchecked(name, email_alias, email_dest)
+            .await?;
+        Ok(decode_alias(alias))
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        self.inner.delete(id).await
+    }
+
+    async fn set_enabled(&self, id: &str, enabled: bool) -> Result<()> {
+        self.inner.set_enabled(id, enabled).await
+    }
+
+    async fn rename(&self, id: &str, name: &str) -> Result<()> {
+        let alias = self.get(id).await?;
+        self.inner
+            .rename(id, &encode_tags(name, &alias.tags)?)
+            .await
+    }
+
+    async fn readdress(&self, id: &str, email_alias: &str) -> Result<RMAlias> {
+        let alias = self
+            .inner
+            .readdress(id, email_alias)
+            .await
+            .map_err(|e| RMAlreadyExists::map(e, decode_alias))?;
+        Ok(decode_alias(alias))
+    }
+
+    async fn set_destinations(&self, id: &str, email_dests: &[String]) -> Result<()> {
+        self.inner.set_destinations(id, email_dests).await
+    }
+
+    async fn annotate(&self, id: &str, update: &RMMetadataUpdate) -> Result<()> {
+        self.inner.annotate(id, update).await
+    }
+
+    async fn set_tags(&self, id: &str, tags: &[String]) -> Result<()> {
+        let alias = self.get(id).await?;
+
+        let mut normalized = Vec::new();
+
+        for tag in tags {
+            let tag = normalize_tag(tag)?;
+            if !normalized.contains(&tag) {
+                normalized.push(tag);
+            }
+        }
+
+        self.inner
+            .rename(id, &encode_tags(&alias.name, &normalized)?)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_reads_trailing_tags() {
+        assert_eq!(
+            decode_tags("Shop #shopping #work"),
+            (
+                "Shop".to_string(),
+                vec!["shopping".to_string(), "work".to_string()]
+            )
+        );
+        assert_eq!(
+            decode_tags("#news"),
+            (String::new(), vec!["news".to_string()])
+        );
+    }
+
+    #[test]
+    fn decode_keeps_numbers_and_mixed_case() {
+        for name in ["Order #12345", "buy #1", "#1 Fan", "Team #Blue", "C# #"] {
+            assert_eq!(decode_tags(name), (name.to_string(), Vec::new()));
+        }
+    }
+
+    #[test]
+    fn decode_drops_repeated_tags() {
+        assert_eq!(
+            decode_tags("Shop #work #news #work"),
+            (
+                "Shop".to_string(),
+                vec!["work".to_string(), "news".to_string()]
+            )
+        );
+    }
+
+    #[test]
+    fn encode_round_trips() -> Result<()> {
+        let tags = vec!["a-1".to_string(), "b_2".to_string()];
+
+        assert_eq!(
+            decode_tags(&encode_tags("Order #12345", &tags)?),
+            ("Order #12345".to_string(), tags)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn encode_refuses_names_ending_in_tags() {
+        for desc in ["ticket #backend", "#news", "Shop #work "] {
+            assert!(encode_tags(desc, &[]).is_err_and(|e| e.is::<RMInvalidInput>()));
+        }
+
+        assert!(encode_tags("ticket #backend fix", &[]).is_ok());
+    }
+
+    #[test]
+    fn normalize_requires_a_letter_first() {
+        assert!(normalize_tag("#Work").is_ok_and(|t| t == "work"));
+        assert!(normalize_tag("2fa").is_err());
+        assert!(normalize_tag("-x").is_err());
+        assert!(normalize_tag("").is_err());
+    }
+}
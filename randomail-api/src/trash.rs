@@ -0,0 +1,416 @@
+use std::{
+    fmt,
+    path::Path,
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::info;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::Serialize;
+use tabled::Tabled;
+
+use crate::{
+    audit::current_actor,
+    cf_email::{CFEmailRule, create_email_rule, get_email_rule},
+    http::CFClient,
+    metadata::RMMetadataUpdate,
+    provider::{AliasProvider, RMAlias, RMAlreadyExists, RMCapabilities},
+    tags::encode_tags,
+};
+
+/// Days deleted aliases stay in the trash unless configured otherwise
+pub const DEFAULT_TRASH_DAYS: u32 = 30;
+
+/// `untrash` found nothing to restore
+#[derive(Debug)]
+pub struct RMNotInTrash {
+    /// Address that was asked for, none for the latest deleted alias
+    pub email_alias: Option<String>,
+}
+
+impl fmt::Display for RMNotInTrash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.email_alias {
+            Some(email_alias) => write!(f, "{email_alias} is not in the trash"),
+            None => write!(f, "the trash is empty"),
+        }
+    }
+}
+
+impl std::error::Error for RMNotInTrash {}
+
+/// A deleted alias, with everything needed to create it again
+#[derive(Debug, Clone, Serialize, Tabled)]
+pub struct RMTrashEntry {
+    #[tabled(skip)]
+    pub id: i64,
+    #[tabled(rename = "alias", display = "display_alias")]
+    pub alias: RMAlias,
+    #[tabled(rename = "deleted", display = "display_time")]
+    pub deleted_at: DateTime<Utc>,
+    #[tabled(rename = "by")]
+    pub deleted_by: String,
+    /// The Cloudflare rule as it was, restored as is
+    #[tabled(skip)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<CFEmailRule>,
+}
+
+fn display_alias(alias: &RMAlias) -> String {
+    alias.email_alias.clone()
+}
+
+fn display_time(date: &DateTime<Utc>) -> String {
+    date.format("%Y-%m-%d %H:%M").to_string()
+}
+
+/// Columns of a trash row, the alias and rule still as JSON
+type RMTrashRow = (i64, String, DateTime<Utc>, String, Option<String>);
+
+fn read_entry(row: &rusqlite::Row<'_>) -> rusqlite::Result<RMTrashRow> {
+    Ok((
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+    ))
+}
+
+/// Deleted aliases, kept in the metadata database for `retention`
+pub struct RMTrashStore {
+    conn: Mutex<Connection>,
+    retention: chrono::Duration,
+}
+
+impl RMTrashStore {
+    pub fn open<P>(path: P, days: u32) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        info!("opening trash in {}", path.as_ref().display());
+
+        let conn = Connection::open(&path)
+            .with_context(|| format!("Unable to open {}", path.as_ref().display()))?;
+
+        // the metadata store has its own connection to the same file
+        conn.busy_timeout(Duration::from_secs(5))
+            .context("Unable to configure trash database")?;
+
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS trash (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                email_alias TEXT NOT NULL,
+                deleted_at TEXT NOT NULL,
+                deleted_by TEXT NOT NULL,
+                alias TEXT NOT NULL,
+                rule TEXT
+            );",
+        )
+        .context("Unable to create trash table")?;
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+            retention: chrono::Duration::days(days.into()),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Forget aliases deleted longer ago than the retention period
+    pub fn purge(&self) -> Result<usize> {
+        let cutoff = Utc::now()
+            .checked_sub_signed(self.retention)
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+
+        let purged = self
+            .lock()
+            .execute("DELETE FROM trash WHERE deleted_at < ?1", params![cutoff])
+            .context("Unable to purge trash")?;
+
+        if purged > 0 {
+            info!("purged {purged} aliases from the trash");
+        }
+
+        Ok(purged)
+    }
+
+    /// Keep `alias` in the trash and return the id of the entry
+    pub fn push(&self, alias: &RMAlias, rule: Option<&CFEmailRule>, by: &str) -> Result<i64> {
+        self.purge()?;
+
+        let alias_json = serde_json::to_string(alias).context("Unable to serialize alias")?;
+        let rule_json = rule
+            .map(serde_json::to_string)
+            .transpose()
+            .context("Unable to serialize rule")?;
+
+        let conn = self.lock();
+
+        conn.execute(
+            "INSERT INTO trash (email_alias, deleted_at, deleted_by, alias, rule)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                alias.email_alias.to_lowercase(),
+                Utc::now(),
+                by,
+                alias_json,
+                rule_json
+            ],
+        )
+        .with_context(|| format!("Unable to move {} to the trash", alias.email_alias))?;
+
+        Ok(conn.last_insert_rowid())
+    }
+
+    fn decode((id, alias, deleted_at, deleted_by, rule): RMTrashRow) -> Result<RMTrashEntry> {
+        Ok(RMTrashEntry {
+            id,
+            alias: serde_json::from_str(&alias)
+                .with_context(|| format!("Unable to read trash entry {id}"))?,
+            deleted_at,
+            deleted_by,
+            rule: rule
+                .map(|r| serde_json::from_str(&r))
+                .transpose()
+                .with_context(|| format!("Unable to read trash entry {id}"))?,
+        })
+    }
+
+    /// Every alias in the trash, most recently deleted first
+    pub fn list(&self) -> Result<Vec<RMTrashEntry>> {
+        self.purge()?;
+
+        let conn = self.lock();
+
+        let mut stmt = conn
+            .prepare(
+                "SELECT id, alias, deleted_at, deleted_by, rule FROM trash
+                 ORDER BY deleted_at DESC, id DESC",
+            )
+            .context("Unable to read trash")?;
+
+        let rows = stmt
+            .query_map([], read_entry)
+            .context("Unable to read trash")?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Unable to read trash")?;
+
+        rows.into_iter().map(Self::decode).collect()
+    }
+
+    /// The most recently deleted alias, only looking at `email_alias` when given
+    pub fn latest(&self, email_alias: Option<&str>) -> Result<Option<RMTrashEntry>> {
+        self.purge()?;
+
+        let row = self
+            .lock()
+            .query_row(
+                "SELECT id, alias, deleted_at, deleted_by, rule FROM trash
+                 WHERE ?1 IS NULL OR email_alias = ?1
+                 ORDER BY deleted_at DESC, id DESC LIMIT 1",
+                params![email_alias.map(str::to_lowercase)],
+                read_entry,
+            )
+            .optional()
+            .context("Unable to read trash")?;
+
+        row.map(Self::decode).transpose()
+    }
+
+    pub fn remove(&self, id: i64) -> Result<()> {
+        self.lock()
+            .execute("DELETE FROM trash WHERE id = ?1", params![id])
+            .with_context(|| format!("Unable to remove trash entry {id}"))?;
+
+        Ok(())
+    }
+}
+
+/// Wraps a provider and moves aliases to the trash before deleting them
+pub struct TrashProvider {
+    inner: Box<dyn AliasProvider>,
+    trash: RMTrashStore,
+    /// Client and zone id to keep the full rule, when the backend is Cloudflare
+    rules: Option<(CFClient, String)>,
+}
+
+impl TrashProvider {
+    pub fn new(
+        inner: Box<dyn AliasProvider>,
+        trash: RMTrashStore,
+        rules: Option<(CFClient, String)>,
+    ) -> Self {
+        Self {
+            inner,
+            trash,
+            rules,
+        }
+    }
+
+    /// Create the alias of `entry` again and return its new id. Rules are
+    /// restored as they were, bypassing the metadata of inner providers:
+    /// `untrash` puts it back afterwards.
+    async fn recreate(&self, entry: &RMTrashEntry) -> Result<String> {
+        let alias = &entry.alias;
+
+        if let (Some((client, zone_id)), Some(rule)) = (&self.rules, &entry.rule) {
+            return Ok(create_email_rule(client, zone_id, rule).await?.id);
+        }
+
+        // `untrash` just checked the address against a listing
+        let new = self
+            .inner
+            .create_unchecked(
+                &encode_tags(&alias.name, &alias.tags)?,
+                &alias.email_alias,
+                &alias.email_destination,
+            )
+            .await?;
+
+        if !alias.enabled {
+            self.inner.set_enabled(&new.id, false).await?;
+        }
+
+        Ok(new.id)
+    }
+}
+
+#[async_trait]
+impl AliasProvider for TrashProvider {
+    fn domain(&self) -> &str {
+        self.inner.domain()
+    }
+
+    fn capabilities(&self) -> RMCapabilities {
+        RMCapabilities {
+            trash: true,
+            ..self.inner.capabilities()
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<RMAlias>> {
+        self.inner.list().await
+    }
+
+    async fn get(&self, id: &str) -> Result<RMAlias> {
+        self.inner.get(id).await
+    }
+
+    async fn create(&self, name: &str, email_alias: &str, email_dest: &str) -> Result<RMAlias> {
+        self.inner.create(name, email_alias, email_dest).await
+    }
+
+    async fn create_unchecked(
+        &self,
+        name: &str,
+        email_alias: &str,
+        email_dest: &str,
+    ) -> Result<RMAlias> {
+        self.inner
+            .create_unchecked(name, email_alias, email_dest)
+            .await
+    }
+
+    async fn delete(&self, id: &str) -> Result<()> {
+        let alias = self.inner.get(id).await?;
+
+        let rule = match &self.rules {
+            Some((client, zone_id)) => Some(get_email_rule(client, zone_id, id).await?),
+            None => None,
+        };
+
+        let entry = self.trash.push(&alias, rule.as_ref(), &current_actor())?;
+
+        if let Err(e) = self.inner.delete(id).await {
+            self.trash.remove(entry)?;
+            return Err(e);
+        }
+
+        info!("{} moved to the trash", alias.email_alias);
+
+        Ok(())
+    }
+
+    async fn set_enabled(&self, id: &str, enabled: bool) -> Result<()> {
+        self.inner.set_enabled(id, enabled).await
+    }
+
+    async fn rename(&self, id: &str, name: &str) -> Result<()> {
+        self.inner.rename(id, name).await
+    }
+
+    async fn readdress(&self, id: &str, email_alias: &str) -> Result<RMAlias> {
+        self.inner.readdress(id, email_alias).await
+    }
+
+    async fn set_destinations(&self, id: &str, email_dests: &[String]) -> Result<()> {
+        self.inner.set_destinations(id, email_dests).await
+    }
+
+    async fn annotate(&self, id: &str, update: &RMMetadataUpdate) -> Result<()> {
+        self.inner.annotate(id, update).await
+    }
+
+    async fn set_tags(&self, id: &str, tags: &[String]) -> Result<()> {
+        self.inner.set_tags(id, tags).await
+    }
+
+    async fn trashed(&self) -> Result<Vec<RMTrashEntry>> {
+        self.trash.list()
+    }
+
+    async fn untrash(&self, email_alias: Option<&str>) -> Result<RMAlias> {
+        let Some(entry) = self.trash.latest(email_alias)? else {
+            return Err(RMNotInTrash {
+                email_alias: email_alias.map(str::to_string),
+            }
+            .into());
+        };
+
+        let alias = &entry.alias;
+
+        if let Some(existing) = self
+            .inner
+            .list()
+            .await?
+            .into_iter()
+            .find(|a| a.email_alias.eq_ignore_ascii_case(&alias.email_alias))
+        {
+            return Err(RMAlreadyExists { alias: existing }.into());
+        }
+
+        let id = self.recreate(&entry).await?;
+
+        let metadata = &alias.metadata;
+
+        let update = RMMetadataUpdate {
+            website: metadata.website.clone(),
+            notes: metadata.notes.clone(),
+            // an alias that expired while in the trash would be reaped again
+            expires_at: metadata.expires_at.filter(|e| *e > Utc::now()),
+            replaces: metadata.replaces.clone(),
+            replaced_by: metadata.replaced_by.clone(),
+            // the alias keeps its history under its new id
+            created_at: metadata.created_at,
+            creator: metadata.creator.clone(),
+            ..RMMetadataUpdate::default()
+        };
+
+        if !update.is_empty() {
+            self.inner.annotate(&id, &update).await?;
+        }
+
+        self.trash.remove(entry.id)?;
+
+        info!("{} restored from the trash", alias.email_alias);
+
+        self.inner.get(&id).await
+    }
+}
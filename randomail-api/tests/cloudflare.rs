@@ -0,0 +1,489 @@
+//! The Cloudflare client and provider against the in-process fake API
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use serde_json::{Value, json};
+
+use randomail_api::{
+    cf_email::{
+        CFEmailRule, CloudflareProvider, create_email_rule, get_email_rule, list_email_rules,
+    },
+    http::{CFApiError, CFClient, CFTransport, cassette::CFCassette},
+    metadata::{MetadataProvider, RMMetadataStore, RMMetadataUpdate},
+    provider::{AliasProvider, RMAlias, RMAlreadyExists, RMPartialFailure},
+    retarget::{retarget, retarget_plan},
+    trash::{RMTrashStore, TrashProvider},
+};
+use randomail_cf_fake::{FakeCloudflare, FakeRule};
+
+const TOKEN: &str = "test-token";
+const ZONE: &str = "example.com";
+const DEST: &str = "you@inbox.example";
+
+/// A fake Cloudflare with one zone
+struct Fake {
+    cf: FakeCloudflare,
+    zone_id: String,
+}
+
+impl Fake {
+    async fn start() -> Result<Self> {
+        let cf = FakeCloudflare::start(TOKEN).await?;
+        let zone_id = cf.add_zone(ZONE);
+
+        Ok(Self { cf, zone_id })
+    }
+
+    fn client(&self) -> CFClient {
+        CFClient::new(TOKEN).with_api_url(self.cf.api_url())
+    }
+
+    fn provider(&self) -> CloudflareProvider {
+        CloudflareProvider::new(self.client(), ZONE, &self.zone_id)
+    }
+
+    fn seed(&self, email_alias: &str) -> String {
+        self.cf.add_rule(
+            &self.zone_id,
+            FakeRule::forward("seeded", email_alias, DEST),
+        )
+    }
+}
+
+/// A file of its own, such as a metadata database, removed when dropped
+struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    fn new(ext: &str) -> Self {
+        Self {
+            path: std::env::temp_dir().join(format!(
+                "randomail-test-{}-{:08x}.{ext}",
+                std::process::id(),
+                rand::random::<u32>()
+            )),
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// The `CFApiError` of a failed request
+fn api_error(err: &anyhow::Error) -> Result<&CFApiError> {
+    err.downcast_ref::<CFApiError>()
+        .with_context(|| format!("not a Cloudflare error: {err:#}"))
+}
+
+/// Code of the first error of a Cloudflare error envelope
+fn error_code(err: &CFApiError) -> Result<Value> {
+    let body: Value = serde_json::from_str(&err.body)?;
+
+    assert_eq!(body.get("success"), Some(&json!(false)));
+
+    body.pointer("/errors/0/code")
+        .cloned()
+        .context("no error in the envelope")
+}
+
+#[tokio::test]
+async fn list_follows_every_page() -> Result<()> {
+    let fake = Fake::start().await?;
+
+    for i in 0..120 {
+        fake.seed(&format!("alias{i}@{ZONE}"));
+    }
+
+    let mut catch_all = FakeRule::forward("catch-all", "", DEST);
+    catch_all.matchers = vec![json!({ "type": "all" })];
+    catch_all.actions = vec![json!({ "type": "drop" })];
+    fake.cf.add_rule(&fake.zone_id, catch_all);
+
+    let aliases = fake.provider().list().await?;
+
+    // three pages of 50, the catch-all isn't an alias
+    assert_eq!(aliases.len(), 120);
+    assert_eq!(fake.cf.requests(), 3);
+    assert!(
+        aliases
+            .iter()
+            .any(|a| a.email_alias == "alias0@example.com")
+    );
+    assert!(
+        aliases
+            .iter()
+            .any(|a| a.email_alias == "alias119@example.com")
+    );
+
+    let rules = list_email_rules(&fake.client(), &fake.zone_id).await?;
+    assert_eq!(rules.len(), 121);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn create_adds_a_forward_rule() -> Result<()> {
+    let fake = Fake::start().await?;
+
+    let alias = fake
+        .provider()
+        .create("Shop", "shop@example.com", DEST)
+        .await?;
+
+    assert_eq!(alias.email_alias, "shop@example.com");
+    assert_eq!(alias.email_destination, DEST);
+    assert_eq!(alias.name, "Shop");
+    assert!(alias.enabled);
+
+    let rule = get_email_rule(&fake.client(), &fake.zone_id, &alias.id).await?;
+    assert_eq!(rule.email_alias(), Some("shop@example.com"));
+    assert_eq!(
+        rule.actions,
+        vec![json!({ "type": "forward", "value": [DEST] })]
+    );
+
+    // a duplicate check, then the creation
+    assert_eq!(fake.cf.requests(), 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn create_rejects_a_taken_address() -> Result<()> {
+    let fake = Fake::start().await?;
+    let id = fake.seed("taken@example.com");
+
+    let err = fake
+        .provider()
+        .create("Again", "Taken@Example.com", DEST)
+        .await
+        .err()
+        .context("created a duplicate")?;
+
+    let exists = err
+        .downcast_ref::<RMAlreadyExists>()
+        .with_context(|| format!("unexpected error: {err:#}"))?;
+
+    assert_eq!(exists.alias.id, id);
+    assert_eq!(exists.alias.email_alias, "taken@example.com");
+    assert_eq!(fake.cf.rules(&fake.zone_id).len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn create_rejects_the_address_of_a_non_forward_rule() -> Result<()> {
+    let fake = Fake::start().await?;
+
+    let mut dropped = FakeRule::forward("Dropped", "dropped@example.com", DEST);
+    dropped.actions = vec![json!({ "type": "drop" })];
+    let id = fake.cf.add_rule(&fake.zone_id, dropped);
+
+    let err = fake
+        .provider()
+        .create("Dropped", "dropped@example.com", DEST)
+        .await
+        .err()
+        .context("created a rule shadowed by another")?;
+
+    let exists = err
+        .downcast_ref::<RMAlreadyExists>()
+        .with_context(|| format!("unexpected error: {err:#}"))?;
+
+    assert_eq!(exists.alias.id, id);
+    assert_eq!(exists.alias.email_alias, "dropped@example.com");
+    assert_eq!(fake.cf.rules(&fake.zone_id).len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn rate_limited_requests_are_retried() -> Result<()> {
+    let fake = Fake::start().await?;
+    fake.seed("shop@example.com");
+    fake.cf.set_rate_limit(1, Duration::from_secs(1));
+
+    let provider = fake.provider();
+    provider.list().await?;
+
+    // throttled once, retried after the second `Retry-After` asks for
+    let aliases = provider.list().await?;
+
+    assert_eq!(aliases.len(), 1);
+    assert_eq!(fake.cf.requests(), 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn long_retry_after_fails_instead_of_waiting() -> Result<()> {
+    let fake = Fake::start().await?;
+    fake.cf
+        .set_rate_limit_retry_after(1, Duration::from_mins(1), 120);
+
+    let provider = fake.provider();
+    provider.list().await?;
+
+    let err = provider
+        .list()
+        .await
+        .err()
+        .context("waited out a two minute Retry-After")?;
+
+    let api_err = api_error(&err)?;
+    assert_eq!(api_err.status, 429);
+    assert_eq!(error_code(api_err)?, json!(971));
+    assert_eq!(fake.cf.requests(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn error_envelopes_become_api_errors() -> Result<()> {
+    let fake = Fake::start().await?;
+
+    // wrong token
+    let provider = CloudflareProvider::new(
+        CFClient::new("wrong").with_api_url(fake.cf.api_url()),
+        ZONE,
+        &fake.zone_id,
+    );
+    let err = provider
+        .list()
+        .await
+        .err()
+        .context("listed with a bad token")?;
+    let api_err = api_error(&err)?;
+    assert_eq!(api_err.status, 403);
+    assert_eq!(error_code(api_err)?, json!(10000));
+
+    // unknown zone
+    let provider = CloudflareProvider::new(fake.client(), ZONE, "0".repeat(32));
+    let err = provider
+        .list()
+        .await
+        .err()
+        .context("listed an unknown zone")?;
+    let api_err = api_error(&err)?;
+    assert_eq!(api_err.status, 404);
+    assert_eq!(error_code(api_err)?, json!(7003));
+
+    // unknown rule
+    let err = fake
+        .provider()
+        .get(&"f".repeat(32))
+        .await
+        .err()
+        .context("found an unknown rule")?;
+    let api_err = api_error(&err)?;
+    assert_eq!(api_err.status, 404);
+    assert_eq!(error_code(api_err)?, json!(2020));
+
+    // invalid rule
+    let rule = CFEmailRule {
+        id: String::new(),
+        tag: String::new(),
+        name: "Broken".to_string(),
+        enabled: true,
+        priority: 0,
+        matchers: Vec::new(),
+        actions: vec![json!({ "type": "drop" })],
+    };
+    let err = create_email_rule(&fake.client(), &fake.zone_id, &rule)
+        .await
+        .err()
+        .context("created a rule without matchers")?;
+    let api_err = api_error(&err)?;
+    assert_eq!(api_err.status, 400);
+    assert_eq!(error_code(api_err)?, json!(1001));
+    assert!(fake.cf.rules(&fake.zone_id).is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn untrash_keeps_the_history_of_the_alias() -> Result<()> {
+    let fake = Fake::start().await?;
+    let db = TempFile::new("db");
+
+    let provider = TrashProvider::new(
+        Box::new(MetadataProvider::new(
+            Box::new(fake.provider()),
+            RMMetadataStore::open(&db.path)?,
+        )),
+        RMTrashStore::open(&db.path, 30)?,
+        Some((fake.client(), fake.zone_id.clone())),
+    );
+
+    let created = provider.create("Shop", "shop@example.com", DEST).await?;
+    let update = RMMetadataUpdate {
+        website: Some("shop.example".to_string()),
+        ..RMMetadataUpdate::default()
+    };
+    provider.annotate(&created.id, &update).await?;
+
+    provider.delete(&created.id).await?;
+    assert!(fake.cf.rules(&fake.zone_id).is_empty());
+
+    let restored = provider.untrash(Some("shop@example.com")).await?;
+
+    assert_ne!(restored.id, created.id);
+    assert_eq!(restored.email_alias, "shop@example.com");
+    assert_eq!(restored.metadata.created_at, created.metadata.created_at);
+    assert_eq!(restored.metadata.creator, created.metadata.creator);
+    assert_eq!(restored.metadata.website.as_deref(), Some("shop.example"));
+    assert!(provider.trashed().await?.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn retarget_keeps_other_destinations() -> Result<()> {
+    let fake = Fake::start().await?;
+
+    let mut multi = FakeRule::forward("Multi", "multi@example.com", "old@inbox.example");
+    multi.actions = vec![json!({
+        "type": "forward",
+        "value": ["old@inbox.example", "other@inbox.example"],
+    })];
+    let id = fake.cf.add_rule(&fake.zone_id, multi);
+
+    let provider = fake.provider();
+    let aliases = retarget_plan(&provider, "old@inbox.example").await?;
+    assert_eq!(aliases.len(), 1);
+
+    retarget(
+        &provider,
+        &aliases,
+        "old@inbox.example",
+        "new@inbox.example",
+        |_, _| {},
+    )
+    .await?;
+
+    let alias = provider.get(&id).await?;
+    assert_eq!(
+        alias.destinations().collect::<Vec<_>>(),
+        ["new@inbox.example", "other@inbox.example"]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn failed_retarget_restores_every_destination() -> Result<()> {
+    let fake = Fake::start().await?;
+
+    let mut multi = FakeRule::forward("Multi", "multi@example.com", "old@inbox.example");
+    multi.actions = vec![json!({
+        "type": "forward",
+        "value": ["old@inbox.example", "other@inbox.example"],
+    })];
+    let id = fake.cf.add_rule(&fake.zone_id, multi);
+
+    let provider = fake.provider();
+    let mut aliases = retarget_plan(&provider, "old@inbox.example").await?;
+
+    // deleted behind our back, so its update fails
+    aliases.push(RMAlias {
+        id: "f".repeat(32),
+        email_alias: "gone@example.com".to_string(),
+        email_destination: "old@inbox.example".to_string(),
+        ..RMAlias::default()
+    });
+
+    let err = retarget(
+        &provider,
+        &aliases,
+        "old@inbox.example",
+        "new@inbox.example",
+        |_, _| {},
+    )
+    .await
+    .err()
+    .context("retargeted an alias that doesn't exist")?;
+    assert!(err.is::<RMPartialFailure>());
+
+    let alias = provider.get(&id).await?;
+    assert_eq!(
+        alias.destinations().collect::<Vec<_>>(),
+        ["old@inbox.example", "other@inbox.example"]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn readdress_changes_the_recipient_matcher() -> Result<()> {
+    let fake = Fake::start().await?;
+
+    let mut rule = FakeRule::forward("Shop", "shop@example.com", DEST);
+    rule.matchers.insert(
+        0,
+        json!({ "type": "literal", "field": "from", "value": "store@shop.example" }),
+    );
+    let id = fake.cf.add_rule(&fake.zone_id, rule);
+
+    let provider = fake.provider();
+    assert_eq!(provider.get(&id).await?.email_alias, "shop@example.com");
+
+    let alias = provider.readdress(&id, "store@example.com").await?;
+    assert_eq!(alias.email_alias, "store@example.com");
+
+    let rule = get_email_rule(&fake.client(), &fake.zone_id, &id).await?;
+    assert_eq!(
+        rule.matchers,
+        vec![
+            json!({ "type": "literal", "field": "from", "value": "store@shop.example" }),
+            json!({ "type": "literal", "field": "to", "value": "store@example.com" }),
+        ]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn recorded_cassette_replays() -> Result<()> {
+    let fake = Fake::start().await?;
+    let cassette = TempFile::new("jsonl");
+
+    let recording = CloudflareProvider::new(
+        fake.client()
+            .with_transport(CFTransport::Record(Arc::new(CFCassette::create(
+                &cassette.path,
+            )?))),
+        ZONE,
+        &fake.zone_id,
+    );
+    let created = recording.create("Shop", "shop@example.com", DEST).await?;
+    let listed = recording.list().await?;
+
+    let recorded = std::fs::read_to_string(&cassette.path)?;
+    assert!(!recorded.contains(TOKEN));
+
+    // every exchange is appended as soon as it's done
+    let exchanges = recorded.lines().count();
+    assert!(exchanges >= 2, "{recorded}");
+
+    let replaying = CloudflareProvider::new(
+        CFClient::new(TOKEN)
+            .with_api_url("http://127.0.0.1:9")
+            .with_transport(CFTransport::Replay(Arc::new(CFCassette::open(
+                &cassette.path,
+            )?))),
+        ZONE,
+        &fake.zone_id,
+    );
+    assert_eq!(
+        replaying.create("Shop", "shop@example.com", DEST).await?.id,
+        created.id
+    );
+    assert_eq!(replaying.list().await?.len(), listed.len());
+
+    Ok(())
+}
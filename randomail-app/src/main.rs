@@ -1,33 +1,70 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
-use anyhow::Result;
+use anyhow::{Result, anyhow};
 use axum::{
     Json, Router,
-    extract::{Path, State},
-    http::{StatusCode, header},
+    extract::{ConnectInfo, Multipart, Path, Query, Request, State},
+    http::{HeaderMap, StatusCode, header},
+    middleware::{self, Next},
     response::{Html, IntoResponse, Response},
-    routing::{delete, get},
+    routing::{delete, get, post},
 };
+use data_encoding::BASE64;
+use log::error;
 use serde::{Deserialize, Serialize};
 
 use randomail_api::{
-    cf_email::{add_email_route, delete_email_route, list_email_routes, update_email_route},
+    address::{alias_address, parse_address},
+    audit::{RMAuditEntry, RMAuditFilter, RMOperation, parse_since, with_actor},
     config::RMConfig,
+    drift::{RMDrift, accept_drift, detect_drift},
+    error::RMErrorKind,
+    expiry::{parse_expiry, reap},
+    export::{RMExportFormat, export},
+    generator::{RMNameStrategy, suggest},
+    import::{RMImportResult, import},
+    metadata::RMMetadataUpdate,
+    migrate::{RMImportSource, parse_source},
+    provider::{AliasProvider, RMAlias, RMAlreadyExists, RMCapabilities},
+    rotate::{RMRetirePolicy, replacement_name, rotate},
+    site::{matches_site, normalize_site, site_alias_name},
+    tags::{encode_tags, normalize_tag},
+    trash::RMTrashEntry,
 };
 
 const INDEX_HTML: &str = include_str!("../static/index.html");
 const FAVICON: &[u8] = include_bytes!("../static/favicon.ico");
+const REAP_INTERVAL: Duration = Duration::from_mins(1);
+const SUGGESTION_COUNT: usize = 10;
+const MAX_SUGGESTION_COUNT: usize = 50;
 
 struct AppState {
     config: RMConfig,
+    provider: Box<dyn AliasProvider>,
 }
 
 struct AppError(anyhow::Error);
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        let body = serde_json::json!({ "error": self.0.to_string() });
-        (StatusCode::INTERNAL_SERVER_ERROR, Json(body)).into_response()
+        let kind = RMErrorKind::of(&self.0);
+        let status = match kind {
+            RMErrorKind::NotFound => StatusCode::NOT_FOUND,
+            RMErrorKind::AlreadyExists => StatusCode::CONFLICT,
+            RMErrorKind::InvalidAlias | RMErrorKind::InvalidInput => StatusCode::BAD_REQUEST,
+            RMErrorKind::NotConfigured => StatusCode::PRECONDITION_FAILED,
+            RMErrorKind::Cloudflare => StatusCode::BAD_GATEWAY,
+            RMErrorKind::PartialFailure | RMErrorKind::Other => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let body = match self.0.downcast_ref::<RMAlreadyExists>() {
+            Some(exists) => serde_json::json!({
+                "error": exists.to_string(),
+                "kind": kind.to_string(),
+                "alias": exists.alias,
+            }),
+            None => serde_json::json!({ "error": self.0.to_string(), "kind": kind.to_string() }),
+        };
+        (status, Json(body)).into_response()
     }
 }
 
@@ -37,10 +74,25 @@ impl<E: Into<anyhow::Error>> From<E> for AppError {
     }
 }
 
+#[derive(Deserialize)]
+struct ListQuery {
+    tag: Option<String>,
+    /// Only aliases used for this website
+    site: Option<String>,
+}
+
 async fn list_aliases(
     State(state): State<Arc<AppState>>,
+    Query(query): Query<ListQuery>,
 ) -> Result<Json<Vec<serde_json::Value>>, AppError> {
-    let aliases = list_email_routes(&state.config.zone_id, &state.config.token).await?;
+    let mut aliases = state.provider.list().await?;
+    if let Some(tag) = &query.tag {
+        aliases.retain(|a| a.has_tag(tag));
+    }
+    if let Some(site) = &query.site {
+        let site = normalize_site(site)?;
+        aliases.retain(|a| matches_site(a, &site));
+    }
     let json: Vec<serde_json::Value> = aliases
         .into_iter()
         .map(serde_json::to_value)
@@ -48,61 +100,288 @@ async fn list_aliases(
     Ok(Json(json))
 }
 
+#[derive(Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    format: RMExportFormat,
+}
+
+async fn export_aliases(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, AppError> {
+    let aliases = state.provider.list().await?;
+    let data = export(&aliases, query.format)?;
+    let disposition = format!(
+        "attachment; filename=\"randomail-aliases.{}\"",
+        query.format.extension()
+    );
+    Ok((
+        [
+            (
+                header::CONTENT_TYPE,
+                query.format.content_type().to_string(),
+            ),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        data,
+    )
+        .into_response())
+}
+
+#[derive(Deserialize)]
+struct ImportQuery {
+    /// Guessed from the file content when missing
+    format: Option<RMExportFormat>,
+    #[serde(default)]
+    source: RMImportSource,
+    #[serde(default)]
+    keep_mailboxes: bool,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+async fn import_aliases(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ImportQuery>,
+    mut multipart: Multipart,
+) -> Result<Json<Vec<RMImportResult>>, AppError> {
+    let Some(field) = multipart.next_field().await? else {
+        return Err(anyhow!("no file was uploaded").into());
+    };
+    let data = field.bytes().await?;
+    let rows = parse_source(&data, query.source, query.format, query.keep_mailboxes)?;
+    let results = import(
+        state.provider.as_ref(),
+        &state.config,
+        &state.config.client(),
+        rows,
+        query.dry_run,
+        |_| {},
+    )
+    .await?;
+    Ok(Json(results))
+}
+
+#[derive(Deserialize)]
+struct MetadataFields {
+    website: Option<String>,
+    notes: Option<String>,
+    /// Duration or date, empty to clear
+    expires_at: Option<String>,
+}
+
+impl MetadataFields {
+    fn update(&self) -> Result<RMMetadataUpdate> {
+        let mut update = RMMetadataUpdate {
+            website: self.website.clone(),
+            notes: self.notes.clone(),
+            ..RMMetadataUpdate::default()
+        };
+
+        match self.expires_at.as_deref().map(str::trim) {
+            Some("") => update.clear_expiry = true,
+            Some(expires_at) => update.expires_at = Some(parse_expiry(expires_at)?),
+            None => {}
+        }
+
+        Ok(update)
+    }
+}
+
 #[derive(Deserialize)]
 struct CreateAlias {
-    alias: String,
-    description: String,
+    /// Generated from `site` when missing
+    alias: Option<String>,
+    /// Defaults to `site`
+    description: Option<String>,
+    site: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(flatten)]
+    metadata: MetadataFields,
 }
 
 async fn create_alias(
     State(state): State<Arc<AppState>>,
     Json(payload): Json<CreateAlias>,
-) -> Result<StatusCode, AppError> {
-    let email_alias = format!("{}@{}", payload.alias, state.config.zone);
-    add_email_route(
-        &state.config.zone_id,
-        payload.description,
-        email_alias,
-        &state.config.destination_email,
-        &state.config.token,
-    )
-    .await?;
-    Ok(StatusCode::CREATED)
+) -> Result<(StatusCode, Json<RMAlias>), AppError> {
+    let mut update = payload.metadata.update()?;
+    let site = payload.site.as_deref().map(normalize_site).transpose()?;
+    let name = match (&payload.alias, &site) {
+        (Some(alias), _) => alias.clone(),
+        (None, Some(site)) => {
+            site_alias_name(state.provider.as_ref(), state.config.site_template(), site).await?
+        }
+        (None, None) => return Err(anyhow!("either an alias or a site is required").into()),
+    };
+    let description = payload
+        .description
+        .clone()
+        .or_else(|| site.clone())
+        .unwrap_or_default();
+    if update.website.is_none() {
+        update.website.clone_from(&site);
+    }
+    let tags = payload
+        .tags
+        .iter()
+        .map(|t| normalize_tag(t))
+        .collect::<Result<Vec<_>>>()?;
+    let email_alias = alias_address(&name, state.provider.domain())?;
+    let mut alias = state
+        .provider
+        .create(
+            &encode_tags(&description, &tags)?,
+            &email_alias,
+            &state.config.destination_email,
+        )
+        .await?;
+    if !update.is_empty() {
+        state.provider.annotate(&alias.id, &update).await?;
+        alias = state.provider.get(&alias.id).await?;
+    }
+    Ok((StatusCode::CREATED, Json(alias)))
 }
 
 async fn remove_alias(
     State(state): State<Arc<AppState>>,
     Path(id): Path<String>,
 ) -> Result<StatusCode, AppError> {
-    delete_email_route(&state.config.zone_id, &id, &state.config.token).await?;
+    state.provider.delete(&id).await?;
     Ok(StatusCode::NO_CONTENT)
 }
 
+async fn list_trash(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<RMTrashEntry>>, AppError> {
+    Ok(Json(state.provider.trashed().await?))
+}
+
+#[derive(Deserialize, Default)]
+struct RestoreAlias {
+    /// Address of the deleted alias, the latest deleted one when missing
+    alias: Option<String>,
+}
+
+async fn restore_alias(
+    State(state): State<Arc<AppState>>,
+    payload: Option<Json<RestoreAlias>>,
+) -> Result<(StatusCode, Json<RMAlias>), AppError> {
+    let Json(payload) = payload.unwrap_or_default();
+    let email_alias = payload
+        .alias
+        .map(|a| parse_address(&a, state.provider.domain()))
+        .transpose()?;
+    let alias = state.provider.untrash(email_alias.as_deref()).await?;
+    Ok((StatusCode::CREATED, Json(alias)))
+}
+
 #[derive(Deserialize)]
 struct UpdateAlias {
-    enabled: bool,
+    enabled: Option<bool>,
+    tags: Option<Vec<String>>,
+    #[serde(flatten)]
+    metadata: MetadataFields,
 }
 
-async fn toggle_alias(
+async fn update_alias(
     State(state): State<Arc<AppState>>,
     Path(id): Path<String>,
     Json(payload): Json<UpdateAlias>,
 ) -> Result<StatusCode, AppError> {
-    update_email_route(
-        &state.config.zone_id,
-        &id,
-        &state.config.token,
-        payload.enabled,
-    )
-    .await?;
+    let update = payload.metadata.update()?;
+    if let Some(enabled) = payload.enabled {
+        state.provider.set_enabled(&id, enabled).await?;
+    }
+    if let Some(tags) = &payload.tags {
+        state.provider.set_tags(&id, tags).await?;
+    }
+    if !update.is_empty() {
+        state.provider.annotate(&id, &update).await?;
+    }
     Ok(StatusCode::OK)
 }
 
+#[derive(Deserialize)]
+struct MoveAlias {
+    /// New address, or a local part under the configured domain
+    alias: String,
+}
+
+async fn move_alias(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Json(payload): Json<MoveAlias>,
+) -> Result<Json<RMAlias>, AppError> {
+    let email_alias = parse_address(&payload.alias, state.provider.domain())?;
+    let alias = state.provider.readdress(&id, &email_alias).await?;
+    Ok(Json(alias))
+}
+
+#[derive(Deserialize)]
+struct RotateAlias {
+    /// Name for the new alias, generated when missing
+    alias: Option<String>,
+    #[serde(default)]
+    delete: bool,
+    /// Duration or date the old alias keeps working until
+    grace: Option<String>,
+}
+
+async fn rotate_alias(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Json(payload): Json<RotateAlias>,
+) -> Result<(StatusCode, Json<RMAlias>), AppError> {
+    let old = state.provider.get(&id).await?;
+    let name = match &payload.alias {
+        Some(alias) => alias.clone(),
+        None => {
+            replacement_name(
+                state.provider.as_ref(),
+                &old,
+                state.config.name_strategy,
+                state.config.site_template(),
+            )
+            .await?
+        }
+    };
+    let email_alias = alias_address(&name, state.provider.domain())?;
+    let policy = match payload.grace.as_deref() {
+        Some(grace) => RMRetirePolicy::After(parse_expiry(grace)?),
+        None if payload.delete => RMRetirePolicy::Delete,
+        None => RMRetirePolicy::Disable,
+    };
+    let new = rotate(state.provider.as_ref(), &old, &email_alias, policy).await?;
+    Ok((StatusCode::CREATED, Json(new)))
+}
+
+#[derive(Deserialize)]
+struct SuggestionQuery {
+    strategy: Option<RMNameStrategy>,
+    count: Option<usize>,
+}
+
+async fn get_suggestions(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<SuggestionQuery>,
+) -> Result<Json<Vec<String>>, AppError> {
+    let strategy = query.strategy.unwrap_or(state.config.name_strategy);
+    let count = query
+        .count
+        .unwrap_or(SUGGESTION_COUNT)
+        .min(MAX_SUGGESTION_COUNT);
+    let names = suggest(state.provider.as_ref(), strategy, count).await?;
+    Ok(Json(names))
+}
+
 #[derive(Serialize)]
 struct ConfigResponse {
     account_id: String,
     destination_email: String,
     zone: String,
+    capabilities: RMCapabilities,
     version: &'static str,
 }
 
@@ -110,11 +389,97 @@ async fn get_config(State(state): State<Arc<AppState>>) -> Json<ConfigResponse>
     Json(ConfigResponse {
         account_id: state.config.account_id.clone(),
         destination_email: state.config.destination_email.clone(),
-        zone: state.config.zone.clone(),
+        zone: state.provider.domain().to_string(),
+        capabilities: state.provider.capabilities(),
         version: env!("CARGO_PKG_VERSION"),
     })
 }
 
+#[derive(Deserialize)]
+struct AuditQuery {
+    alias: Option<String>,
+    actor: Option<String>,
+    operation: Option<RMOperation>,
+    /// Date or duration, e.g. `7d`
+    since: Option<String>,
+    limit: Option<usize>,
+}
+
+async fn list_audit(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<Vec<RMAuditEntry>>, AppError> {
+    let filter = RMAuditFilter {
+        alias: query
+            .alias
+            .map(|a| parse_address(&a, state.provider.domain()))
+            .transpose()?,
+        actor: query.actor,
+        operation: query.operation,
+        since: query.since.as_deref().map(parse_since).transpose()?,
+        limit: query.limit,
+    };
+    Ok(Json(state.config.audit_log()?.read(&filter)?))
+}
+
+/// Rules changed outside of randomail, none with Postfix
+async fn get_drift(State(state): State<Arc<AppState>>) -> Result<Json<Option<RMDrift>>, AppError> {
+    if state.config.postfix.is_some() {
+        return Ok(Json(None));
+    }
+    let drift = detect_drift(&state.config, &state.config.client()).await?;
+    Ok(Json(Some(drift)))
+}
+
+async fn accept_changes(State(state): State<Arc<AppState>>) -> Result<StatusCode, AppError> {
+    accept_drift(&state.config, &state.config.client()).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// User the reverse proxy authenticated, from its header or basic auth
+fn web_user(headers: &HeaderMap) -> Option<String> {
+    for name in ["remote-user", "x-remote-user", "x-forwarded-user"] {
+        if let Some(user) = headers.get(name).and_then(|v| v.to_str().ok())
+            && !user.is_empty()
+        {
+            return Some(user.to_string());
+        }
+    }
+
+    let credentials = headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = BASE64.decode(credentials.trim().as_bytes()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, _) = decoded.split_once(':')?;
+    (!user.is_empty()).then(|| user.to_string())
+}
+
+/// Run the request on behalf of the web user, for the audit log. Any client
+/// can send user headers, so they are only believed when a reverse proxy
+/// that sets them is configured, the peer address is recorded otherwise.
+async fn audit_actor(
+    State(state): State<Arc<AppState>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let user = if state.config.trust_proxy_user {
+        web_user(req.headers())
+    } else {
+        None
+    };
+
+    let actor = match user {
+        Some(user) => format!("web:{user}"),
+        None => format!("web:{}", peer.ip()),
+    };
+
+    with_actor(actor, next.run(req)).await
+}
+
 async fn index() -> Html<&'static str> {
     Html(INDEX_HTML)
 }
@@ -123,24 +488,71 @@ async fn favicon() -> impl IntoResponse {
     ([(header::CONTENT_TYPE, "image/x-icon")], FAVICON)
 }
 
+async fn reaper(state: Arc<AppState>) {
+    let store = match state.config.metadata_store() {
+        Ok(store) => store,
+        Err(e) => {
+            error!("Unable to open metadata, aliases won't expire ({e})");
+            return;
+        }
+    };
+
+    let mut interval = tokio::time::interval(REAP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let res = with_actor(
+            "expiry".to_string(),
+            reap(state.provider.as_ref(), &store, state.config.expiry_policy),
+        )
+        .await;
+
+        if let Err(e) = res {
+            error!("Unable to expire aliases ({e})");
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
     let config = RMConfig::load()?;
-    let state = Arc::new(AppState { config });
+    let provider = config.provider()?;
+    let state = Arc::new(AppState { config, provider });
+
+    tokio::spawn(reaper(Arc::clone(&state)));
 
     let app = Router::new()
         .route("/", get(index))
         .route("/favicon.ico", get(favicon))
         .route("/aliases", get(list_aliases).post(create_alias))
-        .route("/aliases/{id}", delete(remove_alias).put(toggle_alias))
+        .route("/aliases/export", get(export_aliases))
+        .route("/aliases/import", post(import_aliases))
+        .route("/aliases/{id}", delete(remove_alias).put(update_alias))
+        .route("/aliases/{id}/move", post(move_alias))
+        .route("/aliases/{id}/rotate", post(rotate_alias))
+        .route("/trash", get(list_trash))
+        .route("/trash/restore", post(restore_alias))
+        .route("/suggestions", get(get_suggestions))
         .route("/config", get(get_config))
+        .route("/audit", get(list_audit))
+        .route("/drift", get(get_drift))
+        .route("/drift/accept", post(accept_changes))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            audit_actor,
+        ))
         .with_state(state);
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
     println!("listening on http://localhost:3000");
-    axum::serve(listener, app).await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await?;
 
     Ok(())
 }
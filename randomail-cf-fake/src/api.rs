@@ -0,0 +1,420 @@
+use axum::{
+    Json, Router,
+    extract::{Path, Query, Request, State},
+    http::{StatusCode, Uri, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use crate::{FAKE_ACCOUNT_ID, FakeDestination, FakeRule, FakeZone, SharedState, lock};
+
+const DEFAULT_PER_PAGE: usize = 20;
+const MIN_PER_PAGE: usize = 5;
+const MAX_PER_PAGE: usize = 50;
+
+/// Cloudflare v4 error envelope
+pub struct CFError {
+    status: StatusCode,
+    code: u32,
+    message: String,
+}
+
+impl CFError {
+    fn new<M>(status: StatusCode, code: u32, message: M) -> Self
+    where
+        M: Into<String>,
+    {
+        Self {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn no_route(uri: &Uri) -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            7003,
+            format!(
+                "Could not route to {}, perhaps your object identifier is invalid?",
+                uri.path()
+            ),
+        )
+    }
+
+    fn invalid<M>(message: M) -> Self
+    where
+        M: Into<String>,
+    {
+        Self::new(StatusCode::BAD_REQUEST, 1001, message)
+    }
+}
+
+impl IntoResponse for CFError {
+    fn into_response(self) -> Response {
+        let body = json!({
+            "success": false,
+            "errors": [{ "code": self.code, "message": self.message }],
+            "messages": [],
+            "result": null,
+        });
+
+        (self.status, Json(body)).into_response()
+    }
+}
+
+type CFResult = Result<Json<Value>, CFError>;
+
+fn success<R>(result: R) -> Json<Value>
+where
+    R: Serialize,
+{
+    Json(json!({
+        "success": true,
+        "errors": [],
+        "messages": [],
+        "result": result,
+    }))
+}
+
+#[derive(Deserialize)]
+struct PageQuery {
+    page: Option<usize>,
+    per_page: Option<usize>,
+}
+
+fn paged<R>(items: Vec<R>, query: &PageQuery, total_pages: bool) -> CFResult
+where
+    R: Serialize,
+{
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(DEFAULT_PER_PAGE);
+
+    if !(MIN_PER_PAGE..=MAX_PER_PAGE).contains(&per_page) {
+        return Err(CFError::invalid(format!(
+            "per_page must be between {MIN_PER_PAGE} and {MAX_PER_PAGE}"
+        )));
+    }
+
+    let total_count = items.len();
+    let skip = page.saturating_sub(1).saturating_mul(per_page);
+    let result: Vec<R> = items.into_iter().skip(skip).take(per_page).collect();
+
+    let mut result_info = json!({
+        "page": page,
+        "per_page": per_page,
+        "count": result.len(),
+        "total_count": total_count,
+    });
+
+    if total_pages && let Some(info) = result_info.as_object_mut() {
+        info.insert(
+            "total_pages".to_string(),
+            json!(total_count.div_ceil(per_page)),
+        );
+    }
+
+    Ok(Json(json!({
+        "success": true,
+        "errors": [],
+        "messages": [],
+        "result": result,
+        "result_info": result_info,
+    })))
+}
+
+fn parse_body<R>(body: &str) -> Result<R, CFError>
+where
+    R: for<'de> Deserialize<'de>,
+{
+    serde_json::from_str(body).map_err(|e| CFError::invalid(format!("Invalid JSON body: {e}")))
+}
+
+fn check_rule(rule: &FakeRule) -> Result<(), CFError> {
+    if rule.matchers.is_empty() {
+        return Err(CFError::invalid("matchers must not be empty"));
+    }
+
+    if rule.actions.is_empty() {
+        return Err(CFError::invalid("actions must not be empty"));
+    }
+
+    Ok(())
+}
+
+async fn guard(State(state): State<SharedState>, req: Request, next: Next) -> Response {
+    {
+        let mut state = lock(&state);
+
+        if let Some(retry_after) = state.admit() {
+            let mut res = CFError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                971,
+                "Please wait and consider throttling your request speed",
+            )
+            .into_response();
+
+            res.headers_mut()
+                .insert(header::RETRY_AFTER, header::HeaderValue::from(retry_after));
+
+            return res;
+        }
+
+        let expected = format!("Bearer {}", state.token);
+
+        let authorized = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v == expected);
+
+        if !authorized {
+            return CFError::new(StatusCode::FORBIDDEN, 10000, "Authentication error")
+                .into_response();
+        }
+    }
+
+    next.run(req).await
+}
+
+async fn list_zones(State(state): State<SharedState>, Query(query): Query<PageQuery>) -> CFResult {
+    let zones: Vec<Value> = lock(&state)
+        .zones
+        .iter()
+        .map(|z: &FakeZone| {
+            json!({
+                "id": z.id,
+                "name": z.name,
+                "status": "active",
+                "account": { "id": FAKE_ACCOUNT_ID },
+            })
+        })
+        .collect();
+
+    paged(zones, &query, true)
+}
+
+fn known_zone(state: &SharedState, zone_id: &str, uri: &Uri) -> Result<(), CFError> {
+    if lock(state).zones.iter().any(|z| z.id == zone_id) {
+        return Ok(());
+    }
+
+    Err(CFError::no_route(uri))
+}
+
+async fn list_rules(
+    State(state): State<SharedState>,
+    Path(zone_id): Path<String>,
+    Query(query): Query<PageQuery>,
+    uri: Uri,
+) -> CFResult {
+    known_zone(&state, &zone_id, &uri)?;
+
+    let rules: Vec<FakeRule> = lock(&state)
+        .rules
+        .iter()
+        .filter(|(z, _)| *z == zone_id)
+        .map(|(_, r)| r.clone())
+        .collect();
+
+    paged(rules, &query, false)
+}
+
+async fn create_rule(
+    State(state): State<SharedState>,
+    Path(zone_id): Path<String>,
+    uri: Uri,
+    body: String,
+) -> CFResult {
+    known_zone(&state, &zone_id, &uri)?;
+
+    let mut rule: FakeRule = parse_body(&body)?;
+    check_rule(&rule)?;
+
+    let mut state = lock(&state);
+
+    let id = state.new_id();
+    rule.id.clone_from(&id);
+    rule.tag = id;
+
+    state.rules.push((zone_id, rule.clone()));
+
+    Ok(success(rule))
+}
+
+#[derive(Deserialize)]
+struct RulePath {
+    zone_id: String,
+    rule_id: String,
+}
+
+fn rule_not_found() -> CFError {
+    CFError::new(StatusCode::NOT_FOUND, 2020, "Rule not found")
+}
+
+async fn get_rule(State(state): State<SharedState>, Path(p): Path<RulePath>, uri: Uri) -> CFResult {
+    known_zone(&state, &p.zone_id, &uri)?;
+
+    let state = lock(&state);
+
+    let rule = state
+        .rules
+        .iter()
+        .find(|(z, r)| *z == p.zone_id && r.id == p.rule_id)
+        .map(|(_, r)| r.clone())
+        .ok_or_else(rule_not_found)?;
+
+    Ok(success(rule))
+}
+
+async fn update_rule(
+    State(state): State<SharedState>,
+    Path(p): Path<RulePath>,
+    uri: Uri,
+    body: String,
+) -> CFResult {
+    known_zone(&state, &p.zone_id, &uri)?;
+
+    let mut update: FakeRule = parse_body(&body)?;
+    check_rule(&update)?;
+
+    let mut state = lock(&state);
+
+    let (_, rule) = state
+        .rules
+        .iter_mut()
+        .find(|(z, r)| *z == p.zone_id && r.id == p.rule_id)
+        .ok_or_else(rule_not_found)?;
+
+    update.id.clone_from(&rule.id);
+    update.tag.clone_from(&rule.tag);
+    *rule = update.clone();
+
+    Ok(success(update))
+}
+
+async fn delete_rule(
+    State(state): State<SharedState>,
+    Path(p): Path<RulePath>,
+    uri: Uri,
+) -> CFResult {
+    known_zone(&state, &p.zone_id, &uri)?;
+
+    let mut state = lock(&state);
+
+    let pos = state
+        .rules
+        .iter()
+        .position(|(z, r)| *z == p.zone_id && r.id == p.rule_id)
+        .ok_or_else(rule_not_found)?;
+
+    let (_, rule) = state.rules.remove(pos);
+
+    Ok(success(rule))
+}
+
+fn known_account(account_id: &str, uri: &Uri) -> Result<(), CFError> {
+    if account_id == FAKE_ACCOUNT_ID {
+        return Ok(());
+    }
+
+    Err(CFError::no_route(uri))
+}
+
+async fn list_destinations(
+    State(state): State<SharedState>,
+    Path(account_id): Path<String>,
+    Query(query): Query<PageQuery>,
+    uri: Uri,
+) -> CFResult {
+    known_account(&account_id, &uri)?;
+
+    let addrs: Vec<Value> = lock(&state)
+        .destinations
+        .iter()
+        .map(|d: &FakeDestination| {
+            json!({
+                "id": d.id,
+                "tag": d.id,
+                "email": d.email,
+                "verified": d.verified,
+            })
+        })
+        .collect();
+
+    paged(addrs, &query, false)
+}
+
+#[derive(Deserialize)]
+struct CreateDestination {
+    email: String,
+}
+
+async fn create_destination(
+    State(state): State<SharedState>,
+    Path(account_id): Path<String>,
+    uri: Uri,
+    body: String,
+) -> CFResult {
+    known_account(&account_id, &uri)?;
+
+    let req: CreateDestination = parse_body(&body)?;
+
+    let mut state = lock(&state);
+
+    if state.destinations.iter().any(|d| d.email == req.email) {
+        return Err(CFError::invalid(format!(
+            "{} is already a destination address",
+            req.email
+        )));
+    }
+
+    let id = state.new_id();
+
+    let dst = FakeDestination {
+        id,
+        email: req.email,
+        verified: None,
+    };
+
+    state.destinations.push(dst.clone());
+
+    Ok(success(json!({
+        "id": dst.id,
+        "tag": dst.id,
+        "email": dst.email,
+        "verified": dst.verified,
+    })))
+}
+
+async fn fallback(uri: Uri) -> CFError {
+    CFError::no_route(&uri)
+}
+
+pub fn router(state: SharedState) -> Router {
+    let api = Router::new()
+        .route("/zones", get(list_zones))
+        .route(
+            "/zones/{zone_id}/email/routing/rules",
+            get(list_rules).post(create_rule),
+        )
+        .route(
+            "/zones/{zone_id}/email/routing/rules/{rule_id}",
+            get(get_rule).put(update_rule).delete(delete_rule),
+        )
+        .route(
+            "/accounts/{account_id}/email/routing/addresses",
+            get(list_destinations).post(create_destination),
+        )
+        .fallback(fallback)
+        .route_layer(middleware::from_fn_with_state(
+            std::sync::Arc::clone(&state),
+            guard,
+        ))
+        .with_state(state);
+
+    Router::new().nest("/client/v4", api).fallback(fallback)
+}
@@ -0,0 +1,259 @@
+mod api;
+
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::{net::TcpListener, task::JoinHandle};
+
+pub const FAKE_ACCOUNT_ID: &str = "fake-account";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FakeZone {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FakeDestination {
+    pub id: String,
+    pub email: String,
+    pub verified: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FakeRule {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub tag: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub priority: i64,
+    #[serde(default)]
+    pub matchers: Vec<Value>,
+    #[serde(default)]
+    pub actions: Vec<Value>,
+}
+
+const fn default_enabled() -> bool {
+    true
+}
+
+impl FakeRule {
+    /// A rule forwarding `email_alias` to `email_dest`, as randomail creates them
+    pub fn forward<N, A, D>(name: N, email_alias: A, email_dest: D) -> Self
+    where
+        N: Into<String>,
+        A: Into<String>,
+        D: Into<String>,
+    {
+        Self {
+            name: name.into(),
+            enabled: true,
+            matchers: vec![serde_json::json!({
+                "type": "literal",
+                "field": "to",
+                "value": email_alias.into(),
+            })],
+            actions: vec![serde_json::json!({
+                "type": "forward",
+                "value": [email_dest.into()],
+            })],
+            ..Self::default()
+        }
+    }
+}
+
+struct FakeRateLimit {
+    requests: usize,
+    window: Duration,
+    /// Seconds sent in the `Retry-After` header of throttled requests
+    retry_after: u64,
+    seen: VecDeque<Instant>,
+}
+
+struct FakeState {
+    token: String,
+    next_id: u64,
+    requests: usize,
+    zones: Vec<FakeZone>,
+    destinations: Vec<FakeDestination>,
+    rules: Vec<(String, FakeRule)>,
+    rate_limit: Option<FakeRateLimit>,
+}
+
+impl FakeState {
+    fn new_id(&mut self) -> String {
+        self.next_id = self.next_id.wrapping_add(1);
+        format!("{:032x}", self.next_id)
+    }
+
+    /// Returns the seconds to wait for in `Retry-After` when the request has
+    /// to be throttled
+    fn admit(&mut self) -> Option<u64> {
+        self.requests = self.requests.wrapping_add(1);
+
+        let limit = self.rate_limit.as_mut()?;
+
+        let now = Instant::now();
+
+        while let Some(first) = limit.seen.front()
+            && now.duration_since(*first) >= limit.window
+        {
+            limit.seen.pop_front();
+        }
+
+        if limit.seen.len() >= limit.requests {
+            return Some(limit.retry_after);
+        }
+
+        limit.seen.push_back(now);
+        None
+    }
+}
+
+type SharedState = Arc<Mutex<FakeState>>;
+
+fn lock(state: &SharedState) -> MutexGuard<'_, FakeState> {
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// In-process fake of the Cloudflare zones, Email Routing rules and
+/// destination addresses endpoints. The server stops when dropped.
+pub struct FakeCloudflare {
+    addr: SocketAddr,
+    state: SharedState,
+    task: JoinHandle<()>,
+}
+
+impl FakeCloudflare {
+    /// Listen on a random local port and only accept `token`
+    pub async fn start<T>(token: T) -> Result<Self>
+    where
+        T: Into<String>,
+    {
+        let state = Arc::new(Mutex::new(FakeState {
+            token: token.into(),
+            next_id: 0,
+            requests: 0,
+            zones: Vec::new(),
+            destinations: Vec::new(),
+            rules: Vec::new(),
+            rate_limit: None,
+        }));
+
+        let listener = TcpListener::bind("127.0.0.1:0")
+            .await
+            .context("Unable to bind fake Cloudflare server")?;
+
+        let addr = listener
+            .local_addr()
+            .context("Unable to get fake Cloudflare address")?;
+
+        let app = api::router(Arc::clone(&state));
+
+        let task = tokio::spawn(async move {
+            let _ = axum::serve(listener, app).await;
+        });
+
+        Ok(Self { addr, state, task })
+    }
+
+    /// Base URL to inject in place of the public Cloudflare API
+    #[must_use]
+    pub fn api_url(&self) -> String {
+        format!("http://{}/client/v4", self.addr)
+    }
+
+    pub fn add_zone<N>(&self, name: N) -> String
+    where
+        N: Into<String>,
+    {
+        let mut state = lock(&self.state);
+        let id = state.new_id();
+        state.zones.push(FakeZone {
+            id: id.clone(),
+            name: name.into(),
+        });
+        id
+    }
+
+    pub fn add_destination<E>(&self, email: E, verified: bool) -> String
+    where
+        E: Into<String>,
+    {
+        let mut state = lock(&self.state);
+        let id = state.new_id();
+        state.destinations.push(FakeDestination {
+            id: id.clone(),
+            email: email.into(),
+            verified: verified.then(|| "2024-01-01T00:00:00Z".to_string()),
+        });
+        id
+    }
+
+    /// Seed a rule in `zone_id` and return its id
+    pub fn add_rule<Z>(&self, zone_id: Z, mut rule: FakeRule) -> String
+    where
+        Z: Into<String>,
+    {
+        let mut state = lock(&self.state);
+        let id = state.new_id();
+        rule.id.clone_from(&id);
+        rule.tag.clone_from(&id);
+        state.rules.push((zone_id.into(), rule));
+        id
+    }
+
+    #[must_use]
+    pub fn rules(&self, zone_id: &str) -> Vec<FakeRule> {
+        lock(&self.state)
+            .rules
+            .iter()
+            .filter(|(z, _)| z == zone_id)
+            .map(|(_, r)| r.clone())
+            .collect()
+    }
+
+    /// Answer 429 once more than `requests` requests arrived within `window`,
+    /// asking to retry after a second
+    pub fn set_rate_limit(&self, requests: usize, window: Duration) {
+        self.set_rate_limit_retry_after(requests, window, 1);
+    }
+
+    /// Like `set_rate_limit`, asking to retry after `retry_after` seconds
+    pub fn set_rate_limit_retry_after(&self, requests: usize, window: Duration, retry_after: u64) {
+        lock(&self.state).rate_limit = Some(FakeRateLimit {
+            requests,
+            window,
+            retry_after,
+            seen: VecDeque::new(),
+        });
+    }
+
+    pub fn clear_rate_limit(&self) {
+        lock(&self.state).rate_limit = None;
+    }
+
+    /// Number of requests received so far, throttled ones included
+    #[must_use]
+    pub fn requests(&self) -> usize {
+        lock(&self.state).requests
+    }
+}
+
+impl Drop for FakeCloudflare {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -0,0 +1,76 @@
+use std::fmt;
+
+use randomail_api::error::RMErrorKind;
+
+/// Exit codes, see `ErrorKind::code`
+pub const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  success
+  1  any other error
+  2  invalid command line
+  3  randomail is not configured, run `randomail config`, or `derive`
+     has no secret
+  4  no alias matches, or nothing in the trash to restore
+  5  the alias already exists
+  6  invalid alias name, or a name matching several aliases
+  7  Cloudflare refused a request
+  8  some changes of a bulk operation failed, the others were made
+  9  cancelled at the confirmation prompt
+ 10  invalid tag, site or expiry date";
+
+/// The user declined to go on at a confirmation prompt
+#[derive(Debug)]
+pub struct Cancelled(pub &'static str);
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} cancelled", self.0)
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Why a command failed. Each kind has its own exit code, which scripts can
+/// rely on: codes are never reused for another kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Classified the same way as the errors of the web API
+    Api(RMErrorKind),
+    Cancelled,
+}
+
+impl ErrorKind {
+    pub fn of(err: &anyhow::Error) -> Self {
+        if err.downcast_ref::<Cancelled>().is_some() {
+            Self::Cancelled
+        } else {
+            Self::Api(RMErrorKind::of(err))
+        }
+    }
+
+    /// Exit code of the process, 2 is left to clap for usage errors
+    pub fn code(self) -> u8 {
+        match self {
+            Self::Api(kind) => match kind {
+                RMErrorKind::Other => 1,
+                RMErrorKind::NotConfigured => 3,
+                RMErrorKind::NotFound => 4,
+                RMErrorKind::AlreadyExists => 5,
+                RMErrorKind::InvalidAlias => 6,
+                RMErrorKind::Cloudflare => 7,
+                RMErrorKind::PartialFailure => 8,
+                RMErrorKind::InvalidInput => 10,
+            },
+            Self::Cancelled => 9,
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Api(kind) => kind.fmt(f),
+            Self::Cancelled => f.write_str("cancelled"),
+        }
+    }
+}
@@ -1,21 +1,52 @@
-use std::fmt::Display;
+mod exit;
+mod output;
 
-use anyhow::{Result, bail};
+use std::{
+    fmt::Display,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    process::ExitCode,
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
 use clap::{Args, Parser, Subcommand};
 use log::{LevelFilter, info};
-use tabled::{
-    Table,
-    settings::{Rotate, Style},
-};
+use serde::Serialize;
+use tabled::Tabled;
 
 use randomail_api::{
-    cf_email::{
-        add_email_route, delete_email_route, list_email_routes, rename_email_route,
-        update_email_route,
-    },
+    address::{alias_address, parse_address},
+    audit::{RMAuditFilter, RMOperation, parse_since},
     config::RMConfig,
+    derive::{MAX_COUNTER, current_counter, derive_name, new_secret},
+    drift::{accept_drift, detect_drift},
+    expiry::{parse_expiry, reap},
+    export::{RMExportFormat, export},
+    generator::{RMNameStrategy, existing_names, unique_name},
+    http::{CFTransport, cassette::CFCassette},
+    import::{RMImportStatus, import},
+    lookup::{RMAliasNotFound, find_alias},
+    metadata::RMMetadataUpdate,
+    migrate::{RMImportSource, parse_source},
+    provider::{AliasProvider, RMAlreadyExists, RMPartialFailure},
+    retarget::{retarget, retarget_plan},
+    rotate::{RMRetirePolicy, replacement_name, rotate},
+    site::{matches_site, normalize_site, site_alias_name},
+    snapshot::{
+        RMSnapshot, RMSnapshotInfo, auto_snapshot, diff, list_snapshots, resolve_snapshot, restore,
+        restore_plan, store_snapshot,
+    },
+    sync::{RMAliasFile, RMPlan, apply, plan},
+    tags::{encode_tags, normalize_tag},
 };
 
+use exit::{Cancelled, EXIT_CODES_HELP, ErrorKind};
+use output::{ChangeRow, Output, OutputFormat, is_broken_pipe};
+
 #[derive(Args)]
 struct ConfigArgs {
     /// Cloudflare account ID
@@ -33,28 +64,142 @@ struct ConfigArgs {
     /// Domain to create email aliases under
     #[arg(long, short)]
     domain: Option<String>,
+
+    /// Secret used by derive, the same on every machine
+    #[arg(long, conflicts_with = "new_derive_secret")]
+    derive_secret: Option<String>,
+
+    /// Generate a secret for derive and print it, to copy to other machines
+    #[arg(long)]
+    new_derive_secret: bool,
+
+    /// Print the secret used by derive, to copy to other machines
+    #[arg(long, conflicts_with = "new_derive_secret")]
+    show_derive_secret: bool,
+}
+
+/// The secret `derive` uses, on its own so it can be copied
+#[derive(Serialize, Tabled)]
+struct DeriveSecret<'a> {
+    derive_secret: &'a str,
 }
 
 #[derive(Args)]
 struct AddArgs {
     /// Name for the new email alias (e.g. "shopping" for shopping@domain.com)
+    #[arg(long, short, required_unless_present_any = ["random", "site"])]
+    alias: Option<String>,
+
+    /// Generate an unused random name instead of --alias
+    #[arg(long, short, conflicts_with_all = ["alias", "site"])]
+    random: bool,
+
+    /// Generate a name for this website (e.g. amazon.com gives amazon-k3vq)
+    #[arg(long, conflicts_with = "alias", value_parser = normalize_site)]
+    site: Option<String>,
+
+    /// Name strategy for --random: words, base32, uuid-short or pronounceable
+    #[arg(long, short, requires = "random", value_parser = RMNameStrategy::from_str)]
+    strategy: Option<RMNameStrategy>,
+
+    /// Human-readable description for the alias, defaults to --site
+    #[arg(long, short, required_unless_present = "site")]
+    description: Option<String>,
+
+    /// Website the alias is used for, defaults to --site
     #[arg(long, short)]
-    alias: String,
+    website: Option<String>,
+
+    /// Free-form notes about the alias
+    #[arg(long, short)]
+    notes: Option<String>,
+
+    /// Expire the alias after a duration (e.g. 30d) or on a date (YYYY-MM-DD)
+    #[arg(long, short = 'x', value_parser = parse_expiry)]
+    expires: Option<DateTime<Utc>>,
+
+    /// Tag the alias (e.g. shopping), can be repeated
+    #[arg(long = "tag", short, value_name = "TAG", value_parser = normalize_tag)]
+    tags: Vec<String>,
+
+    /// Succeed without changes when the alias already exists
+    #[arg(long)]
+    if_not_exists: bool,
+}
+
+#[derive(Args)]
+struct ListArgs {
+    /// Only list aliases with this tag
+    #[arg(long, short, value_parser = normalize_tag)]
+    tag: Option<String>,
+}
+
+#[derive(Args)]
+struct ExportArgs {
+    /// Output format: json or csv
+    #[arg(long, short, default_value_t, value_parser = RMExportFormat::from_str)]
+    format: RMExportFormat,
+
+    /// Write to this file instead of stdout
+    #[arg(long, value_name = "FILE")]
+    file: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct ImportArgs {
+    /// JSON or CSV export of randomail (only alias is required) or of the
+    /// service given with --source
+    file: PathBuf,
+
+    /// Format of the file, guessed from its content when missing
+    #[arg(long, short, value_parser = RMExportFormat::from_str)]
+    format: Option<RMExportFormat>,
+
+    /// Where the file comes from: randomail, simplelogin, addy or relay
+    #[arg(long, short, default_value_t, value_parser = RMImportSource::from_str)]
+    source: RMImportSource,
+
+    /// Forward to the mailboxes of the hosted service instead of the
+    /// configured destination, they must be verified
+    #[arg(long)]
+    keep_mailboxes: bool,
+
+    /// Report what would be created without creating anything
+    #[arg(long, short = 'n')]
+    dry_run: bool,
+}
+
+#[derive(Args)]
+struct SiteArgs {
+    /// Website to look up (e.g. amazon.com)
+    #[arg(value_parser = normalize_site)]
+    site: String,
+}
 
-    /// Human-readable description for the alias
+#[derive(Args)]
+struct DeriveArgs {
+    /// Website to derive the alias for (e.g. amazon.com)
+    #[arg(value_parser = normalize_site)]
+    site: String,
+
+    /// Use this counter instead of the current one
     #[arg(long, short)]
-    description: String,
+    counter: Option<u32>,
+
+    /// Move to the next counter, giving a fresh alias
+    #[arg(long, short, conflicts_with = "counter")]
+    rotate: bool,
 }
 
 #[derive(Args)]
 struct RemoveArgs {
-    /// Email alias to remove (e.g. shopping@domain.com)
+    /// Email alias to remove (e.g. shopping@domain.com, shopping or a rule id)
     email: String,
 }
 
 #[derive(Args)]
 struct ToggleArgs {
-    /// Email alias to enable or disable
+    /// Email alias to enable or disable, as an address, local part or rule id
     email: String,
 }
 
@@ -69,16 +214,211 @@ struct RenameArgs {
     name: String,
 }
 
+#[derive(Args)]
+struct MoveArgs {
+    /// Email alias to change the address of
+    email: String,
+
+    /// New address, or a local part under the configured domain
+    new_email: String,
+}
+
+#[derive(Args)]
+struct RotateArgs {
+    /// Email alias to replace
+    email: String,
+
+    /// Name for the new alias, generated when missing
+    #[arg(long, short)]
+    alias: Option<String>,
+
+    /// Delete the old alias instead of disabling it
+    #[arg(long, conflicts_with = "grace")]
+    delete: bool,
+
+    /// Keep the old alias working for a while (e.g. 7d), it then expires
+    #[arg(long, short, value_parser = parse_expiry)]
+    grace: Option<DateTime<Utc>>,
+}
+
+#[derive(Args)]
+struct RetargetArgs {
+    /// Destination to move aliases away from, defaults to the configured one
+    #[arg(long, short)]
+    from: Option<String>,
+
+    /// Verified destination the aliases should forward to instead
+    #[arg(long, short)]
+    to: String,
+
+    /// Show the aliases that would change without changing them
+    #[arg(long, short = 'n')]
+    dry_run: bool,
+
+    /// Don't ask for confirmation
+    #[arg(long, short)]
+    yes: bool,
+}
+
+#[derive(Args)]
+struct PlanArgs {
+    /// YAML, TOML or JSON file listing the aliases that should exist
+    #[arg(long, short)]
+    file: PathBuf,
+
+    /// Delete aliases that are not in the file
+    #[arg(long)]
+    prune: bool,
+}
+
+#[derive(Args)]
+struct ApplyArgs {
+    #[command(flatten)]
+    plan: PlanArgs,
+
+    /// Don't ask for confirmation
+    #[arg(long, short)]
+    yes: bool,
+}
+
+#[derive(Args)]
+struct SnapshotArgs {
+    /// Write the snapshot to this file instead of the snapshot directory
+    #[arg(long, value_name = "FILE", conflicts_with = "list")]
+    file: Option<PathBuf>,
+
+    /// List stored snapshots instead of taking one
+    #[arg(long, short)]
+    list: bool,
+}
+
+#[derive(Args)]
+struct RestoreArgs {
+    /// Snapshot file, or the name of a stored snapshot
+    snapshot: String,
+
+    /// Only show what would be restored
+    #[arg(long, short = 'n')]
+    dry_run: bool,
+
+    /// Don't ask for confirmation
+    #[arg(long, short)]
+    yes: bool,
+}
+
+#[derive(Args)]
+struct DiffArgs {
+    /// Older snapshot
+    from: String,
+
+    /// Newer snapshot, the current rules when missing
+    to: Option<String>,
+}
+
+#[derive(Subcommand)]
+enum TrashCommands {
+    /// List deleted aliases that can still be restored
+    #[command(alias = "ls")]
+    List,
+    /// Recreate the latest deleted alias with this address
+    Restore {
+        /// Address or local part of the deleted alias
+        email: String,
+    },
+}
+
+#[derive(Args)]
+struct TrashArgs {
+    #[command(subcommand)]
+    command: Option<TrashCommands>,
+}
+
+#[derive(Args)]
+struct LogArgs {
+    /// Only changes to this alias
+    #[arg(long, short)]
+    alias: Option<String>,
+
+    /// Only changes made by this user
+    #[arg(long)]
+    actor: Option<String>,
+
+    /// Only this kind of change: create, delete, enable, disable, rename,
+    /// readdress, retarget, restore or reset
+    #[arg(long)]
+    operation: Option<RMOperation>,
+
+    /// Only changes since a date or for a duration, e.g. 2025-01-31 or 7d
+    #[arg(long, value_parser = parse_since)]
+    since: Option<DateTime<Utc>>,
+
+    /// Only the most recent changes
+    #[arg(long, short = 'n')]
+    limit: Option<usize>,
+}
+
+#[derive(Args)]
+struct DriftArgs {
+    /// Accept the current rules as they are, changes made so far are no
+    /// longer reported
+    #[arg(long)]
+    accept: bool,
+}
+
+#[derive(Args)]
+struct NoteArgs {
+    /// Email alias to annotate
+    email: String,
+
+    /// Website the alias is used for, empty to clear
+    #[arg(long, short)]
+    website: Option<String>,
+
+    /// Free-form notes about the alias, empty to clear
+    #[arg(long, short)]
+    notes: Option<String>,
+
+    /// Expire the alias after a duration (e.g. 30d) or on a date (YYYY-MM-DD)
+    #[arg(long, short = 'x', value_parser = parse_expiry, conflicts_with = "no_expiry")]
+    expires: Option<DateTime<Utc>>,
+
+    /// Remove the expiry of the alias
+    #[arg(long)]
+    no_expiry: bool,
+}
+
+#[derive(Args)]
+struct TagArgs {
+    /// Email alias to tag
+    email: String,
+
+    /// Tag to add, can be repeated
+    #[arg(long, short, value_parser = normalize_tag)]
+    add: Vec<String>,
+
+    /// Tag to remove, can be repeated
+    #[arg(long, short, value_parser = normalize_tag)]
+    remove: Vec<String>,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Set or update Cloudflare configuration
     Config(ConfigArgs),
     #[command(alias = "ls")]
     /// List all email aliases and their status
-    List,
+    List(ListArgs),
+    /// Export all email aliases with their metadata as JSON or CSV
+    Export(ExportArgs),
+    /// Create email aliases from a JSON or CSV file, skipping existing ones
+    Import(ImportArgs),
     /// Create a new email alias
     Add(AddArgs),
-    /// Delete an email alias permanently
+    /// Show which aliases are used for a website
+    Site(SiteArgs),
+    /// Print the alias derived from a website, creating it when missing
+    Derive(DeriveArgs),
+    /// Delete an email alias, keeping it in the trash for a while
     #[command(alias = "rm")]
     Remove(RemoveArgs),
     /// Disable an email alias without deleting it
@@ -87,15 +427,58 @@ enum Commands {
     Enable(ToggleArgs),
     /// Update the description of an email alias
     Rename(RenameArgs),
+    /// Change the address of an email alias, keeping everything else
+    #[command(alias = "readdress")]
+    Move(MoveArgs),
+    /// Replace an email alias with a fresh one and retire the old one
+    Rotate(RotateArgs),
+    /// Forward every alias of one destination inbox to another
+    Retarget(RetargetArgs),
+    /// Show the changes needed to match an alias file
+    Plan(PlanArgs),
+    /// Create, update and delete email aliases to match an alias file
+    Apply(ApplyArgs),
+    /// Save every routing rule to a timestamped snapshot file
+    Snapshot(SnapshotArgs),
+    /// Recreate and reset routing rules from a snapshot
+    Restore(RestoreArgs),
+    /// Compare the routing rules of two snapshots
+    Diff(DiffArgs),
+    /// Recreate the most recently deleted email alias
+    Undo,
+    /// List or restore deleted email aliases
+    Trash(TrashArgs),
+    /// Show the audit log of changes made to routing rules
+    Log(LogArgs),
+    /// Report routing rules added, modified or deleted outside of randomail
+    Drift(DriftArgs),
+    /// Set the website, notes or expiry of an email alias
+    Note(NoteArgs),
+    /// Show, add or remove the tags of an email alias
+    Tag(TagArgs),
+    /// Disable or delete expired aliases, depending on the expiry policy
+    Reap,
 }
 
 #[derive(Parser)]
-#[command(version)]
+#[command(version, after_help = EXIT_CODES_HELP)]
 struct UserArgs {
     /// Enable verbose logging output
     #[arg(long, short)]
     verbose: bool,
 
+    /// How to print results: table, json, jsonl, csv or plain
+    #[arg(long, short, global = true, default_value_t, value_parser = OutputFormat::from_str)]
+    output: OutputFormat,
+
+    /// Record Cloudflare API requests and responses to a cassette file
+    #[arg(long, global = true, value_name = "FILE", conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Answer Cloudflare API requests from a recorded cassette file
+    #[arg(long, global = true, value_name = "FILE")]
+    replay: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -110,125 +493,854 @@ fn init_logging(verbose: bool) {
     env_logger::builder().filter_level(level).init();
 }
 
-async fn get_email_id(config: &RMConfig, email: &str) -> Result<String> {
-    let routes = list_email_routes(&config.zone_id, &config.token).await?;
+fn init_transport(args: &UserArgs) -> Result<CFTransport> {
+    if let Some(path) = &args.record {
+        return Ok(CFTransport::Record(Arc::new(CFCassette::create(path)?)));
+    }
 
-    for r in routes {
-        if r.email_alias.eq(email) {
-            return Ok(r.id);
-        }
+    if let Some(path) = &args.replay {
+        return Ok(CFTransport::Replay(Arc::new(CFCassette::open(path)?)));
     }
 
-    bail!("email id not found for {email}")
+    Ok(CFTransport::Live)
+}
+
+fn load_provider(transport: &CFTransport) -> Result<(RMConfig, Box<dyn AliasProvider>)> {
+    let config = RMConfig::load()?;
+    let client = config.client().with_transport(transport.clone());
+    let provider = config.provider_with(client)?;
+    Ok((config, provider))
+}
+
+/// Save the rules before `command` changes them, so `restore` can undo it
+async fn snapshot_before(config: &RMConfig, transport: &CFTransport, command: &str) -> Result<()> {
+    let client = config.client().with_transport(transport.clone());
+
+    if let Some(path) = auto_snapshot(config, &client, command).await? {
+        info!("rules saved to {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// Rule id of the alias `email` refers to: an address, a local part or an id
+async fn get_email_id(provider: &dyn AliasProvider, email: &str) -> Result<String> {
+    Ok(find_alias(provider, email).await?.id)
 }
 
-async fn command_config(args: &ConfigArgs) -> Result<()> {
+async fn command_config(output: Output, args: &ConfigArgs) -> Result<()> {
     let mut data = RMConfig::soft_load().unwrap_or_default();
 
+    let derive_secret = if args.new_derive_secret {
+        if data.derive_secret.is_some() {
+            bail!(
+                "a derive secret is already set, replacing it changes every derived alias; \
+                 use --derive-secret to replace it anyway"
+            );
+        }
+
+        Some(new_secret())
+    } else {
+        args.derive_secret.clone()
+    };
+
     data.update(
         args.account_id.clone(),
         args.token.clone(),
         args.email.clone(),
         args.domain.clone(),
+        derive_secret,
     )
     .await?;
 
     let conf = RMConfig::soft_load()?;
 
-    let mut table = Table::new(vec![conf]);
-    table.with(Style::modern_rounded());
-    table.with(Rotate::Left);
+    if args.new_derive_secret || args.show_derive_secret {
+        return output.item(&DeriveSecret {
+            derive_secret: conf.derive_secret()?,
+        });
+    }
 
-    println!("{table}");
+    output.item(&conf)
+}
 
-    Ok(())
+async fn command_list(transport: &CFTransport, output: Output, args: &ListArgs) -> Result<()> {
+    let (_, provider) = load_provider(transport)?;
+
+    let mut routes = provider.list().await?;
+
+    if let Some(tag) = &args.tag {
+        routes.retain(|r| r.has_tag(tag));
+    }
+
+    output.list(&routes)
 }
 
-async fn command_list() -> Result<()> {
-    let conf = RMConfig::load()?;
+async fn command_export(transport: &CFTransport, args: &ExportArgs) -> Result<()> {
+    let (_, provider) = load_provider(transport)?;
 
-    let routes = list_email_routes(&conf.zone_id, &conf.token).await?;
+    let aliases = provider.list().await?;
 
-    let mut table = Table::new(&routes);
-    table.with(Style::modern_rounded());
+    let data = export(&aliases, args.format)?;
 
-    println!("{table}");
+    match &args.file {
+        Some(path) => fs::write(path, data)
+            .with_context(|| format!("Unable to write to {}", path.display()))?,
+        None => io::stdout().write_all(&data)?,
+    }
 
     Ok(())
 }
 
-async fn command_add<A, D>(alias: &A, description: D) -> Result<()>
-where
-    A: Into<String> + Display,
-    D: Into<String> + Display,
-{
-    let config = RMConfig::load()?;
+async fn command_import(transport: &CFTransport, output: Output, args: &ImportArgs) -> Result<()> {
+    let (config, provider) = load_provider(transport)?;
+    let client = config.client().with_transport(transport.clone());
 
-    let email_alias = format!("{alias}@{}", config.zone);
+    let data =
+        fs::read(&args.file).with_context(|| format!("Unable to read {}", args.file.display()))?;
 
-    add_email_route(
-        config.zone_id,
-        description,
-        email_alias,
-        config.destination_email,
-        config.token,
+    let rows = parse_source(&data, args.source, args.format, args.keep_mailboxes)?;
+
+    let total = rows.len();
+    let mut done = 0_usize;
+
+    let results = import(
+        provider.as_ref(),
+        &config,
+        &client,
+        rows,
+        args.dry_run,
+        |res| {
+            done = done.saturating_add(1);
+            eprintln!("[{done}] {} {}", res.alias, res.status);
+        },
     )
-    .await
+    .await?;
+
+    output.list(&results)?;
+
+    let count = |status| results.iter().filter(|r| r.status == status).count();
+
+    let created = count(RMImportStatus::Created);
+    let planned = count(RMImportStatus::Planned);
+    let duplicates = count(RMImportStatus::Duplicate);
+    let rejected = total
+        .saturating_sub(created)
+        .saturating_sub(planned)
+        .saturating_sub(duplicates);
+
+    if args.dry_run {
+        output.note(format!(
+            "{planned} to create, {duplicates} duplicates, {rejected} rejected"
+        ))?;
+    } else {
+        output.note(format!(
+            "{created} created, {duplicates} duplicates, {rejected} rejected"
+        ))?;
+    }
+
+    if rejected > 0 {
+        return Err(RMPartialFailure {
+            failed: rejected,
+            total,
+            what: "rows were not imported".to_string(),
+        }
+        .into());
+    }
+
+    Ok(())
 }
 
-async fn command_rem<I>(email: I) -> Result<()>
+async fn command_add(transport: &CFTransport, output: Output, args: &AddArgs) -> Result<()> {
+    let (config, provider) = load_provider(transport)?;
+
+    let name = if let Some(alias) = &args.alias {
+        alias.clone()
+    } else if let Some(site) = &args.site {
+        site_alias_name(provider.as_ref(), config.site_template(), site).await?
+    } else {
+        let strategy = args.strategy.unwrap_or(config.name_strategy);
+        unique_name(provider.as_ref(), strategy).await?
+    };
+
+    let email_alias = alias_address(&name, provider.domain())?;
+
+    let description = args
+        .description
+        .clone()
+        .or_else(|| args.site.clone())
+        .unwrap_or_default();
+
+    let res = provider
+        .create(
+            &encode_tags(&description, &args.tags)?,
+            &email_alias,
+            &config.destination_email,
+        )
+        .await;
+
+    let alias = match res {
+        Ok(alias) => alias,
+        Err(e) if args.if_not_exists => {
+            let existing = e.downcast::<RMAlreadyExists>()?.alias;
+            info!("{} already exists", existing.email_alias);
+            return output.item(&existing);
+        }
+        Err(e) => return Err(e),
+    };
+
+    let update = RMMetadataUpdate {
+        website: args.website.clone().or_else(|| args.site.clone()),
+        notes: args.notes.clone(),
+        expires_at: args.expires,
+        ..RMMetadataUpdate::default()
+    };
+
+    if !update.is_empty() {
+        provider.annotate(&alias.id, &update).await?;
+    }
+
+    output.item(&provider.get(&alias.id).await?)
+}
+
+async fn command_site(transport: &CFTransport, output: Output, args: &SiteArgs) -> Result<()> {
+    let (_, provider) = load_provider(transport)?;
+
+    let mut routes = provider.list().await?;
+
+    routes.retain(|r| matches_site(r, &args.site));
+
+    if routes.is_empty() {
+        return Err(RMAliasNotFound {
+            query: args.site.clone(),
+        }
+        .into());
+    }
+
+    output.list(&routes)
+}
+
+async fn command_derive(transport: &CFTransport, output: Output, args: &DeriveArgs) -> Result<()> {
+    let (config, provider) = load_provider(transport)?;
+
+    let secret = config.derive_secret()?;
+
+    let existing = existing_names(provider.as_ref()).await?;
+
+    let counter = if let Some(counter) = args.counter {
+        counter
+    } else {
+        match current_counter(&existing, secret, &args.site)? {
+            Some(c) if args.rotate => c.saturating_add(1),
+            Some(c) => c,
+            None => 0,
+        }
+    };
+
+    if counter >= MAX_COUNTER {
+        bail!("counter must be below {MAX_COUNTER}");
+    }
+
+    let name = derive_name(secret, &args.site, counter)?;
+
+    let email_alias = alias_address(&name, provider.domain())?;
+
+    if existing.contains(&name) {
+        return output.item(&find_alias(provider.as_ref(), &email_alias).await?);
+    }
+
+    let alias = provider
+        .create(&args.site, &email_alias, &config.destination_email)
+        .await?;
+
+    let update = RMMetadataUpdate {
+        website: Some(args.site.clone()),
+        ..RMMetadataUpdate::default()
+    };
+
+    provider.annotate(&alias.id, &update).await?;
+
+    output.item(&provider.get(&alias.id).await?)
+}
+
+async fn command_rem<I>(transport: &CFTransport, output: Output, email: I) -> Result<()>
 where
     I: AsRef<str> + Display,
 {
-    let config = RMConfig::load()?;
+    let (config, provider) = load_provider(transport)?;
+
+    let alias = find_alias(provider.as_ref(), email.as_ref()).await?;
+
+    info!("{email} -> {}", alias.id);
 
-    let email_id = get_email_id(&config, email.as_ref()).await?;
+    snapshot_before(&config, transport, "remove").await?;
 
-    info!("{email} -> {email_id}");
+    provider.delete(&alias.id).await?;
 
-    delete_email_route(config.zone_id, email_id, config.token).await
+    output.item(&alias)
 }
 
-async fn command_disable<I>(email: I) -> Result<()>
+async fn command_toggle<I>(
+    transport: &CFTransport,
+    output: Output,
+    email: I,
+    enabled: bool,
+) -> Result<()>
 where
     I: AsRef<str> + Display,
 {
-    let config = RMConfig::load()?;
+    let (_, provider) = load_provider(transport)?;
+
+    let email_id = get_email_id(provider.as_ref(), email.as_ref()).await?;
+
+    provider.set_enabled(&email_id, enabled).await?;
+
+    output.item(&provider.get(&email_id).await?)
+}
+
+async fn command_rename(transport: &CFTransport, output: Output, args: &RenameArgs) -> Result<()> {
+    let (_, provider) = load_provider(transport)?;
+
+    let email_id = get_email_id(provider.as_ref(), &args.email).await?;
+
+    provider.rename(&email_id, &args.name).await?;
+
+    output.item(&provider.get(&email_id).await?)
+}
+
+async fn command_move(transport: &CFTransport, output: Output, args: &MoveArgs) -> Result<()> {
+    let (config, provider) = load_provider(transport)?;
+
+    let email_id = get_email_id(provider.as_ref(), &args.email).await?;
+
+    let new_email = parse_address(&args.new_email, provider.domain())?;
 
-    let email_id = get_email_id(&config, email.as_ref()).await?;
+    snapshot_before(&config, transport, "move").await?;
 
-    update_email_route(config.zone_id, email_id, config.token, false).await
+    let alias = provider.readdress(&email_id, &new_email).await?;
+
+    output.item(&alias)
+}
+
+async fn command_rotate(transport: &CFTransport, output: Output, args: &RotateArgs) -> Result<()> {
+    let (config, provider) = load_provider(transport)?;
+
+    let old = find_alias(provider.as_ref(), &args.email).await?;
+
+    let name = match &args.alias {
+        Some(alias) => alias.clone(),
+        None => {
+            replacement_name(
+                provider.as_ref(),
+                &old,
+                config.name_strategy,
+                config.site_template(),
+            )
+            .await?
+        }
+    };
+
+    let email_alias = alias_address(&name, provider.domain())?;
+
+    let policy = match args.grace {
+        Some(date) => RMRetirePolicy::After(date),
+        None if args.delete => RMRetirePolicy::Delete,
+        None => RMRetirePolicy::Disable,
+    };
+
+    snapshot_before(&config, transport, "rotate").await?;
+
+    let new = rotate(provider.as_ref(), &old, &email_alias, policy).await?;
+
+    output.item(&new)
 }
 
-async fn command_enable<I>(email: I) -> Result<()>
+fn confirm(question: &str) -> Result<bool> {
+    eprint!("{question} [y/N] ");
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Show the one line summary of each change on stderr before asking to go on
+fn show_changes<C>(changes: &[C])
 where
-    I: AsRef<str> + Display,
+    C: Display,
 {
+    for change in changes {
+        eprintln!("{change}");
+    }
+}
+
+/// Status of a change once carried out
+fn status(res: &Result<()>) -> &'static str {
+    if res.is_ok() { "ok" } else { "failed" }
+}
+
+async fn command_retarget(
+    transport: &CFTransport,
+    output: Output,
+    args: &RetargetArgs,
+) -> Result<()> {
+    let (mut config, provider) = load_provider(transport)?;
+    let client = config.client().with_transport(transport.clone());
+
+    let from = args
+        .from
+        .clone()
+        .unwrap_or_else(|| config.destination_email.clone());
+
+    if from.eq_ignore_ascii_case(&args.to) {
+        bail!("aliases already forward to {from}");
+    }
+
+    config.check_destination(&client, &args.to).await?;
+
+    let aliases = retarget_plan(provider.as_ref(), &from).await?;
+
+    let is_default = config.destination_email.eq_ignore_ascii_case(&from);
+
+    if args.dry_run {
+        let rows: Vec<ChangeRow> = aliases
+            .iter()
+            .map(|a| ChangeRow::retarget(a, &from, &args.to, "planned"))
+            .collect();
+
+        output.changes(&rows, &format!("no alias forwards to {from}"))?;
+    } else if aliases.is_empty() {
+        output.note(format!("no alias forwards to {from}"))?;
+    } else {
+        eprintln!(
+            "{} aliases will forward to {} instead of {from}:",
+            aliases.len(),
+            args.to
+        );
+
+        for alias in &aliases {
+            eprintln!("  {}", alias.email_alias);
+        }
+    }
+
+    if is_default {
+        output.note(format!("new aliases will forward to {}", args.to))?;
+    }
+
+    if args.dry_run || (aliases.is_empty() && !is_default) {
+        return Ok(());
+    }
+
+    if !args.yes && !confirm("Continue?")? {
+        return Err(Cancelled("retarget").into());
+    }
+
+    snapshot_before(&config, transport, "retarget").await?;
+
+    let total = aliases.len();
+    let mut rows = Vec::new();
+
+    let res = retarget(
+        provider.as_ref(),
+        &aliases,
+        &from,
+        &args.to,
+        |alias, res| {
+            rows.push(ChangeRow::retarget(alias, &from, &args.to, status(res)));
+            eprintln!(
+                "[{}/{total}] {} {}",
+                rows.len(),
+                alias.email_alias,
+                status(res)
+            );
+        },
+    )
+    .await;
+
+    output.list(&rows)?;
+    res?;
+
+    if is_default {
+        config.set_destination(&client, &args.to).await?;
+    }
+
+    Ok(())
+}
+
+/// Compute the plan for `args`, along with what's needed to carry it out
+async fn load_plan(
+    transport: &CFTransport,
+    args: &PlanArgs,
+) -> Result<(RMConfig, Box<dyn AliasProvider>, RMPlan)> {
+    let (config, provider) = load_provider(transport)?;
+    let client = config.client().with_transport(transport.clone());
+
+    let file = RMAliasFile::load(&args.file)?;
+
+    let plan = plan(provider.as_ref(), &config, &client, &file, args.prune).await?;
+
+    Ok((config, provider, plan))
+}
+
+fn plan_summary(plan: &RMPlan) -> String {
+    let (created, updated, deleted) = plan.counts();
+
+    if plan.is_empty() {
+        "no changes".to_string()
+    } else {
+        format!("{created} to create, {updated} to update, {deleted} to delete")
+    }
+}
+
+async fn command_plan(transport: &CFTransport, output: Output, args: &PlanArgs) -> Result<()> {
+    let (_, _, plan) = load_plan(transport, args).await?;
+
+    let rows = ChangeRow::plan_rows(&plan);
+
+    output.changes(&rows, "no changes")?;
+
+    if !rows.is_empty() {
+        output.note(plan_summary(&plan))?;
+    }
+
+    Ok(())
+}
+
+async fn command_apply(transport: &CFTransport, output: Output, args: &ApplyArgs) -> Result<()> {
+    let (config, provider, plan) = load_plan(transport, &args.plan).await?;
+
+    show_changes(&plan.changes);
+
+    for alias in &plan.unmanaged {
+        eprintln!(
+            "? {} (not in file, kept without --prune)",
+            alias.email_alias
+        );
+    }
+
+    if plan.is_empty() {
+        return output.changes(&[], "no changes");
+    }
+
+    eprintln!("{}", plan_summary(&plan));
+
+    if !args.yes && !confirm("Apply these changes?")? {
+        return Err(Cancelled("apply").into());
+    }
+
+    snapshot_before(&config, transport, "apply").await?;
+
+    let total = plan.changes.len();
+    let mut rows = Vec::new();
+
+    let res = apply(provider.as_ref(), &plan, |change, res| {
+        rows.push(ChangeRow::plan(change, status(res)));
+        let summary = change.to_string();
+        let summary = summary.lines().next().unwrap_or_default();
+        eprintln!("[{}/{total}] {summary} {}", rows.len(), status(res));
+    })
+    .await;
+
+    output.list(&rows)?;
+
+    res
+}
+
+async fn command_snapshot(
+    transport: &CFTransport,
+    output: Output,
+    args: &SnapshotArgs,
+) -> Result<()> {
+    if args.list {
+        return output.list(&list_snapshots()?);
+    }
+
+    let config = RMConfig::load()?;
+    let client = config.client().with_transport(transport.clone());
+
+    let snapshot = RMSnapshot::take(&config, &client, None).await?;
+
+    let path = match &args.file {
+        Some(path) => {
+            snapshot.save(path)?;
+            path.clone()
+        }
+        None => store_snapshot(&snapshot)?,
+    };
+
+    output.item(&RMSnapshotInfo::new(&path, &snapshot))
+}
+
+async fn command_restore(
+    transport: &CFTransport,
+    output: Output,
+    args: &RestoreArgs,
+) -> Result<()> {
+    let config = RMConfig::load()?;
+    let client = config.client().with_transport(transport.clone());
+
+    let snapshot = RMSnapshot::load(resolve_snapshot(&args.snapshot)?)?;
+
+    if snapshot.zone_id != config.zone_id {
+        bail!(
+            "snapshot was taken of {}, not of {}",
+            snapshot.zone,
+            config.zone
+        );
+    }
+
+    let current = RMSnapshot::take(&config, &client, None).await?;
+
+    let changes = restore_plan(&current.rules, &snapshot);
+
+    if changes.is_empty() || args.dry_run {
+        let rows: Vec<ChangeRow> = changes
+            .iter()
+            .map(|c| ChangeRow::rule(c, "planned"))
+            .collect();
+
+        return output.changes(&rows, "rules already match the snapshot");
+    }
+
+    show_changes(&changes);
+
+    if !args.yes && !confirm("Restore these rules?")? {
+        return Err(Cancelled("restore").into());
+    }
+
+    store_snapshot(&RMSnapshot {
+        reason: Some("restore".to_string()),
+        ..current
+    })?;
+
+    let total = changes.len();
+    let mut rows = Vec::new();
+
+    let res = restore(&client, &config.zone_id, &changes, |change, res| {
+        rows.push(ChangeRow::rule(change, status(res)));
+        let summary = change.to_string();
+        let summary = summary.lines().next().unwrap_or_default();
+        eprintln!("[{}/{total}] {summary} {}", rows.len(), status(res));
+    })
+    .await;
+
+    output.list(&rows)?;
+
+    res
+}
+
+async fn command_diff(transport: &CFTransport, output: Output, args: &DiffArgs) -> Result<()> {
+    let from = RMSnapshot::load(resolve_snapshot(&args.from)?)?;
+
+    let to = if let Some(to) = &args.to {
+        RMSnapshot::load(resolve_snapshot(to)?)?
+    } else {
+        let config = RMConfig::load()?;
+        let client = config.client().with_transport(transport.clone());
+        RMSnapshot::take(&config, &client, None).await?
+    };
+
+    let changes = diff(&from.rules, &to.rules);
+
+    let rows: Vec<ChangeRow> = changes
+        .iter()
+        .map(|c| ChangeRow::rule(c, "differs"))
+        .collect();
+
+    output.changes(&rows, "no differences")
+}
+
+async fn command_untrash(
+    transport: &CFTransport,
+    output: Output,
+    email: Option<&str>,
+) -> Result<()> {
+    let (_, provider) = load_provider(transport)?;
+
+    let email = email
+        .map(|e| parse_address(e, provider.domain()))
+        .transpose()?;
+
+    let alias = provider.untrash(email.as_deref()).await?;
+
+    output.item(&alias)
+}
+
+async fn command_trash(transport: &CFTransport, output: Output, args: &TrashArgs) -> Result<()> {
+    if let Some(TrashCommands::Restore { email }) = &args.command {
+        return command_untrash(transport, output, Some(email)).await;
+    }
+
+    let (_, provider) = load_provider(transport)?;
+
+    output.list(&provider.trashed().await?)
+}
+
+fn command_log(output: Output, args: &LogArgs) -> Result<()> {
     let config = RMConfig::load()?;
 
-    let email_id = get_email_id(&config, email.as_ref()).await?;
+    let alias = args
+        .alias
+        .as_deref()
+        .map(|a| parse_address(a, &config.zone))
+        .transpose()?;
 
-    update_email_route(config.zone_id, email_id, config.token, true).await
+    let filter = RMAuditFilter {
+        alias,
+        actor: args.actor.clone(),
+        operation: args.operation,
+        since: args.since,
+        limit: args.limit,
+    };
+
+    output.list(&config.audit_log()?.read(&filter)?)
 }
 
-async fn command_rename(args: &RenameArgs) -> Result<()> {
+async fn command_drift(transport: &CFTransport, output: Output, args: &DriftArgs) -> Result<()> {
     let config = RMConfig::load()?;
-    rename_email_route(config.zone_id, &args.email, config.token, &args.name).await
+    let client = config.client().with_transport(transport.clone());
+
+    let drift = detect_drift(&config, &client).await?;
+
+    if drift.accepted_at.is_none() {
+        if !args.accept {
+            return output.changes(
+                &[],
+                "rules aren't tracked yet, `randomail drift --accept` to take the current ones as known",
+            );
+        }
+
+        let count = accept_drift(&config, &client).await?;
+
+        return output.changes(
+            &[],
+            &format!("{count} rules accepted as known, drift is now tracked"),
+        );
+    }
+
+    let status = if args.accept { "accepted" } else { "drifted" };
+
+    let rows: Vec<ChangeRow> = drift
+        .changes
+        .iter()
+        .map(|c| ChangeRow::rule(c, status))
+        .collect();
+
+    if args.accept {
+        accept_drift(&config, &client).await?;
+    }
+
+    output.changes(&rows, "no drift")?;
+
+    if drift.changes.is_empty() {
+        return Ok(());
+    }
+
+    if args.accept {
+        output.note(format!("{} rules accepted as known", drift.changes.len()))?;
+    } else {
+        output.note(format!(
+            "{} rules changed outside of randomail, `randomail drift --accept` to accept them",
+            drift.changes.len()
+        ))?;
+    }
+
+    Ok(())
+}
+
+async fn command_note(transport: &CFTransport, output: Output, args: &NoteArgs) -> Result<()> {
+    let (_, provider) = load_provider(transport)?;
+
+    let email_id = get_email_id(provider.as_ref(), &args.email).await?;
+
+    let update = RMMetadataUpdate {
+        website: args.website.clone(),
+        notes: args.notes.clone(),
+        expires_at: args.expires,
+        clear_expiry: args.no_expiry,
+        ..RMMetadataUpdate::default()
+    };
+
+    provider.annotate(&email_id, &update).await?;
+
+    output.item(&provider.get(&email_id).await?)
+}
+
+async fn command_tag(transport: &CFTransport, output: Output, args: &TagArgs) -> Result<()> {
+    let (_, provider) = load_provider(transport)?;
+
+    let email_id = get_email_id(provider.as_ref(), &args.email).await?;
+
+    if !args.add.is_empty() || !args.remove.is_empty() {
+        let mut tags = provider.get(&email_id).await?.tags;
+
+        tags.retain(|t| !args.remove.contains(t));
+        tags.extend(args.add.iter().cloned());
+
+        provider.set_tags(&email_id, &tags).await?;
+    }
+
+    output.item(&provider.get(&email_id).await?)
+}
+
+async fn command_reap(transport: &CFTransport, output: Output) -> Result<()> {
+    let (config, provider) = load_provider(transport)?;
+
+    let store = config.metadata_store()?;
+    let reaped = reap(provider.as_ref(), &store, config.expiry_policy).await?;
+
+    output.list(&reaped)
+}
+
+async fn run(args: UserArgs, output: Output) -> Result<()> {
+    let transport = init_transport(&args)?;
+
+    match args.command {
+        Commands::Config(a) => command_config(output, &a).await,
+        Commands::List(a) => command_list(&transport, output, &a).await,
+        Commands::Export(a) => command_export(&transport, &a).await,
+        Commands::Import(a) => command_import(&transport, output, &a).await,
+        Commands::Add(a) => command_add(&transport, output, &a).await,
+        Commands::Site(a) => command_site(&transport, output, &a).await,
+        Commands::Derive(a) => command_derive(&transport, output, &a).await,
+        Commands::Remove(a) => command_rem(&transport, output, a.email).await,
+        Commands::Disable(a) => command_toggle(&transport, output, a.email, false).await,
+        Commands::Enable(a) => command_toggle(&transport, output, a.email, true).await,
+        Commands::Rename(a) => command_rename(&transport, output, &a).await,
+        Commands::Move(a) => command_move(&transport, output, &a).await,
+        Commands::Rotate(a) => command_rotate(&transport, output, &a).await,
+        Commands::Retarget(a) => command_retarget(&transport, output, &a).await,
+        Commands::Plan(a) => command_plan(&transport, output, &a).await,
+        Commands::Apply(a) => command_apply(&transport, output, &a).await,
+        Commands::Snapshot(a) => command_snapshot(&transport, output, &a).await,
+        Commands::Restore(a) => command_restore(&transport, output, &a).await,
+        Commands::Diff(a) => command_diff(&transport, output, &a).await,
+        Commands::Undo => command_untrash(&transport, output, None).await,
+        Commands::Trash(a) => command_trash(&transport, output, &a).await,
+        Commands::Log(a) => command_log(output, &a),
+        Commands::Drift(a) => command_drift(&transport, output, &a).await,
+        Commands::Note(a) => command_note(&transport, output, &a).await,
+        Commands::Tag(a) => command_tag(&transport, output, &a).await,
+        Commands::Reap => command_reap(&transport, output).await,
+    }
 }
 
 #[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<()> {
+async fn main() -> ExitCode {
     let args = UserArgs::parse();
 
     init_logging(args.verbose);
 
-    match args.command {
-        Commands::Config(a) => command_config(&a).await,
-        Commands::List => command_list().await,
-        Commands::Add(a) => command_add(&a.alias, a.description).await,
-        Commands::Remove(a) => command_rem(a.email).await,
-        Commands::Disable(a) => command_disable(a.email).await,
-        Commands::Enable(a) => command_enable(a.email).await,
-        Commands::Rename(a) => command_rename(&a).await,
+    let output = Output::new(args.output);
+
+    match run(args, output).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) if is_broken_pipe(&e) => ExitCode::SUCCESS,
+        Err(e) => {
+            let kind = ErrorKind::of(&e);
+            output.error(&e, kind);
+            ExitCode::from(kind.code())
+        }
     }
 }
@@ -0,0 +1,370 @@
+use std::{
+    fmt::{self, Display},
+    io::{self, Write},
+    str::FromStr,
+};
+
+use anyhow::{Context, Result, bail};
+use serde::Serialize;
+use tabled::{
+    Table, Tabled,
+    settings::{Rotate, Style},
+};
+
+use randomail_api::{
+    provider::RMAlias,
+    snapshot::RMRuleChange,
+    sync::{RMChange, RMPlan},
+};
+
+use crate::exit::ErrorKind;
+
+/// How results are printed on stdout
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Table,
+    /// A JSON array, or a JSON object for a single result
+    Json,
+    /// One JSON object per line
+    Jsonl,
+    /// A header line, then one line per result
+    Csv,
+    /// Tab separated fields, one line per result and no header
+    Plain,
+}
+
+impl Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let format = match self {
+            Self::Table => "table",
+            Self::Json => "json",
+            Self::Jsonl => "jsonl",
+            Self::Csv => "csv",
+            Self::Plain => "plain",
+        };
+
+        f.write_str(format)
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "table" => Ok(Self::Table),
+            "json" => Ok(Self::Json),
+            "jsonl" => Ok(Self::Jsonl),
+            "csv" => Ok(Self::Csv),
+            "plain" => Ok(Self::Plain),
+            _ => bail!("unknown output format {s}, use table, json, jsonl, csv or plain"),
+        }
+    }
+}
+
+/// Prints results in the chosen format. Results go to stdout, messages meant
+/// for people go to stderr unless the output is a table. Write errors are
+/// returned whatever the format, see `is_broken_pipe` for a closed stdout.
+#[derive(Debug, Clone, Copy)]
+pub struct Output {
+    format: OutputFormat,
+}
+
+impl Output {
+    pub fn new(format: OutputFormat) -> Self {
+        Self { format }
+    }
+
+    pub fn is_table(self) -> bool {
+        self.format == OutputFormat::Table
+    }
+
+    /// Print every record of `records`
+    pub fn list<T>(self, records: &[T]) -> Result<()>
+    where
+        T: Serialize + Tabled,
+    {
+        match self.format {
+            OutputFormat::Table => {
+                let mut table = Table::new(records);
+                table.with(Style::modern_rounded());
+                print_line(&table)?;
+            }
+            OutputFormat::Json => {
+                let json =
+                    serde_json::to_string_pretty(records).context("Unable to serialize results")?;
+                print_line(&json)?;
+            }
+            OutputFormat::Jsonl => {
+                for record in records {
+                    print_json_line(record)?;
+                }
+            }
+            OutputFormat::Csv => print_csv(records)?,
+            OutputFormat::Plain => {
+                for record in records {
+                    print_plain(record)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Print a single record, tables show it one field per line
+    pub fn item<T>(self, record: &T) -> Result<()>
+    where
+        T: Serialize + Tabled,
+    {
+        match self.format {
+            OutputFormat::Table => {
+                let mut table = Table::new([record]);
+                table.with(Style::modern_rounded());
+                // one field per line, in the order they are declared
+                table.with(Rotate::Left);
+                table.with(Rotate::Bottom);
+                print_line(&table)?;
+            }
+            OutputFormat::Json => {
+                let json =
+                    serde_json::to_string_pretty(record).context("Unable to serialize result")?;
+                print_line(&json)?;
+            }
+            OutputFormat::Jsonl => print_json_line(record)?,
+            OutputFormat::Csv => print_csv(&[record])?,
+            OutputFormat::Plain => print_plain(record)?,
+        }
+
+        Ok(())
+    }
+
+    /// Print a report of changes, or `none` when there are none. An empty
+    /// table says nothing, so only the message is shown then.
+    pub fn changes(self, rows: &[ChangeRow], none: &str) -> Result<()> {
+        if !rows.is_empty() || !self.is_table() {
+            self.list(rows)?;
+        }
+
+        if rows.is_empty() {
+            self.note(none)?;
+        }
+
+        Ok(())
+    }
+
+    /// A message for whoever is reading, such as a summary or a hint
+    pub fn note<M>(self, message: M) -> Result<()>
+    where
+        M: Display,
+    {
+        if self.is_table() {
+            print_line(&message)
+        } else {
+            writeln!(io::stderr().lock(), "{message}").context("Unable to write message")
+        }
+    }
+
+    /// Report `err`, as JSON when the output is JSON so scripts can read it
+    pub fn error(self, err: &anyhow::Error, kind: ErrorKind) {
+        if matches!(self.format, OutputFormat::Json | OutputFormat::Jsonl) {
+            let body = serde_json::json!({
+                "error": format!("{err:#}"),
+                "kind": kind.to_string(),
+                "code": kind.code(),
+            });
+            // nowhere left to report a failure to write the error itself
+            let _ = writeln!(io::stderr().lock(), "{body}");
+        } else {
+            let _ = writeln!(io::stderr().lock(), "Error: {err:#}");
+        }
+    }
+}
+
+/// Whether `err` comes from writing to a closed stdout, such as
+/// `randomail list | head -1`. Nobody reads the rest, so it isn't a failure.
+pub fn is_broken_pipe(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        let io_err = cause.downcast_ref::<io::Error>().or_else(|| {
+            match cause.downcast_ref::<csv::Error>()?.kind() {
+                csv::ErrorKind::Io(io_err) => Some(io_err),
+                _ => None,
+            }
+        });
+
+        io_err.is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe)
+    })
+}
+
+fn print_line<T>(text: &T) -> Result<()>
+where
+    T: Display + ?Sized,
+{
+    writeln!(io::stdout().lock(), "{text}").context("Unable to write results")
+}
+
+fn print_json_line<T>(record: &T) -> Result<()>
+where
+    T: Serialize,
+{
+    let json = serde_json::to_string(record).context("Unable to serialize result")?;
+    print_line(&json)
+}
+
+fn print_csv<T>(records: &[T]) -> Result<()>
+where
+    T: Tabled,
+{
+    let mut writer = csv::Writer::from_writer(io::stdout().lock());
+
+    writer
+        .write_record(T::headers().iter().map(AsRef::<str>::as_ref))
+        .context("Unable to write CSV")?;
+
+    for record in records {
+        writer
+            .write_record(record.fields().iter().map(AsRef::<str>::as_ref))
+            .context("Unable to write CSV")?;
+    }
+
+    writer.flush().context("Unable to write CSV")
+}
+
+fn print_plain<T>(record: &T) -> Result<()>
+where
+    T: Tabled,
+{
+    let fields: Vec<String> = record
+        .fields()
+        .iter()
+        .map(|f| f.replace(['\t', '\n'], " "))
+        .collect();
+
+    print_line(&fields.join("\t"))
+}
+
+/// One line of a plan, diff or drift report, or the outcome of carrying it out
+#[derive(Debug, Serialize, Tabled)]
+pub struct ChangeRow {
+    pub change: &'static str,
+    pub alias: String,
+    #[tabled(display = "display_details")]
+    pub details: Vec<String>,
+    pub status: &'static str,
+}
+
+fn display_details(details: &[String]) -> String {
+    details.join("; ")
+}
+
+impl ChangeRow {
+    /// A change of an alias file plan
+    pub fn plan(change: &RMChange, status: &'static str) -> Self {
+        match change {
+            RMChange::Create(target) => Self {
+                change: "create",
+                alias: target.email_alias.clone(),
+                details: Vec::new(),
+                status,
+            },
+            RMChange::Update {
+                current, fields, ..
+            } => Self {
+                change: "update",
+                alias: current.email_alias.clone(),
+                details: fields.iter().map(ToString::to_string).collect(),
+                status,
+            },
+            RMChange::Delete(current) => Self {
+                change: "delete",
+                alias: current.email_alias.clone(),
+                details: Vec::new(),
+                status,
+            },
+        }
+    }
+
+    /// Every change of `plan`, and the aliases it leaves alone
+    pub fn plan_rows(plan: &RMPlan) -> Vec<Self> {
+        plan.changes
+            .iter()
+            .map(|c| Self::plan(c, "planned"))
+            .chain(plan.unmanaged.iter().map(|alias| Self {
+                change: "keep",
+                alias: alias.email_alias.clone(),
+                details: vec!["not in file, kept without --prune".to_string()],
+                status: "unmanaged",
+            }))
+            .collect()
+    }
+
+    /// A difference between two sets of routing rules
+    pub fn rule(change: &RMRuleChange, status: &'static str) -> Self {
+        let (kind, details) = match change {
+            RMRuleChange::Added(_) => ("added", Vec::new()),
+            RMRuleChange::Changed { fields, .. } => {
+                ("changed", fields.iter().map(ToString::to_string).collect())
+            }
+            RMRuleChange::Removed(_) => ("removed", Vec::new()),
+        };
+
+        Self {
+            change: kind,
+            alias: change.label().to_string(),
+            details,
+            status,
+        }
+    }
+
+    /// An alias forwarding to `to` instead of `from`
+    pub fn retarget(alias: &RMAlias, from: &str, to: &str, status: &'static str) -> Self {
+        Self {
+            change: "retarget",
+            alias: alias.email_alias.clone(),
+            details: vec![format!("destination: {from} -> {to}")],
+            status,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+
+    use anyhow::{Context, Result};
+
+    use super::is_broken_pipe;
+
+    /// A reader that went away, like `head` once it has its lines
+    struct ClosedPipe;
+
+    impl Write for ClosedPipe {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn broken_pipe_from_any_writer() {
+        let line = writeln!(ClosedPipe, "line").context("Unable to write results");
+        assert!(line.is_err_and(|e| is_broken_pipe(&e)));
+
+        let mut writer = csv::Writer::from_writer(ClosedPipe);
+        let csv: Result<()> = writer
+            .write_record(["a", "b"])
+            .and_then(|()| writer.flush().map_err(Into::into))
+            .context("Unable to write CSV");
+        assert!(csv.is_err_and(|e| is_broken_pipe(&e)));
+    }
+
+    #[test]
+    fn other_errors_are_failures() {
+        let err = anyhow::Error::from(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert!(!is_broken_pipe(&err));
+        assert!(!is_broken_pipe(&anyhow::anyhow!("broken pipe")));
+    }
+}
@@ -0,0 +1,164 @@
+//! The `randomail` binary against the in-process fake Cloudflare API
+
+use std::{
+    path::PathBuf,
+    process::{Output, Stdio},
+};
+
+use anyhow::{Context, Result};
+use serde_json::{Value, json};
+use tokio::process::Command;
+
+use randomail_cf_fake::{FAKE_ACCOUNT_ID, FakeCloudflare, FakeRule};
+
+const TOKEN: &str = "test-token";
+const ZONE: &str = "example.com";
+const DEST: &str = "you@inbox.example";
+
+/// A fake Cloudflare with one zone, and a config directory pointing at it
+struct Setup {
+    cf: FakeCloudflare,
+    zone_id: String,
+    home: PathBuf,
+}
+
+impl Setup {
+    async fn new(name: &str, token: &str) -> Result<Self> {
+        let cf = FakeCloudflare::start(TOKEN).await?;
+        let zone_id = cf.add_zone(ZONE);
+        let dest_id = cf.add_destination(DEST, true);
+
+        let home =
+            std::env::temp_dir().join(format!("randomail-cli-{name}-{}", std::process::id()));
+        let config_dir = home.join("randomail");
+        std::fs::create_dir_all(&config_dir)?;
+
+        let config = json!({
+            "account_id": FAKE_ACCOUNT_ID,
+            "token": token,
+            "destination_email": DEST,
+            "destination_email_id": dest_id,
+            "zone": ZONE,
+            "zone_id": zone_id,
+            "api_url": cf.api_url(),
+        });
+        std::fs::write(
+            config_dir.join("config.json"),
+            serde_json::to_string(&config)?,
+        )?;
+
+        Ok(Self { cf, zone_id, home })
+    }
+
+    fn command(&self, args: &[&str]) -> Command {
+        let mut cmd = Command::new(env!("CARGO_BIN_EXE_randomail"));
+        cmd.args(args)
+            .env("XDG_CONFIG_HOME", &self.home)
+            .env("HOME", &self.home)
+            .env_remove("RUST_LOG");
+        cmd
+    }
+
+    async fn run(&self, args: &[&str]) -> Result<Output> {
+        self.command(args)
+            .output()
+            .await
+            .context("Unable to run randomail")
+    }
+}
+
+impl Drop for Setup {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.home);
+    }
+}
+
+#[tokio::test]
+async fn list_add_and_duplicate() -> Result<()> {
+    let setup = Setup::new("add", TOKEN).await?;
+
+    for i in 0..60 {
+        setup.cf.add_rule(
+            &setup.zone_id,
+            FakeRule::forward("seeded", format!("alias{i}@{ZONE}"), DEST),
+        );
+    }
+
+    let out = setup.run(&["-o", "json", "list"]).await?;
+    assert_eq!(out.status.code(), Some(0));
+    let aliases: Vec<Value> = serde_json::from_slice(&out.stdout)?;
+    assert_eq!(aliases.len(), 60);
+
+    let out = setup
+        .run(&[
+            "-o",
+            "json",
+            "add",
+            "--alias",
+            "shop",
+            "--description",
+            "Shop",
+        ])
+        .await?;
+    assert_eq!(out.status.code(), Some(0));
+    let alias: Value = serde_json::from_slice(&out.stdout)?;
+    assert_eq!(alias.get("email_alias"), Some(&json!("shop@example.com")));
+    assert_eq!(setup.cf.rules(&setup.zone_id).len(), 61);
+
+    // already exists
+    let out = setup
+        .run(&[
+            "-o",
+            "json",
+            "add",
+            "--alias",
+            "shop",
+            "--description",
+            "Shop",
+        ])
+        .await?;
+    assert_eq!(out.status.code(), Some(5));
+    let err: Value = serde_json::from_slice(&out.stderr)?;
+    assert_eq!(err.get("code"), Some(&json!(5)));
+    assert_eq!(setup.cf.rules(&setup.zone_id).len(), 61);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn refused_request_exits_with_7() -> Result<()> {
+    let setup = Setup::new("refused", "wrong").await?;
+
+    let out = setup.run(&["list"]).await?;
+
+    assert_eq!(out.status.code(), Some(7));
+    assert!(String::from_utf8(out.stderr)?.contains("403"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn closed_stdout_is_not_an_error() -> Result<()> {
+    let setup = Setup::new("pipe", TOKEN).await?;
+    setup.cf.add_rule(
+        &setup.zone_id,
+        FakeRule::forward("seeded", "shop@example.com", DEST),
+    );
+
+    for format in ["table", "json", "jsonl", "csv", "plain"] {
+        let mut child = setup
+            .command(&["-o", format, "list"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // gone before anything is written, the fake only answers once we wait
+        drop(child.stdout.take());
+
+        let out = child.wait_with_output().await?;
+        assert_eq!(out.status.code(), Some(0), "{format}");
+        assert!(out.stderr.is_empty(), "{format}");
+    }
+
+    Ok(())
+}